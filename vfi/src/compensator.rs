@@ -1,41 +1,175 @@
+use std::f32::consts::PI;
+
 use image::{RgbImage, ImageBuffer, Rgb};
 
-use crate::operations::pixel_average;
+use crate::error::{check_dimensions, Result, VfiError};
+use crate::flow::{FlowField, Visibility};
+use crate::operations::{pixel_average, sample_bilinear};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompensationMethod {
+    Scatter,
+    Obmc,
+    BackwardWarp,
+}
+
+pub fn compensate(
+    frame_1_filename: &str,
+    frame_2_filename: &str,
+    flow: &FlowField,
+    method: CompensationMethod,
+) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>> {
+    let frame_1 = image::open(frame_1_filename)?.into_rgb8();
+    let frame_2 = image::open(frame_2_filename)?.into_rgb8();
+    interpolate_at(&frame_1, &frame_2, flow, 0.5, method)
+}
+
+/// Interpolates the frame at time `t` in (0, 1) between `frame_1` (t = 0) and
+/// `frame_2` (t = 1) with the given compensation method.
+pub fn interpolate_at(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    flow: &FlowField,
+    t: f32,
+    method: CompensationMethod,
+) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>> {
+    check_dimensions(frame_1.dimensions(), frame_2.dimensions())?;
+    check_time(t)?;
+    Ok(match method {
+        CompensationMethod::Scatter => scatter(frame_1, frame_2, flow, t),
+        CompensationMethod::Obmc => obmc(frame_1, frame_2, flow, t),
+        CompensationMethod::BackwardWarp => compensate_at(frame_1, frame_2, flow, t)?,
+    })
+}
+
+fn check_time(t: f32) -> Result<()> {
+    if !(t > 0.0 && t < 1.0) {
+        return Err(VfiError::InvalidSettings(format!(
+            "interpolation time must be between 0 and 1, got {}",
+            t
+        )));
+    }
+    Ok(())
+}
+
+/// Interpolates the frame at time `t` in (0, 1) between `frame_1` (t = 0) and
+/// `frame_2` (t = 1) by backward-warping both frames along `flow`.
+pub fn compensate_at(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    flow: &FlowField,
+    t: f32,
+) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>> {
+    check_dimensions(frame_1.dimensions(), frame_2.dimensions())?;
+    check_time(t)?;
+    let (warped_1, valid_1) = backward_warp(frame_1, flow, -t);
+    let (warped_2, valid_2) = backward_warp(frame_2, flow, 1.0 - t);
 
-pub fn compensate(frame_1_filename: &str, frame_2_filename: &str, flow: &Vec<Vec<(f32, f32)>>) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-    let frame_1 = image::open(frame_1_filename).unwrap().into_rgb8();
-    let frame_2 = image::open(frame_2_filename).unwrap().into_rgb8();
     let (width, height) = frame_1.dimensions();
     let mut img: RgbImage = ImageBuffer::new(width, height);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        // A frame only contributes where its sample landed inside the image;
+        // where neither did, both clamped samples are blended as usual.
+        let index = (y * width + x) as usize;
+        let (weight_1, weight_2) = match (valid_1[index], valid_2[index]) {
+            (true, false) => (1.0, 0.0),
+            (false, true) => (0.0, 1.0),
+            _ => {
+                let (dx, dy) = flow.at_pixel(x, y);
+                visible_weights(flow, t, (x as f32 - t * dx, y as f32 - t * dy))
+            }
+        };
+        let color: [f32; 3] = std::array::from_fn(|c| {
+            weight_1 * warped_1[index][c] + weight_2 * warped_2[index][c]
+        });
+        *pixel = Rgb(color.map(|value| value.round().clamp(0.0, 255.0) as u8));
+    }
+    Ok(img)
+}
+
+fn visibility_at(flow: &FlowField, (x, y): (f32, f32)) -> Option<Visibility> {
+    flow.visibility_at_pixel(x.round().max(0.0) as u32, y.round().max(0.0) as u32)
+}
+
+// Weights of the two frames at time `t` for a vector whose sample in the
+// first frame is taken at `position_1`. The visibility mask lies on the first
+// frame's grid. Content the consistency check found in one frame only is
+// pulled from that frame alone instead of being blended with whatever covers
+// it in the other.
+fn visible_weights(flow: &FlowField, t: f32, position_1: (f32, f32)) -> (f32, f32) {
+    match visibility_at(flow, position_1) {
+        Some(Visibility::OnlyFirst) => (1.0, 0.0),
+        Some(Visibility::OnlySecond) => (0.0, 1.0),
+        _ => (1.0 - t, t),
+    }
+}
+
+// Samples `frame` at `p + scale * flow(p)` for every pixel `p`. The mask marks
+// pixels whose sample position fell inside the frame.
+fn backward_warp(
+    frame: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    flow: &FlowField,
+    scale: f32,
+) -> (Vec<[f32; 3]>, Vec<bool>) {
+    let (width, height) = frame.dimensions();
+    let mut samples = Vec::with_capacity((width * height) as usize);
+    let mut valid = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let (dx, dy) = flow.at_pixel(x, y);
+            let (sx, sy) = (x as f32 + scale * dx, y as f32 + scale * dy);
+            samples.push(sample_bilinear(frame, sx, sy));
+            valid.push(
+                (0.0..=(width - 1) as f32).contains(&sx) && (0.0..=(height - 1) as f32).contains(&sy),
+            );
+        }
+    }
+    (samples, valid)
+}
+
+fn scatter(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    flow: &FlowField,
+    t: f32,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let (width, height) = frame_1.dimensions();
+    let mut img: RgbImage = ImageBuffer::new(width, height);
+    let mut filled = vec![false; (width * height) as usize];
     
     for i in 0..height {
         for j in 0..width { 
-            let (mut fdx, mut fdy) = flow[i as usize][j as usize];
-            fdx /= 2.0; fdy /= 2.0;
-            let dx = fdx.round() as i32;
-            let dy = fdy.round() as i32;
+            let (fdx, fdy) = flow.at_pixel(j, i);
+            let dx = (fdx * t).round() as i32;
+            let dy = (fdy * t).round() as i32;
 
             let res_1_x = (j as i32 + dx) as u32;
             let res_1_y = (i as i32 + dy) as u32;
             if res_1_x < width && res_1_y < height {
-                if img.get_pixel(res_1_x, res_1_y) == &Rgb([0, 0, 0]) {
+                let index = (res_1_y * width + res_1_x) as usize;
+                if !filled[index] {
                     img.put_pixel(res_1_x, res_1_y, *frame_1.get_pixel(j, i));
+                    filled[index] = true;
                 } else {
                     img.put_pixel(
                         res_1_x, 
                         res_1_y, 
                         pixel_average(
                             *img.get_pixel(res_1_x, res_1_y), 
-                            *frame_2.get_pixel(j, i)
+                            *frame_1.get_pixel(j, i)
                         )
                     );
                 }
             }
+            let dx = (fdx * (1.0 - t)).round() as i32;
+            let dy = (fdy * (1.0 - t)).round() as i32;
             let res_2_x = (j as i32 - dx) as u32;
             let res_2_y = (i as i32 - dy) as u32;
             if res_2_x < width && res_2_y < height {
-                if img.get_pixel(res_2_x, res_2_y) == &Rgb([0, 0, 0]) {
+                let index = (res_2_y * width + res_2_x) as usize;
+                if !filled[index] {
                     img.put_pixel(res_2_x, res_2_y, *frame_2.get_pixel(j, i));
+                    filled[index] = true;
                 } else {
                     img.put_pixel(
                         res_2_x, 
@@ -52,7 +186,7 @@ pub fn compensate(frame_1_filename: &str, frame_2_filename: &str, flow: &Vec<Vec
 
     for i in 0..height {
         for j in 0..width { 
-            if img.get_pixel(j, i) == &Rgb([0, 0, 0]) {
+            if !filled[(i * width + j) as usize] {
                 img.put_pixel(
                     j, 
                     i, 
@@ -66,4 +200,167 @@ pub fn compensate(frame_1_filename: &str, frame_2_filename: &str, flow: &Vec<Vec
     }
 
     img
-}
\ No newline at end of file
+}
+
+// Raised-cosine weight of a block centered `distance` pixels away. Windows of
+// neighbouring blocks overlap by half and sum to one between their centers.
+fn raised_cosine(distance: f32, spacing: f32) -> f32 {
+    if distance >= spacing {
+        return 0.0;
+    }
+    0.5 * (1.0 + (PI * distance / spacing).cos())
+}
+
+// Overlapped block motion compensation: every pixel blends the predictions
+// of its own block's vector and its neighbours' vectors.
+fn obmc(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    flow: &FlowField,
+    t: f32,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let (width, height) = frame_1.dimensions();
+    let (grid_width, grid_height) = flow.dimensions();
+    let spacing = flow.spacing();
+    let block_center = |index: u32| (index * spacing) as f32 + (spacing - 1) as f32 / 2.0;
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let grid_x = (x / spacing).min(grid_width.saturating_sub(1));
+        let grid_y = (y / spacing).min(grid_height.saturating_sub(1));
+        let mut color = [0f32; 3];
+        let mut total_weight = 0f32;
+        for block_y in grid_y.saturating_sub(1)..(grid_y + 2).min(grid_height) {
+            for block_x in grid_x.saturating_sub(1)..(grid_x + 2).min(grid_width) {
+                let weight = raised_cosine((x as f32 - block_center(block_x)).abs(), spacing as f32)
+                    * raised_cosine((y as f32 - block_center(block_y)).abs(), spacing as f32);
+                if weight == 0.0 {
+                    continue;
+                }
+                let (dx, dy) = flow[(block_x, block_y)];
+                let position_1 = (x as f32 - t * dx, y as f32 - t * dy);
+                let position_2 = (x as f32 + (1.0 - t) * dx, y as f32 + (1.0 - t) * dy);
+                let (weight_1, weight_2) = visible_weights(flow, t, position_1);
+                let from_1 = sample_bilinear(frame_1, position_1.0, position_1.1);
+                let from_2 = sample_bilinear(frame_2, position_2.0, position_2.1);
+                for c in 0..3 {
+                    color[c] += weight * (weight_1 * from_1[c] + weight_2 * from_2[c]);
+                }
+                total_weight += weight;
+            }
+        }
+        if total_weight == 0.0 {
+            return pixel_average(frame_1[(x, y)], frame_2[(x, y)]);
+        }
+        Rgb(color.map(|value| (value / total_weight).round().clamp(0.0, 255.0) as u8))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant(value: u8) -> RgbImage {
+        ImageBuffer::from_pixel(16, 16, Rgb([value; 3]))
+    }
+
+    // Texture moved right by `shift` pixels.
+    fn textured(shift: f32) -> RgbImage {
+        ImageBuffer::from_fn(16, 16, |x, y| {
+            let u = x as f32 - shift;
+            Rgb([(60.0 + 8.0 * u + 3.0 * y as f32) as u8, (20.0 + 5.0 * u) as u8, 60])
+        })
+    }
+
+    #[test]
+    fn raised_cosine_windows_sum_to_one() {
+        for spacing in [4.0, 8.0, 16.0] {
+            let mut distance = 0.0;
+            while distance <= spacing {
+                let sum = raised_cosine(distance, spacing) + raised_cosine(spacing - distance, spacing);
+                assert!((sum - 1.0).abs() < 1e-6, "{} at {} of {}", sum, distance, spacing);
+                distance += 0.5;
+            }
+        }
+        assert_eq!(raised_cosine(0.0, 8.0), 1.0);
+        assert_eq!(raised_cosine(8.0, 8.0), 0.0);
+    }
+
+    #[test]
+    fn obmc_weights_frames_by_time() {
+        let flow = FlowField::new(4, 4, 4);
+        for (t, expected) in [(0.25, 50), (0.5, 100), (0.75, 150)] {
+            let img = interpolate_at(&constant(0), &constant(200), &flow, t, CompensationMethod::Obmc).unwrap();
+            assert!(img.pixels().all(|pixel| *pixel == Rgb([expected; 3])), "t = {}", t);
+        }
+    }
+
+    #[test]
+    fn obmc_follows_uniform_motion_without_seams() {
+        let vectors = vec![(4.0, 0.0); 16];
+        let flow = FlowField::from_vectors(4, 4, 4, vectors).unwrap();
+        let img = interpolate_at(&textured(0.0), &textured(4.0), &flow, 0.5, CompensationMethod::Obmc).unwrap();
+        let expected = textured(2.0);
+        for y in 0..16 {
+            for x in 2..14 {
+                assert_eq!(img[(x, y)], expected[(x, y)], "pixel {},{}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn backward_warp_weights_frames_by_time() {
+        let flow = FlowField::new(16, 16, 1);
+        for (t, expected) in [(0.25, 50), (0.5, 100), (0.9, 180)] {
+            let img = compensate_at(&constant(0), &constant(200), &flow, t).unwrap();
+            assert!(img.pixels().all(|pixel| *pixel == Rgb([expected; 3])), "t = {}", t);
+        }
+    }
+
+    #[test]
+    fn backward_warp_keeps_black_content() {
+        // A black square moves 4 pixels right over a grey background.
+        let frame = |left: u32| {
+            ImageBuffer::from_fn(16, 16, |x, y| {
+                if (left..left + 4).contains(&x) && (6..10).contains(&y) {
+                    Rgb([0; 3])
+                } else {
+                    Rgb([128; 3])
+                }
+            })
+        };
+        let vectors = (0..256)
+            .map(|index| {
+                let moving = (4..12).contains(&(index % 16)) && (6..10).contains(&(index / 16));
+                if moving { (4.0, 0.0) } else { (0.0, 0.0) }
+            })
+            .collect();
+        let flow = FlowField::from_vectors(16, 16, 1, vectors).unwrap();
+        let img = compensate_at(&frame(4), &frame(8), &flow, 0.5).unwrap();
+        for x in 6..10 {
+            assert_eq!(img[(x, 7)], Rgb([0; 3]), "pixel {} of the square", x);
+        }
+        assert_eq!(img[(1, 1)], Rgb([128; 3]));
+    }
+
+    #[test]
+    fn samples_outside_one_frame_come_from_the_other() {
+        // At the left border the frame-1 sample lies outside the image.
+        let flow = FlowField::from_vectors(16, 16, 1, vec![(4.0, 0.0); 256]).unwrap();
+        let img = compensate_at(&constant(0), &constant(200), &flow, 0.5).unwrap();
+        assert_eq!(img[(0, 5)], Rgb([200; 3]));
+        assert_eq!(img[(8, 5)], Rgb([100; 3]));
+        assert_eq!(img[(15, 5)], Rgb([0; 3]));
+    }
+
+    #[test]
+    fn scatter_averages_colliding_pixels_of_the_same_frame() {
+        // Pixels 0 and 1 of frame 1 both land on pixel 1 at t = 0.5.
+        let frame_1 = ImageBuffer::from_fn(4, 1, |x, _| Rgb([if x == 0 { 0 } else { 100 }; 3]));
+        let frame_2 = ImageBuffer::from_pixel(4, 1, Rgb([200; 3]));
+        let flow = FlowField::from_vectors(4, 1, 1, vec![(2.0, 0.0), (0.0, 0.0), (0.0, 0.0), (0.0, 0.0)]).unwrap();
+        let img = interpolate_at(&frame_1, &frame_2, &flow, 0.5, CompensationMethod::Scatter).unwrap();
+        // Frame 1 contributes 0 and 100, then frame 2's 200 is averaged in.
+        assert_eq!(img[(1, 0)], Rgb([125; 3]));
+        assert_eq!(img[(3, 0)], Rgb([150; 3]));
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt;
+use std::io;
+
+use image::ImageError;
+
+#[derive(Debug)]
+pub enum VfiError {
+    Io(io::Error),
+    Decoding(ImageError),
+    DimensionMismatch {
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+    InvalidSettings(String),
+    MissingInput(String),
+    ExternalEstimator(String),
+    MalformedFlow(String),
+}
+
+pub type Result<T> = std::result::Result<T, VfiError>;
+
+impl fmt::Display for VfiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VfiError::Io(err) => write!(f, "I/O error: {}", err),
+            VfiError::Decoding(err) => write!(f, "image error: {}", err),
+            VfiError::DimensionMismatch { expected, found } => write!(
+                f,
+                "dimension mismatch: expected {}x{}, found {}x{}",
+                expected.0, expected.1, found.0, found.1
+            ),
+            VfiError::InvalidSettings(message) => write!(f, "invalid settings: {}", message),
+            VfiError::MissingInput(message) => write!(f, "missing input: {}", message),
+            VfiError::ExternalEstimator(message) => {
+                write!(f, "external estimator failed: {}", message)
+            }
+            VfiError::MalformedFlow(message) => write!(f, "malformed flow file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for VfiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VfiError::Io(err) => Some(err),
+            VfiError::Decoding(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VfiError {
+    fn from(err: io::Error) -> VfiError {
+        VfiError::Io(err)
+    }
+}
+
+impl From<ImageError> for VfiError {
+    fn from(err: ImageError) -> VfiError {
+        match err {
+            ImageError::IoError(err) => VfiError::Io(err),
+            err => VfiError::Decoding(err),
+        }
+    }
+}
+
+pub(crate) fn check_dimensions(expected: (u32, u32), found: (u32, u32)) -> Result<()> {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(VfiError::DimensionMismatch { expected, found })
+    }
+}
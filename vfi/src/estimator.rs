@@ -2,9 +2,12 @@ pub mod combinator;
 
 use image::{ImageBuffer, Rgb};
 
+use crate::compensator::CompensationMethod;
+use crate::error::{check_dimensions, Result, VfiError};
+use crate::flow::FlowField;
 use crate::operations::scale_up;
 
-use self::combinator::{run_algo, AlgoSettings, Algorithm};
+use self::combinator::{run_algo, AlgoSettings, Algorithm, BlockRegion, BmaLayout, SearchStats};
 
 pub struct Estimator {
     frame_1: Option<ImageBuffer<Rgb<u8>, Vec<u8>>>,
@@ -12,20 +15,35 @@ pub struct Estimator {
     frame_1_filename: Option<String>,
     frame_2_filename: Option<String>,
     algorithm: Algorithm,
+    search_stats: Option<SearchStats>,
+    partition: Option<Vec<BlockRegion>>,
+    previous_flow: Option<FlowField>,
     pub settings: AlgoSettings,
     pub description: String,
+    // Compensator the tester interpolates this estimator's flow with.
+    pub compensation: CompensationMethod,
+}
+
+impl Default for Estimator {
+    fn default() -> Estimator {
+        Estimator::new()
+    }
 }
 
 impl Estimator {
     pub fn new() -> Estimator {
         Estimator {
             algorithm: Algorithm::BlockMatching,
+            search_stats: None,
+            partition: None,
+            previous_flow: None,
             settings: AlgoSettings::default(),
             frame_1: None,
             frame_2: None,
             frame_1_filename: None,
             frame_2_filename: None,
             description: "Default description".to_string(),
+            compensation: CompensationMethod::Scatter,
         }
     }
 
@@ -33,42 +51,85 @@ impl Estimator {
         &mut self,
         frame_1: ImageBuffer<Rgb<u8>, Vec<u8>>,
         frame_2: ImageBuffer<Rgb<u8>, Vec<u8>>,
-    ) {
-        assert_eq!(frame_1.dimensions(), frame_2.dimensions());
+    ) -> Result<()> {
+        check_dimensions(frame_1.dimensions(), frame_2.dimensions())?;
+        if frame_1.width() == 0 || frame_1.height() == 0 {
+            return Err(VfiError::InvalidSettings("frames must not be empty".to_string()));
+        }
         self.frame_1 = Some(frame_1);
         self.frame_2 = Some(frame_2);
+        self.frame_1_filename = None;
+        self.frame_2_filename = None;
+        Ok(())
     }
 
     pub fn set_frames_from_files(
         &mut self,
         frame_1_filename: &str,
         frame_2_filename: &str,
-    ) {
+    ) -> Result<()> {
+        let frame_1 = image::open(frame_1_filename)?.into_rgb8();
+        let frame_2 = image::open(frame_2_filename)?.into_rgb8();
+        self.set_frames_directly(frame_1, frame_2)?;
         self.frame_1_filename = Some(String::from(frame_1_filename));
         self.frame_2_filename = Some(String::from(frame_2_filename));
-        let frame_1 = image::open(frame_1_filename).unwrap().into_rgb8();
-        let frame_2 = image::open(frame_2_filename).unwrap().into_rgb8();
-        self.set_frames_directly(frame_1, frame_2);
+        Ok(())
     }
 
-    pub fn get_frames_size(&self) -> (u32, u32) {
-        self.frame_1.as_ref().unwrap().dimensions()
+    pub fn get_frames_size(&self) -> Result<(u32, u32)> {
+        self.frame_1
+            .as_ref()
+            .map(|frame| frame.dimensions())
+            .ok_or_else(|| VfiError::MissingInput("no frames have been set".to_string()))
     }
 
     pub fn set_algorithm(&mut self, algo: Algorithm) {
         self.algorithm = algo;
     }
 
-    pub fn estimate_motion(&mut self) -> Vec<Vec<(f32, f32)>> {
-        let flow = run_algo(
+    // Flow of the previous frame pair, used as a temporal predictor when
+    // `BmaSettings::use_predictors` is set.
+    pub fn set_previous_flow(&mut self, flow: Option<FlowField>) {
+        self.previous_flow = flow;
+    }
+
+    pub fn search_stats(&self) -> Option<SearchStats> {
+        self.search_stats
+    }
+
+    // Leaf blocks of the last quadtree estimate.
+    pub fn partition(&self) -> Option<&[BlockRegion]> {
+        self.partition.as_deref()
+    }
+
+    pub fn validate(&self) -> Result<Option<BmaLayout>> {
+        let (width, height) = self.get_frames_size()?;
+        self.settings.validate(&self.algorithm, width, height)
+    }
+
+    pub fn estimate_block_motion(&mut self) -> Result<FlowField> {
+        self.validate()?;
+        let (frame_1, frame_2) = match (self.frame_1.as_ref(), self.frame_2.as_ref()) {
+            (Some(frame_1), Some(frame_2)) => (frame_1, frame_2),
+            _ => return Err(VfiError::MissingInput("no frames have been set".to_string())),
+        };
+        let output = run_algo(
             &self.algorithm,
-            self.frame_1.as_ref().unwrap(),
-            self.frame_2.as_ref().unwrap(),
-            self.frame_1_filename.clone().unwrap(),
-            self.frame_2_filename.clone().unwrap(),
+            frame_1,
+            frame_2,
+            self.frame_1_filename.as_deref(),
+            self.frame_2_filename.as_deref(),
+            self.previous_flow.as_ref(),
             &mut self.settings,
-        );
-        let scale_factor = self.get_frames_size().0 / flow[0].len() as u32;
-        scale_up(flow, scale_factor)
+        )?;
+        self.search_stats = output.search_stats;
+        self.partition = output.partition;
+        Ok(output.flow)
+    }
+
+    pub fn estimate_motion(&mut self) -> Result<FlowField> {
+        let flow = self.estimate_block_motion()?;
+        let (width, height) = self.get_frames_size()?;
+        Ok(scale_up(&flow, width, height))
     }
 }
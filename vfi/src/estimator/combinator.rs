@@ -3,154 +3,375 @@ mod bma;
 mod smoothing;
 mod convert_to_grayscale;
 mod conv_edges;
+mod external;
+mod farneback;
+mod float_image;
+mod horn_schunck;
+mod lucas_kanade;
+mod phase_correlation;
+mod tv_l1;
 
 use image::{ImageBuffer, Rgb};
-use std::process::Command;
+use num::integer::div_ceil;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::error::{check_dimensions, Result, VfiError};
+use crate::flow::FlowField;
 
 use self::{
-    bidirectional_flow::combine_bidirectional_flows,
-    bma::{BmaSettings, BMA},
-    smoothing::{smooth_error_flow, SmoothingSettings}, convert_to_grayscale::to_grayscale, conv_edges::get_conv_edges,
+    bidirectional_flow::{check_consistency, combine_bidirectional_flows, combine_consistent_flows},
+    bma::BMA,
+    external::ExternalCommand,
+    smoothing::smooth_error_flow, convert_to_grayscale::to_grayscale, conv_edges::get_conv_edges,
+};
+
+pub use self::{
+    bma::{
+        BlockRegion, BmaLayout, BmaSettings, MatchCost, MatchCostKind, QuadtreeSettings,
+        SearchStats, SearchStrategy, SubpixelMethod, SubpixelPrecision,
+    },
+    bidirectional_flow::OcclusionSettings,
+    external::FlowPayload,
+    farneback::FarnebackSettings,
+    horn_schunck::HornSchunckSettings,
+    lucas_kanade::{detect_features, track_features, LucasKanadeMode, LucasKanadeSettings},
+    phase_correlation::{PhaseCorrelationMode, PhaseCorrelationSettings},
+    smoothing::SmoothingSettings,
+    tv_l1::TvL1Settings,
 };
 
-#[allow(dead_code)]
 pub enum Algorithm {
     BlockMatching,
     BidirectionalBlockMatching,
     SmoothedBlockMatching,
     SmoothedBidirectionalBlockMatching,
+    QuadtreeBlockMatching,
     LucasKanade,
+    OpenCvLucasKanade,
     GunnarFarneback,
+    OpenCvGunnarFarneback,
+    HornSchunck,
+    TvL1,
+    PhaseCorrelation,
+    /// Any executable speaking the plugin protocol described in the README.
+    External {
+        executable: PathBuf,
+        arguments: Vec<String>,
+        payload: FlowPayload,
+        timeout: Option<Duration>,
+    },
+}
+
+impl Algorithm {
+    pub fn uses_block_matching(&self) -> bool {
+        matches!(
+            self,
+            Algorithm::BlockMatching
+                | Algorithm::BidirectionalBlockMatching
+                | Algorithm::SmoothedBlockMatching
+                | Algorithm::SmoothedBidirectionalBlockMatching
+                | Algorithm::QuadtreeBlockMatching
+        )
+    }
+
+    pub fn uses_smoothing(&self) -> bool {
+        matches!(
+            self,
+            Algorithm::SmoothedBlockMatching | Algorithm::SmoothedBidirectionalBlockMatching
+        )
+    }
+
+    pub fn uses_quadtree(&self) -> bool {
+        matches!(self, Algorithm::QuadtreeBlockMatching)
+    }
+
+    fn external_command(&self) -> Option<ExternalCommand<'_>> {
+        match self {
+            Algorithm::External {
+                executable,
+                arguments,
+                payload,
+                timeout,
+            } => Some(ExternalCommand {
+                executable,
+                arguments,
+                payload: *payload,
+                timeout: *timeout,
+            }),
+            _ => None,
+        }
+    }
 }
 
+#[derive(Default)]
 pub struct AlgoSettings {
     pub verbose: bool,
     pub block_matching: BmaSettings,
     pub smoothing: SmoothingSettings,
+    pub quadtree: QuadtreeSettings,
+    pub occlusion: OcclusionSettings,
+    pub lucas_kanade: LucasKanadeSettings,
+    pub farneback: FarnebackSettings,
+    pub horn_schunck: HornSchunckSettings,
+    pub tv_l1: TvL1Settings,
+    pub phase_correlation: PhaseCorrelationSettings,
     pub grayscale: bool,
     pub conv_edges: bool,
 }
 
-impl AlgoSettings {
-    pub fn default() -> AlgoSettings {
-        AlgoSettings {
-            verbose: false,
-            block_matching: BmaSettings::default(),
-            smoothing: SmoothingSettings::default(),
-            grayscale: false,
-            conv_edges: false,
+pub struct AlgoOutput {
+    pub flow: FlowField,
+    pub search_stats: Option<SearchStats>,
+    pub partition: Option<Vec<BlockRegion>>,
+}
+
+impl From<FlowField> for AlgoOutput {
+    fn from(flow: FlowField) -> AlgoOutput {
+        AlgoOutput {
+            flow,
+            search_stats: None,
+            partition: None,
         }
     }
 }
 
-fn remove_flow_error_data(error_flow: &Vec<Vec<(i16, i16, u32)>>) -> Vec<Vec<(i16, i16)>> {
-    let mut flow = vec![vec![(0i16, 0i16); error_flow[0].len()]; error_flow.len()];
-    for i in 0..error_flow.len() {
-        for j in 0..error_flow[i].len() {
-            flow[i][j] = (error_flow[i][j].0, error_flow[i][j].1);
+impl AlgoSettings {
+    pub fn validate(&self, algo: &Algorithm, width: u32, height: u32) -> Result<Option<BmaLayout>> {
+        match algo {
+            Algorithm::LucasKanade => self.lucas_kanade.validate()?,
+            Algorithm::GunnarFarneback => self.farneback.validate()?,
+            Algorithm::HornSchunck => self.horn_schunck.validate()?,
+            Algorithm::TvL1 => self.tv_l1.validate()?,
+            Algorithm::PhaseCorrelation => self.validate_phase_correlation(width, height)?,
+            Algorithm::External { .. } => algo.external_command().unwrap().validate()?,
+            _ => {}
+        }
+        if !algo.uses_block_matching() {
+            return Ok(None);
         }
+        self.occlusion.validate()?;
+        let layout = self.block_matching.layout(width, height)?;
+        if algo.uses_smoothing() {
+            self.smoothing
+                .validate_for_grid(layout.grid_width, layout.grid_height)?;
+        }
+        if algo.uses_quadtree() {
+            if self.block_matching.pyramid_levels > 1 {
+                return Err(VfiError::InvalidSettings(
+                    "quadtree block matching does not support pyramid_levels above 1".to_string(),
+                ));
+            }
+            self.quadtree
+                .validate_for_block_width(self.block_matching.block_width)?;
+            self.quadtree
+                .validate_for_match_cost(self.block_matching.match_cost)?;
+        }
+        Ok(Some(layout))
     }
-    flow
-}
 
-fn convert_int_flow_to_float(int_flow: &Vec<Vec<(i16, i16)>>) -> Vec<Vec<(f32, f32)>> {
-    let mut flow = vec![vec![(0f32, 0f32); int_flow[0].len() as usize]; int_flow.len() as usize];
-    for i in 0..int_flow.len() {
-        for j in 0..int_flow[0].len() {
-            flow[i][j] = (int_flow[i][j].0 as f32, int_flow[i][j].1 as f32);
+    // Phase correlation fills the block matching grid, so the smoothing
+    // window must fit that grid.
+    fn validate_phase_correlation(&self, width: u32, height: u32) -> Result<()> {
+        let block_width = self.block_matching.block_width;
+        self.phase_correlation.validate_for_block_width(block_width)?;
+        if self.phase_correlation.smoothed {
+            self.smoothing
+                .validate_for_grid(div_ceil(width, block_width), div_ceil(height, block_width))?;
         }
+        if self.phase_correlation.bidirectional {
+            self.occlusion.validate()?;
+        }
+        Ok(())
+    }
+}
+
+fn remove_flow_error_data(error_flow: &[Vec<(f32, f32, f32)>]) -> Vec<Vec<(f32, f32)>> {
+    error_flow
+        .iter()
+        .map(|row| row.iter().map(|&(dx, dy, _)| (dx, dy)).collect())
+        .collect()
+}
+
+fn block_flow_to_field(block_flow: Vec<Vec<(f32, f32)>>, settings: &AlgoSettings) -> Result<FlowField> {
+    FlowField::from_nested(block_flow, settings.block_matching.block_width)
+}
+
+// The backward pass of bidirectional matching predicts from negated vectors.
+fn reverse_flow(flow: &FlowField) -> FlowField {
+    flow.map(|(dx, dy)| (-dx, -dy))
+}
+
+// Combines both directions and, when enabled, marks blocks whose vectors do
+// not round-trip as visible in one frame only.
+fn bidirectional_flow_to_field(
+    forward_flow: &[Vec<(f32, f32)>],
+    backward_flow: &[Vec<(f32, f32)>],
+    settings: &AlgoSettings,
+) -> Result<FlowField> {
+    if !settings.occlusion.detect {
+        return block_flow_to_field(
+            combine_bidirectional_flows(forward_flow, backward_flow),
+            settings,
+        );
     }
-    flow
+    let (visibility, confidence) = check_consistency(
+        forward_flow,
+        backward_flow,
+        settings.block_matching.block_width,
+        settings.occlusion.max_consistency_error,
+    );
+    let flow = block_flow_to_field(
+        combine_consistent_flows(forward_flow, backward_flow, &visibility),
+        settings,
+    )?;
+    flow.with_visibility(visibility)?.with_confidence(confidence)
 }
 
 fn get_bma(settings: &mut AlgoSettings) -> BMA {
     let mut bma = BMA::new();
-    let mut algo_settings = &mut settings.block_matching;
+    let algo_settings = &mut settings.block_matching;
     algo_settings.verbose = settings.verbose;
     bma.apply_settings(algo_settings);
     bma
 }
 
+fn bma_output(flow: FlowField, bma: &BMA) -> AlgoOutput {
+    AlgoOutput {
+        flow,
+        search_stats: Some(bma.search_stats()),
+        partition: None,
+    }
+}
+
 fn run_bma(
     frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
     frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    previous_flow: Option<&FlowField>,
     settings: &mut AlgoSettings,
-) -> Vec<Vec<(f32, f32)>> {
+) -> Result<AlgoOutput> {
     let bma = get_bma(settings);
-    let int_flow = remove_flow_error_data(&bma.calc_flow(frame_1, frame_2));
-    convert_int_flow_to_float(&int_flow)
+    let block_flow = remove_flow_error_data(&bma.calc_flow(frame_1, frame_2, previous_flow));
+    let flow = block_flow_to_field(block_flow, settings)?;
+    Ok(bma_output(flow, &bma))
 }
 
 fn run_bidirectional_bma(
     frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
     frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    previous_flow: Option<&FlowField>,
     settings: &mut AlgoSettings,
-) -> Vec<Vec<(f32, f32)>> {
+) -> Result<AlgoOutput> {
     let bma = get_bma(settings);
-    let forward_flow = remove_flow_error_data(&bma.calc_flow(frame_1, frame_2));
-    let backward_flow = remove_flow_error_data(&bma.calc_flow(frame_2, frame_1));
-    convert_int_flow_to_float(&combine_bidirectional_flows(&forward_flow, &backward_flow))
+    let reversed_flow = previous_flow.map(reverse_flow);
+    let forward_flow = remove_flow_error_data(&bma.calc_flow(frame_1, frame_2, previous_flow));
+    let backward_flow = remove_flow_error_data(&bma.calc_flow(frame_2, frame_1, reversed_flow.as_ref()));
+    let flow = bidirectional_flow_to_field(&forward_flow, &backward_flow, settings)?;
+    Ok(bma_output(flow, &bma))
 }
 
 fn run_smoothed_bma(
     frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
     frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    previous_flow: Option<&FlowField>,
     settings: &mut AlgoSettings,
-) -> Vec<Vec<(f32, f32)>> {
+) -> Result<AlgoOutput> {
     let bma = get_bma(settings);
-    let error_flow = bma.calc_flow(frame_1, frame_2);
-    convert_int_flow_to_float(&smooth_error_flow(&error_flow, settings.smoothing.filter_window_width))
+    let error_flow = bma.calc_flow(frame_1, frame_2, previous_flow);
+    let flow = block_flow_to_field(
+        smooth_error_flow(&error_flow, settings.smoothing.filter_window_width),
+        settings,
+    )?;
+    Ok(bma_output(flow, &bma))
 }
 
 fn run_smoothed_bidirectional_bma(
     frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
     frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    previous_flow: Option<&FlowField>,
     settings: &mut AlgoSettings,
-) -> Vec<Vec<(f32, f32)>> {
+) -> Result<AlgoOutput> {
     let bma = get_bma(settings);
-    let forward_error_flow = bma.calc_flow(frame_1, frame_2);
-    let backward_error_flow = bma.calc_flow(frame_2, frame_1);
+    let reversed_flow = previous_flow.map(reverse_flow);
+    let forward_error_flow = bma.calc_flow(frame_1, frame_2, previous_flow);
+    let backward_error_flow = bma.calc_flow(frame_2, frame_1, reversed_flow.as_ref());
     let forward_flow =
         smooth_error_flow(&forward_error_flow, settings.smoothing.filter_window_width);
     let backward_flow =
         smooth_error_flow(&backward_error_flow, settings.smoothing.filter_window_width);
-    convert_int_flow_to_float(&combine_bidirectional_flows(&forward_flow, &backward_flow))
-}
-
-fn run_executable(exe_name: &str, frame_1_filename: String, frame_2_filename: String)  -> Vec<Vec<(f32, f32)>> {
-    let output = Command::new(format!("./src/executables/{}", exe_name))
-    .arg(frame_1_filename)
-    .arg(frame_2_filename)
-    .output()
-    .expect("failed to execute process");
-    let output = String::from_utf8_lossy(&output.stdout);
-    let mut lines = output.lines();
-
-    let mut parts = lines.next().unwrap().split_whitespace().map(|s| s.parse::<u32>());
-    let height = parts.next().unwrap().unwrap();
-    let width = parts.next().unwrap().unwrap();
-    
-    let mut flow = vec![vec![(0f32, 0f32); width as usize]; height as usize];
-    for i in 0..height as usize {
-        for j in 0..width as usize {
-            let mut offset = lines.next().unwrap().split_whitespace().map(|s| s.parse::<f32>());
-            let offset_x = offset.next().unwrap().unwrap();
-            let offset_y = offset.next().unwrap().unwrap();
-            flow[i][j] = (offset_x, offset_y);
+    let flow = bidirectional_flow_to_field(&forward_flow, &backward_flow, settings)?;
+    Ok(bma_output(flow, &bma))
+}
+
+fn run_quadtree_bma(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    previous_flow: Option<&FlowField>,
+    settings: &mut AlgoSettings,
+) -> Result<AlgoOutput> {
+    let bma = get_bma(settings);
+    let (flow, partition) = bma.calc_quadtree_flow(frame_1, frame_2, &settings.quadtree, previous_flow);
+    Ok(AlgoOutput {
+        partition: Some(partition),
+        ..bma_output(flow, &bma)
+    })
+}
+
+fn run_phase_correlation(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    settings: &AlgoSettings,
+) -> Result<AlgoOutput> {
+    let pc_settings = &settings.phase_correlation;
+    let block_flow = |from, to| {
+        let error_flow = phase_correlation::calc_flow(from, to, settings.block_matching.block_width, pc_settings);
+        if pc_settings.smoothed {
+            smooth_error_flow(&error_flow, settings.smoothing.filter_window_width)
+        } else {
+            remove_flow_error_data(&error_flow)
         }
-    }
+    };
+    let forward_flow = block_flow(frame_1, frame_2);
+    let flow = if pc_settings.bidirectional {
+        bidirectional_flow_to_field(&forward_flow, &block_flow(frame_2, frame_1), settings)?
+    } else {
+        block_flow_to_field(forward_flow, settings)?
+    };
+    Ok(flow.into())
+}
+
+// A plugin reports the flow size itself, so it is checked against the frames
+// before anything indexes the field with pixel coordinates.
+fn external_output(flow: FlowField, frame_dimensions: (u32, u32)) -> Result<AlgoOutput> {
+    check_dimensions(frame_dimensions, flow.dimensions())?;
+    Ok(flow.into())
+}
 
-    flow
+fn run_opencv_executable(
+    exe_name: &str,
+    frame_dimensions: (u32, u32),
+    frame_1_filename: Option<&str>,
+    frame_2_filename: Option<&str>,
+) -> Result<AlgoOutput> {
+    let executable = PathBuf::from(format!("./src/executables/{}", exe_name));
+    let command = ExternalCommand {
+        executable: &executable,
+        arguments: &[],
+        payload: FlowPayload::Text,
+        timeout: None,
+    };
+    external_output(command.run(frame_1_filename, frame_2_filename)?, frame_dimensions)
 }
 
 pub fn run_algo(
     algo: &Algorithm,
     frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
     frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
-    frame_1_filename: String,
-    frame_2_filename: String,
+    frame_1_filename: Option<&str>,
+    frame_2_filename: Option<&str>,
+    previous_flow: Option<&FlowField>,
     settings: &mut AlgoSettings,
-) -> Vec<Vec<(f32, f32)>> {
+) -> Result<AlgoOutput> {
     let res_frame_1: &mut ImageBuffer<Rgb<u8>, Vec<u8>> = &mut frame_1.clone();
     let res_frame_2: &mut ImageBuffer<Rgb<u8>, Vec<u8>> = &mut frame_2.clone();
     if settings.grayscale {
@@ -162,13 +383,23 @@ pub fn run_algo(
         get_conv_edges(res_frame_2);
     }
     match algo {
-        Algorithm::BlockMatching => run_bma(res_frame_1, res_frame_2, settings),
-        Algorithm::BidirectionalBlockMatching => run_bidirectional_bma(res_frame_1, res_frame_2, settings),
-        Algorithm::SmoothedBlockMatching => run_smoothed_bma(res_frame_1, res_frame_2, settings),
+        Algorithm::BlockMatching => run_bma(res_frame_1, res_frame_2, previous_flow, settings),
+        Algorithm::BidirectionalBlockMatching => run_bidirectional_bma(res_frame_1, res_frame_2, previous_flow, settings),
+        Algorithm::SmoothedBlockMatching => run_smoothed_bma(res_frame_1, res_frame_2, previous_flow, settings),
         Algorithm::SmoothedBidirectionalBlockMatching => {
-            run_smoothed_bidirectional_bma(res_frame_1, res_frame_2, settings)
+            run_smoothed_bidirectional_bma(res_frame_1, res_frame_2, previous_flow, settings)
         }
-        Algorithm::LucasKanade => run_executable("lucas_kanade", frame_1_filename, frame_2_filename),
-        Algorithm::GunnarFarneback => run_executable("farneback", frame_1_filename, frame_2_filename)
+        Algorithm::QuadtreeBlockMatching => run_quadtree_bma(res_frame_1, res_frame_2, previous_flow, settings),
+        Algorithm::LucasKanade => Ok(lucas_kanade::calc_flow(res_frame_1, res_frame_2, &settings.lucas_kanade).into()),
+        Algorithm::OpenCvLucasKanade => run_opencv_executable("lucas_kanade", frame_1.dimensions(), frame_1_filename, frame_2_filename),
+        Algorithm::GunnarFarneback => Ok(farneback::calc_flow(res_frame_1, res_frame_2, &settings.farneback).into()),
+        Algorithm::OpenCvGunnarFarneback => run_opencv_executable("farneback", frame_1.dimensions(), frame_1_filename, frame_2_filename),
+        Algorithm::HornSchunck => Ok(horn_schunck::calc_flow(res_frame_1, res_frame_2, &settings.horn_schunck).into()),
+        Algorithm::TvL1 => Ok(tv_l1::calc_flow(res_frame_1, res_frame_2, &settings.tv_l1).into()),
+        Algorithm::PhaseCorrelation => run_phase_correlation(res_frame_1, res_frame_2, settings),
+        Algorithm::External { .. } => external_output(
+            algo.external_command().unwrap().run(frame_1_filename, frame_2_filename)?,
+            frame_1.dimensions(),
+        ),
     }
 }
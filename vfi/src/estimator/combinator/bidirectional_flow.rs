@@ -1,17 +1,214 @@
+use crate::error::{Result, VfiError};
+use crate::flow::Visibility;
+
 pub fn combine_bidirectional_flows(
-    forward_flow: &Vec<Vec<(i16, i16)>>,
-    backward_flow: &Vec<Vec<(i16, i16)>>,
-) -> Vec<Vec<(i16, i16)>> {
+    forward_flow: &[Vec<(f32, f32)>],
+    backward_flow: &[Vec<(f32, f32)>],
+) -> Vec<Vec<(f32, f32)>> {
     assert_eq!(forward_flow.len(), backward_flow.len());
     assert_eq!(forward_flow[0].len(), backward_flow[0].len());
 
-    let mut flow = vec![vec![(0i16, 0i16); forward_flow[0].len()]; forward_flow.len()];
+    let mut flow = vec![vec![(0f32, 0f32); forward_flow[0].len()]; forward_flow.len()];
     for i in 0..forward_flow.len() {
         for j in 0..forward_flow[0].len() {
-            let fx = (forward_flow[i][j].0 - backward_flow[i][j].0) / 2;
-            let fy = (forward_flow[i][j].1 - backward_flow[i][j].1) / 2;
+            let fx = (forward_flow[i][j].0 - backward_flow[i][j].0) / 2.0;
+            let fy = (forward_flow[i][j].1 - backward_flow[i][j].1) / 2.0;
             flow[i][j] = (fx, fy);
         }
     }
     flow
 }
+
+// Like `combine_bidirectional_flows`, but where one direction fails the
+// round trip only the other direction's vector is kept.
+pub fn combine_consistent_flows(
+    forward_flow: &[Vec<(f32, f32)>],
+    backward_flow: &[Vec<(f32, f32)>],
+    visibility: &[Visibility],
+) -> Vec<Vec<(f32, f32)>> {
+    let mut flow = combine_bidirectional_flows(forward_flow, backward_flow);
+    let width = forward_flow[0].len();
+    for (i, row) in flow.iter_mut().enumerate() {
+        for (j, vector) in row.iter_mut().enumerate() {
+            match visibility[i * width + j] {
+                Visibility::OnlyFirst => {
+                    let (dx, dy) = backward_flow[i][j];
+                    *vector = (-dx, -dy);
+                }
+                Visibility::OnlySecond => *vector = forward_flow[i][j],
+                Visibility::Both => {}
+            }
+        }
+    }
+    flow
+}
+
+pub struct OcclusionSettings {
+    pub detect: bool,
+    pub max_consistency_error: f32,
+}
+
+impl Default for OcclusionSettings {
+    fn default() -> OcclusionSettings {
+        OcclusionSettings {
+            detect: false,
+            max_consistency_error: 2.0,
+        }
+    }
+}
+
+impl OcclusionSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.detect && (self.max_consistency_error.is_nan() || self.max_consistency_error <= 0.0) {
+            return Err(VfiError::InvalidSettings(format!(
+                "max_consistency_error must be positive, got {}",
+                self.max_consistency_error
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Block of the opposite frame that the vector of block `(i, j)` points into.
+fn target_block(flow: &[Vec<(f32, f32)>], spacing: u32, i: usize, j: usize) -> (usize, usize) {
+    let (dx, dy) = flow[i][j];
+    let center = (spacing as f32 - 1.0) / 2.0;
+    let target_x = j as f32 * spacing as f32 + center + dx;
+    let target_y = i as f32 * spacing as f32 + center + dy;
+    let target_j = ((target_x / spacing as f32).floor().max(0.0) as usize).min(flow[0].len() - 1);
+    let target_i = ((target_y / spacing as f32).floor().max(0.0) as usize).min(flow.len() - 1);
+    (target_i, target_j)
+}
+
+// Length of `flow(p) + other(p + flow(p))`, which is zero when following a
+// vector and then the opposite field's vector leads back to the start.
+fn round_trip_error(
+    flow: &[Vec<(f32, f32)>],
+    other: &[Vec<(f32, f32)>],
+    spacing: u32,
+    i: usize,
+    j: usize,
+) -> f32 {
+    let (dx, dy) = flow[i][j];
+    let (target_i, target_j) = target_block(flow, spacing, i, j);
+    let (back_x, back_y) = other[target_i][target_j];
+    ((dx + back_x).powi(2) + (dy + back_y).powi(2)).sqrt()
+}
+
+/// Forward-backward consistency check between a `frame_1 -> frame_2` and a
+/// `frame_2 -> frame_1` block flow with `spacing` pixel blocks.
+///
+/// Content whose forward vector does not round-trip is occluded in the second
+/// frame, content whose backward vector does not is disoccluded. Returns the
+/// visibility and a confidence in (0, 1] per block of the first frame.
+pub fn check_consistency(
+    forward_flow: &[Vec<(f32, f32)>],
+    backward_flow: &[Vec<(f32, f32)>],
+    spacing: u32,
+    max_error: f32,
+) -> (Vec<Visibility>, Vec<f32>) {
+    let width = forward_flow[0].len();
+    // A backward error belongs to a block of the second frame. It is moved
+    // along that block's vector to the first frame block it points into, and
+    // the worst error landing on a block is kept.
+    let mut backward_errors = vec![0f32; forward_flow.len() * width];
+    for i in 0..backward_flow.len() {
+        for j in 0..width {
+            let error = round_trip_error(backward_flow, forward_flow, spacing, i, j);
+            let (target_i, target_j) = target_block(backward_flow, spacing, i, j);
+            let target = &mut backward_errors[target_i * width + target_j];
+            *target = target.max(error);
+        }
+    }
+
+    let mut visibility = Vec::with_capacity(backward_errors.len());
+    let mut confidence = Vec::with_capacity(backward_errors.len());
+    for i in 0..forward_flow.len() {
+        for j in 0..width {
+            let forward_error = round_trip_error(forward_flow, backward_flow, spacing, i, j);
+            let backward_error = backward_errors[i * width + j];
+            visibility.push(match (forward_error > max_error, backward_error > max_error) {
+                (true, false) => Visibility::OnlyFirst,
+                (false, true) => Visibility::OnlySecond,
+                // Neither direction round-trips, so the one that comes closer
+                // is trusted. A pair of blocks pointing at each other fails
+                // both checks equally and says nothing about either frame.
+                (true, true) if forward_error < backward_error => Visibility::OnlySecond,
+                (true, true) if backward_error < forward_error => Visibility::OnlyFirst,
+                _ => Visibility::Both,
+            });
+            let error = forward_error.max(backward_error) / max_error;
+            confidence.push((-0.5 * error * error).exp());
+        }
+    }
+    (visibility, confidence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A row of four 4-pixel blocks, checked with a 2-pixel tolerance.
+    fn check(forward: [(f32, f32); 4], backward: [(f32, f32); 4]) -> (Vec<Visibility>, Vec<f32>) {
+        check_consistency(&[forward.to_vec()], &[backward.to_vec()], 4, 2.0)
+    }
+
+    fn combine(forward: [(f32, f32); 4], backward: [(f32, f32); 4]) -> Vec<(f32, f32)> {
+        let (visibility, _) = check(forward, backward);
+        combine_consistent_flows(&[forward.to_vec()], &[backward.to_vec()], &visibility).remove(0)
+    }
+
+    #[test]
+    fn consistent_pair_is_visible_in_both_frames() {
+        let forward = [(4.0, 1.0); 4];
+        let backward = [(-4.0, -1.0); 4];
+        let (visibility, confidence) = check(forward, backward);
+        assert_eq!(visibility, [Visibility::Both; 4]);
+        assert_eq!(confidence, [1.0; 4]);
+        assert_eq!(combine(forward, backward), forward);
+    }
+
+    #[test]
+    fn occluded_block_is_taken_from_the_first_frame() {
+        // Block 1 moves onto block 2, which stays put and covers it.
+        let forward = [(0.0, 0.0), (4.0, 0.0), (0.0, 0.0), (0.0, 0.0)];
+        let backward = [(0.0, 0.0), (-1.0, 0.0), (0.0, 0.0), (0.0, 0.0)];
+        let (visibility, confidence) = check(forward, backward);
+        assert_eq!(visibility, [Visibility::Both, Visibility::OnlyFirst, Visibility::Both, Visibility::Both]);
+        assert_eq!(confidence[0], 1.0);
+        assert!((confidence[1] - (-2.0f32).exp()).abs() < 1e-6);
+        // The failed forward vector gives way to the reversed backward one.
+        assert_eq!(combine(forward, backward)[1], (1.0, 0.0));
+    }
+
+    #[test]
+    fn disoccluded_block_is_taken_from_the_second_frame() {
+        // Blocks 2 and 3 move right, uncovering content in block 2 of the
+        // second frame that points back into block 1, whose own vector
+        // stays put.
+        let forward = [(0.0, 0.0), (1.0, 0.0), (4.0, 0.0), (4.0, 0.0)];
+        let backward = [(0.0, 0.0), (0.0, 0.0), (-4.0, 0.0), (-4.0, 0.0)];
+        let (visibility, confidence) = check(forward, backward);
+        assert_eq!(visibility, [Visibility::Both, Visibility::OnlySecond, Visibility::Both, Visibility::Both]);
+        assert!((confidence[1] - (-0.5f32 * 1.5 * 1.5).exp()).abs() < 1e-6);
+        assert_eq!(confidence[2], 1.0);
+        assert_eq!(combine(forward, backward)[1], (1.0, 0.0));
+    }
+
+    #[test]
+    fn both_failing_trusts_the_closer_direction() {
+        // Block 1's forward vector misses by 4, the backward vector landing
+        // on it misses by 8.
+        let forward = [(0.0, 0.0), (4.0, 0.0), (0.0, 0.0), (0.0, 0.0)];
+        let backward = [(4.0, 0.0), (0.0, 0.0), (0.0, 0.0), (0.0, 0.0)];
+        let (visibility, confidence) = check(forward, backward);
+        assert_eq!(visibility[1], Visibility::OnlySecond);
+        assert!((confidence[1] - (-8.0f32).exp()).abs() < 1e-6);
+        assert_eq!(combine(forward, backward)[1], (4.0, 0.0));
+
+        // Equal errors tell neither frame apart.
+        let backward = [(4.0, 0.0), (0.0, 0.0), (4.0, 0.0), (0.0, 0.0)];
+        let (visibility, _) = check(forward, backward);
+        assert_eq!(visibility[1], Visibility::Both);
+    }
+}
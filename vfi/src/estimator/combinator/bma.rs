@@ -1,23 +1,61 @@
+mod cost;
+mod quadtree;
+mod search;
+mod subpixel;
+
+use std::cell::Cell;
+
+use crate::error::{Result, VfiError};
+use crate::flow::FlowField;
 use crate::operations::add_padding;
+use crate::operations::downsample;
 use crate::operations::pixel_difference;
+use crate::operations::{sample_bicubic, sample_bilinear};
 use image::{ImageBuffer, Rgb};
 use num::integer::div_ceil;
 
+use self::search::search;
+use self::subpixel::refine;
+
+pub use self::cost::{MatchCost, MatchCostKind};
+pub use self::quadtree::QuadtreeSettings;
+pub use self::search::{SearchStats, SearchStrategy};
+pub use self::subpixel::{SubpixelMethod, SubpixelPrecision};
+
+// Error recorded for blocks the movement map skipped.
+pub const UNMATCHED_ERROR: f32 = u32::MAX as f32;
+
 pub struct BmaSettings {
     pub verbose: bool,
     pub block_width: u32,
     pub search_radius: u8,
+    pub search_strategy: SearchStrategy,
+    pub match_cost: MatchCostKind,
+    pub subpixel_precision: SubpixelPrecision,
+    pub subpixel_method: SubpixelMethod,
+    pub pyramid_levels: u8,
+    pub pyramid_search_radius: u8,
+    pub use_predictors: bool,
+    pub early_termination_cost: Option<f32>,
     pub use_movement_map: bool,
     pub movement_map_min_pix_diff: u16,
     pub movement_map_min_change_percentage: u16,
 }
 
-impl BmaSettings {
-    pub fn default() -> BmaSettings {
+impl Default for BmaSettings {
+    fn default() -> BmaSettings {
         BmaSettings {
             verbose: false,
             block_width: 16,
             search_radius: 7,
+            search_strategy: SearchStrategy::Full,
+            match_cost: MatchCostKind::Euclidean,
+            subpixel_precision: SubpixelPrecision::Integer,
+            subpixel_method: SubpixelMethod::Bilinear,
+            pyramid_levels: 1,
+            pyramid_search_radius: 2,
+            use_predictors: false,
+            early_termination_cost: None,
             use_movement_map: true,
             movement_map_min_pix_diff: 10,
             movement_map_min_change_percentage: 30,
@@ -25,9 +63,137 @@ impl BmaSettings {
     }
 }
 
+pub struct BmaLayout {
+    pub padding: u32,
+    pub grid_width: u32,
+    pub grid_height: u32,
+    pub max_displacement: u32,
+}
+
+const MAX_PYRAMID_LEVELS: u8 = 8;
+
+impl BmaSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.block_width == 0 {
+            return Err(VfiError::InvalidSettings(
+                "block_width must be at least 1".to_string(),
+            ));
+        }
+        if self.search_radius == 0 {
+            return Err(VfiError::InvalidSettings(
+                "search_radius must be at least 1".to_string(),
+            ));
+        }
+        if self.pyramid_levels == 0 || self.pyramid_levels > MAX_PYRAMID_LEVELS {
+            return Err(VfiError::InvalidSettings(format!(
+                "pyramid_levels must be between 1 and {}, got {}",
+                MAX_PYRAMID_LEVELS, self.pyramid_levels
+            )));
+        }
+        if self.pyramid_levels > 1 && self.pyramid_search_radius == 0 {
+            return Err(VfiError::InvalidSettings(
+                "pyramid_search_radius must be at least 1".to_string(),
+            ));
+        }
+        if let Some(cost) = self.early_termination_cost {
+            if cost.is_nan() || cost < 0.0 {
+                return Err(VfiError::InvalidSettings(format!(
+                    "early_termination_cost must be non-negative, got {}",
+                    cost
+                )));
+            }
+        }
+        if self.use_movement_map && self.movement_map_min_change_percentage > 100 {
+            return Err(VfiError::InvalidSettings(format!(
+                "movement_map_min_change_percentage must be at most 100, got {}",
+                self.movement_map_min_change_percentage
+            )));
+        }
+        Ok(())
+    }
+
+    // Predictors are clamped to `max_displacement`, and the search around
+    // them may reach as far again.
+    pub fn padding(&self) -> u32 {
+        let reach = if self.use_predictors {
+            2 * self.max_displacement()
+        } else {
+            self.max_displacement()
+        };
+        reach + self.block_width
+    }
+
+    // Largest offset a full-resolution vector can reach: the coarsest level
+    // searches `search_radius` and every finer level doubles the estimate and
+    // adds up to `pyramid_search_radius`.
+    pub fn max_displacement(&self) -> u32 {
+        let mut displacement = self.search_radius as u32;
+        for _ in 1..self.pyramid_levels {
+            displacement = displacement * 2 + self.pyramid_search_radius as u32;
+        }
+        displacement
+    }
+
+    pub fn layout(&self, width: u32, height: u32) -> Result<BmaLayout> {
+        self.validate()?;
+        let coarsest_shift = self.pyramid_levels as u32 - 1;
+        if width >> coarsest_shift == 0 || height >> coarsest_shift == 0 {
+            return Err(VfiError::InvalidSettings(format!(
+                "cannot match blocks on a {}x{} frame with {} pyramid levels",
+                width, height, self.pyramid_levels
+            )));
+        }
+        Ok(BmaLayout {
+            padding: self.padding(),
+            grid_width: div_ceil(width, self.block_width),
+            grid_height: div_ceil(height, self.block_width),
+            max_displacement: self.max_displacement(),
+        })
+    }
+}
+
+// `(dx, dy, error)` of a matched block.
+type BlockVector = (f32, f32, f32);
+
+/// Square block of `size` pixels with its top-left corner at `(x, y)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockRegion {
+    pub x: u32,
+    pub y: u32,
+    pub size: u32,
+}
+
+struct BlockSearch {
+    center: (i16, i16),
+    predictors: Vec<(i16, i16)>,
+    radius: u8,
+    refine_subpixel: bool,
+}
+
+// Drops duplicates and the search center, which is always evaluated.
+fn unique_predictors(predictors: Vec<(i16, i16)>, center: (i16, i16)) -> Vec<(i16, i16)> {
+    let mut unique = Vec::with_capacity(predictors.len());
+    for predictor in predictors {
+        if predictor != center && !unique.contains(&predictor) {
+            unique.push(predictor);
+        }
+    }
+    unique
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct BMA {
     block_width: u32,
     search_radius: u8,
+    search_strategy: SearchStrategy,
+    match_cost: Box<dyn MatchCost>,
+    subpixel_precision: SubpixelPrecision,
+    subpixel_method: SubpixelMethod,
+    pyramid_levels: u8,
+    pyramid_search_radius: u8,
+    use_predictors: bool,
+    early_termination_cost: Option<f32>,
+    max_displacement: i16,
     use_movement_map: bool,
     movement_map_min_pix_diff: u16,
     movement_map_min_change_percentage: u16,
@@ -35,6 +201,7 @@ pub struct BMA {
     verbose: bool,
 
     padding: u32,
+    search_stats: Cell<SearchStats>,
 }
 
 impl BMA {
@@ -42,6 +209,15 @@ impl BMA {
         BMA {
             block_width: 16,
             search_radius: 7,
+            search_strategy: SearchStrategy::Full,
+            match_cost: MatchCostKind::Euclidean.match_cost(),
+            subpixel_precision: SubpixelPrecision::Integer,
+            subpixel_method: SubpixelMethod::Bilinear,
+            pyramid_levels: 1,
+            pyramid_search_radius: 2,
+            use_predictors: false,
+            early_termination_cost: None,
+            max_displacement: 7,
             use_movement_map: true,
             movement_map_min_pix_diff: 10,
             movement_map_min_change_percentage: 30,
@@ -49,6 +225,7 @@ impl BMA {
             verbose: false,
 
             padding: 7 + 16,
+            search_stats: Cell::new(SearchStats::default()),
         }
     }
 
@@ -56,26 +233,42 @@ impl BMA {
         self.verbose = settings.verbose;
         self.block_width = settings.block_width;
         self.search_radius = settings.search_radius;
-        self.padding = self.search_radius as u32 + self.block_width;
+        self.search_strategy = settings.search_strategy;
+        self.match_cost = settings.match_cost.match_cost();
+        self.subpixel_precision = settings.subpixel_precision;
+        self.subpixel_method = settings.subpixel_method;
+        self.pyramid_levels = settings.pyramid_levels;
+        self.pyramid_search_radius = settings.pyramid_search_radius;
+        self.use_predictors = settings.use_predictors;
+        self.early_termination_cost = settings.early_termination_cost;
+        self.max_displacement = settings.max_displacement().min(i16::MAX as u32) as i16;
+        self.padding = settings.padding();
         self.use_movement_map = settings.use_movement_map;
         self.movement_map_min_pix_diff = settings.movement_map_min_pix_diff;
         self.movement_map_min_change_percentage = settings.movement_map_min_change_percentage;
     }
+
+    pub fn search_stats(&self) -> SearchStats {
+        self.search_stats.get()
+    }
 }
 
 impl BMA {
     fn has_block_moved(
         &self,
-        block_i: u32,
-        block_j: u32,
+        block: &BlockRegion,
         frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
         frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
     ) -> bool {
+        let (width, height) = frame_1.dimensions();
         let mut changed_pixels: u32 = 0;
-        for x in 0..self.block_width {
-            for y in 0..self.block_width {
-                let pix_x = block_j * self.block_width + x;
-                let pix_y = block_i * self.block_width + y;
+        for x in 0..block.size {
+            for y in 0..block.size {
+                let pix_x = block.x + x;
+                let pix_y = block.y + y;
+                if pix_x >= width || pix_y >= height {
+                    continue;
+                }
                 if pixel_difference(frame_1[(pix_x, pix_y)], frame_2[(pix_x, pix_y)])
                     > self.movement_map_min_pix_diff
                 {
@@ -86,53 +279,170 @@ impl BMA {
         if changed_pixels == 0 {
             return false;
         }
-        let change_percentage = self.block_width.pow(2) * 100 / changed_pixels;
+        let change_percentage = block.size.pow(2) * 100 / changed_pixels;
         change_percentage > self.movement_map_min_change_percentage as u32
     }
 
+    fn read_block(
+        &self,
+        img: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        region: &BlockRegion,
+        x_offset: f32,
+        y_offset: f32,
+        block: &mut Vec<[f32; 3]>,
+    ) {
+        block.clear();
+        let integer_offset = x_offset.fract() == 0.0 && y_offset.fract() == 0.0;
+        for y in 0..region.size {
+            for x in 0..region.size {
+                let pix_x = self.padding + region.x + x;
+                let pix_y = self.padding + region.y + y;
+                if integer_offset {
+                    let pixel = img[(
+                        (pix_x as i64 + x_offset as i64) as u32,
+                        (pix_y as i64 + y_offset as i64) as u32,
+                    )];
+                    block.push([pixel[0] as f32, pixel[1] as f32, pixel[2] as f32]);
+                } else if self.subpixel_method == SubpixelMethod::Bicubic {
+                    block.push(sample_bicubic(img, pix_x as f32 + x_offset, pix_y as f32 + y_offset));
+                } else {
+                    block.push(sample_bilinear(img, pix_x as f32 + x_offset, pix_y as f32 + y_offset));
+                }
+            }
+        }
+    }
+
     fn get_motion_vector(
         &self,
         img_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
         img_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
-        block_i: u32,
-        block_j: u32,
-    ) -> (i16, i16, u32) {
-        let mut error = u32::MAX;
-        let mut motion_vector: (i16, i16, u32) = (0, 0, error);
-
-        for x_offset in self.search_radius as i16 * (-1)..self.search_radius as i16 {
-            for y_offset in self.search_radius as i16 * (-1)..self.search_radius as i16 {
-                let mut cur_error: u32 = 0;
-                for x in 0..self.block_width {
-                    for y in 0..self.block_width {
-                        let pix_x = self.padding + block_j * self.block_width + x;
-                        let pix_y = self.padding + block_i * self.block_width + y;
-                        cur_error += (pixel_difference(
-                            img_1[(pix_x, pix_y)],
-                            img_2[(
-                                (pix_x as i64 + x_offset as i64) as u32,
-                                (pix_y as i64 + y_offset as i64) as u32,
-                            )],
-                        ) as u32)
-                            .pow(2);
-                    }
-                }
-                cur_error /= self.block_width * self.block_width;
-                if cur_error < error {
-                    error = cur_error;
-                    motion_vector = (x_offset, y_offset, cur_error);
+        region: &BlockRegion,
+        block_search: &BlockSearch,
+    ) -> (f32, f32, f32) {
+        let mut reference = Vec::with_capacity((region.size * region.size) as usize);
+        self.read_block(img_1, region, 0.0, 0.0, &mut reference);
+        let mut candidate = Vec::with_capacity(reference.len());
+        let mut block_cost = |x_offset: f32, y_offset: f32| {
+            self.read_block(img_2, region, x_offset, y_offset, &mut candidate);
+            self.match_cost.cost(&reference, &candidate, region.size)
+        };
+
+        // Predictors are tried before the search, which then starts from the
+        // best of them unless it is already good enough to stop.
+        let mut predicted = (block_search.center.0, block_search.center.1, f32::INFINITY);
+        let mut candidates = 0;
+        if !block_search.predictors.is_empty() || self.early_termination_cost.is_some() {
+            let centers = std::iter::once(block_search.center).chain(block_search.predictors.iter().copied());
+            for (dx, dy) in centers {
+                let error = block_cost(dx as f32, dy as f32);
+                candidates += 1;
+                if error < predicted.2 {
+                    predicted = (dx, dy, error);
                 }
             }
         }
+        let terminate_early = self
+            .early_termination_cost
+            .is_some_and(|threshold| predicted.2 <= threshold);
+
+        let integer_vector = if terminate_early {
+            predicted
+        } else {
+            let (cx, cy) = (predicted.0, predicted.1);
+            let (searched, search_candidates) = search(
+                self.search_strategy,
+                block_search.radius as i16,
+                |x_offset, y_offset| block_cost((cx + x_offset) as f32, (cy + y_offset) as f32),
+            );
+            candidates += search_candidates;
+            let searched = (searched.0 + cx, searched.1 + cy, searched.2);
+            if searched.2 < predicted.2 {
+                searched
+            } else {
+                predicted
+            }
+        };
+        let precision = if block_search.refine_subpixel {
+            self.subpixel_precision
+        } else {
+            SubpixelPrecision::Integer
+        };
+        let (motion_vector, refinement_candidates) =
+            refine(precision, self.subpixel_method, integer_vector, block_cost);
+
+        let mut stats = self.search_stats.get();
+        stats += SearchStats {
+            blocks: 1,
+            candidates: candidates + refinement_candidates,
+        };
+        self.search_stats.set(stats);
 
         motion_vector
     }
 
-    pub fn calc_flow(
+    fn clamp_vector(&self, dx: f32, dy: f32) -> (i16, i16) {
+        let limit = self.max_displacement as f32;
+        (
+            dx.round().clamp(-limit, limit) as i16,
+            dy.round().clamp(-limit, limit) as i16,
+        )
+    }
+
+    // Left, top and top-right neighbours of an already matched block, their
+    // median, and the co-located vector of the previous frame pair.
+    fn predictors(
+        &self,
+        flow: &[Vec<BlockVector>],
+        block_i: u32,
+        block_j: u32,
+        center: (i16, i16),
+        temporal_flow: Option<&FlowField>,
+    ) -> Vec<(i16, i16)> {
+        let (i, j) = (block_i as usize, block_j as usize);
+        let mut neighbours = Vec::with_capacity(3);
+        if j > 0 {
+            neighbours.push(flow[i][j - 1]);
+        }
+        if i > 0 {
+            neighbours.push(flow[i - 1][j]);
+            if let Some(&top_right) = flow[i - 1].get(j + 1) {
+                neighbours.push(top_right);
+            }
+        }
+        neighbours.retain(|&(_, _, error)| error != UNMATCHED_ERROR);
+
+        let mut predictors: Vec<(i16, i16)> = neighbours
+            .iter()
+            .map(|&(dx, dy, _)| self.clamp_vector(dx, dy))
+            .collect();
+        if neighbours.len() == 3 {
+            let median = |mut values: [f32; 3]| {
+                values.sort_by(f32::total_cmp);
+                values[1]
+            };
+            predictors.push(self.clamp_vector(
+                median([neighbours[0].0, neighbours[1].0, neighbours[2].0]),
+                median([neighbours[0].1, neighbours[1].1, neighbours[2].1]),
+            ));
+        }
+        if let Some(temporal_flow) = temporal_flow {
+            let (dx, dy) = temporal_flow.at_pixel(
+                block_j * self.block_width + self.block_width / 2,
+                block_i * self.block_width + self.block_width / 2,
+            );
+            predictors.push(self.clamp_vector(dx, dy));
+        }
+        unique_predictors(predictors, center)
+    }
+
+    fn calc_level_flow(
         &self,
         frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
         frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
-    ) -> Vec<Vec<(i16, i16, u32)>> {
+        coarse_flow: Option<&[Vec<BlockVector>]>,
+        temporal_flow: Option<&FlowField>,
+        finest_level: bool,
+    ) -> Vec<Vec<(f32, f32, f32)>> {
         let pf1 = add_padding(frame_1, self.padding);
         let pf2 = add_padding(frame_2, self.padding);
 
@@ -140,18 +450,45 @@ impl BMA {
         let hor_blocks = div_ceil(width, self.block_width);
         let ver_blocks = div_ceil(height, self.block_width);
 
-        let mut flow = vec![vec![(0i16, 0i16, u32::MAX); hor_blocks as usize]; ver_blocks as usize];
+        let mut flow = vec![vec![(0f32, 0f32, UNMATCHED_ERROR); hor_blocks as usize]; ver_blocks as usize];
 
         for block_i in 0..ver_blocks {
             for block_j in 0..hor_blocks {
-                if !self.use_movement_map
-                    || (self.use_movement_map
-                        && self.has_block_moved(block_i, block_j, frame_1, frame_2))
+                let region = BlockRegion {
+                    x: block_j * self.block_width,
+                    y: block_i * self.block_width,
+                    size: self.block_width,
+                };
+                if !finest_level
+                    || !self.use_movement_map
+                    || self.has_block_moved(&region, frame_1, frame_2)
                 {
-                    let motion_vector = self.get_motion_vector(&pf1, &pf2, block_i, block_j);
+                    let (center, radius) = match coarse_flow {
+                        Some(coarse_flow) => {
+                            let row = &coarse_flow[(block_i as usize / 2).min(coarse_flow.len() - 1)];
+                            let parent = row[(block_j as usize / 2).min(row.len() - 1)];
+                            (
+                                self.clamp_vector(parent.0 * 2.0, parent.1 * 2.0),
+                                self.pyramid_search_radius,
+                            )
+                        }
+                        None => ((0, 0), self.search_radius),
+                    };
+                    let predictors = if self.use_predictors {
+                        self.predictors(&flow, block_i, block_j, center, temporal_flow)
+                    } else {
+                        Vec::new()
+                    };
+                    let block_search = BlockSearch {
+                        center,
+                        predictors,
+                        radius,
+                        refine_subpixel: finest_level,
+                    };
+                    let motion_vector = self.get_motion_vector(&pf1, &pf2, &region, &block_search);
                     flow[block_i as usize][block_j as usize] = motion_vector;
 
-                    if self.verbose && (motion_vector.0, motion_vector.1) != (0, 0) {
+                    if self.verbose && finest_level && (motion_vector.0, motion_vector.1) != (0.0, 0.0) {
                         println!(
                             "Block {},{} -> ({}, {}), error: {}",
                             block_j, block_i, motion_vector.0, motion_vector.1, motion_vector.2
@@ -163,4 +500,217 @@ impl BMA {
 
         flow
     }
+
+    /// Matches every block of `frame_1` in `frame_2`. `temporal_flow` is the
+    /// flow of the previous frame pair, used as a predictor when enabled.
+    pub fn calc_flow(
+        &self,
+        frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        temporal_flow: Option<&FlowField>,
+    ) -> Vec<Vec<(f32, f32, f32)>> {
+        if self.pyramid_levels <= 1 {
+            return self.calc_level_flow(frame_1, frame_2, None, temporal_flow, true);
+        }
+
+        let mut pyramid = Vec::with_capacity(self.pyramid_levels as usize - 1);
+        for level in 1..self.pyramid_levels as usize {
+            let (finer_1, finer_2) = match level {
+                1 => (frame_1, frame_2),
+                _ => {
+                    let (finer_1, finer_2) = &pyramid[level - 2];
+                    (finer_1, finer_2)
+                }
+            };
+            pyramid.push((downsample(finer_1), downsample(finer_2)));
+        }
+
+        let (coarsest_1, coarsest_2) = &pyramid[pyramid.len() - 1];
+        let mut flow = self.calc_level_flow(coarsest_1, coarsest_2, None, None, false);
+        for (level_1, level_2) in pyramid.iter().rev().skip(1) {
+            flow = self.calc_level_flow(level_1, level_2, Some(&flow), None, false);
+        }
+        self.calc_level_flow(frame_1, frame_2, Some(&flow), temporal_flow, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::translation_frames;
+
+    fn settings(search_strategy: SearchStrategy) -> BmaSettings {
+        BmaSettings {
+            search_strategy,
+            use_movement_map: false,
+            ..Default::default()
+        }
+    }
+
+    fn block_matching(settings: &BmaSettings) -> BMA {
+        settings.validate().unwrap();
+        let mut bma = BMA::new();
+        bma.apply_settings(settings);
+        bma
+    }
+
+    // Vectors of the blocks whose match stays inside the frame.
+    fn interior(flow: &[Vec<BlockVector>], margin: usize) -> Vec<(f32, f32)> {
+        let mut vectors = Vec::new();
+        for row in &flow[margin..flow.len() - margin] {
+            for &(dx, dy, _) in &row[margin..row.len() - margin] {
+                vectors.push((dx, dy));
+            }
+        }
+        vectors
+    }
+
+    // The fast strategies follow the error surface downhill and may settle
+    // next to the true vector where the texture makes it anisotropic.
+    #[test]
+    fn strategies_recover_a_translation_with_fewer_candidates() {
+        let frames = translation_frames(5.0, -4.0, 2);
+        let full = block_matching(&settings(SearchStrategy::Full));
+        let flow = full.calc_flow(&frames[0], &frames[1], None);
+        assert!(interior(&flow, 1).iter().all(|&vector| vector == (5.0, -4.0)));
+        let full_candidates = full.search_stats().average_candidates();
+        assert_eq!(full_candidates, 225.0);
+
+        for strategy in [
+            SearchStrategy::ThreeStep,
+            SearchStrategy::NewThreeStep,
+            SearchStrategy::Diamond,
+            SearchStrategy::Hexagon,
+            SearchStrategy::Cross,
+        ] {
+            let bma = block_matching(&settings(strategy));
+            let flow = bma.calc_flow(&frames[0], &frames[1], None);
+            let vectors = interior(&flow, 1);
+            let recovered = vectors.iter().filter(|&&vector| vector == (5.0, -4.0)).count();
+            assert!(recovered * 10 >= vectors.len() * 8, "{:?} recovered {} blocks", strategy, recovered);
+            let candidates = bma.search_stats().average_candidates();
+            assert!(candidates < full_candidates / 3.0, "{:?} averaged {} candidates", strategy, candidates);
+        }
+    }
+
+    #[test]
+    fn full_search_reaches_the_search_radius() {
+        let frames = translation_frames(7.0, -7.0, 2);
+        let bma = block_matching(&settings(SearchStrategy::Full));
+        let flow = bma.calc_flow(&frames[0], &frames[1], None);
+        assert!(interior(&flow, 1).iter().all(|&vector| vector == (7.0, -7.0)));
+    }
+
+    #[test]
+    fn pyramid_follows_motion_beyond_the_search_radius() {
+        let frames = translation_frames(16.0, -12.0, 2);
+        let single_level = BmaSettings {
+            block_width: 8,
+            ..settings(SearchStrategy::Full)
+        };
+        let pyramid = BmaSettings {
+            pyramid_levels: 3,
+            ..single_level
+        };
+        let recovered = |settings: &BmaSettings| {
+            let flow = block_matching(settings).calc_flow(&frames[0], &frames[1], None);
+            // Blocks of the outer coarsest-level blocks follow content that
+            // leaves the frame.
+            let vectors = interior(&flow, 4);
+            vectors.iter().filter(|&&vector| vector == (16.0, -12.0)).count() * 100 / vectors.len()
+        };
+        assert_eq!(recovered(&single_level), 0);
+        assert_eq!(recovered(&pyramid), 100);
+    }
+
+    #[test]
+    fn reach_grows_with_pyramid_levels_and_predictors() {
+        let mut settings = settings(SearchStrategy::Full);
+        assert_eq!((settings.max_displacement(), settings.padding()), (7, 7 + 16));
+        settings.pyramid_levels = 3;
+        // 7 at the coarsest level, then 7 * 2 + 2 and 16 * 2 + 2.
+        assert_eq!((settings.max_displacement(), settings.padding()), (34, 34 + 16));
+        settings.use_predictors = true;
+        assert_eq!((settings.max_displacement(), settings.padding()), (34, 2 * 34 + 16));
+
+        let layout = settings.layout(100, 60).unwrap();
+        assert_eq!((layout.grid_width, layout.grid_height), (7, 4));
+        assert_eq!((layout.padding, layout.max_displacement), (84, 34));
+        settings.pyramid_levels = 7;
+        assert!(settings.layout(100, 60).is_err());
+    }
+
+    // Motion past `max_displacement` must neither read outside the padding
+    // nor produce vectors the padding does not cover.
+    #[test]
+    fn vectors_stay_within_the_padding() {
+        let frames = translation_frames(45.0, 40.0, 2);
+        for (pyramid_levels, use_predictors) in [(1, false), (1, true), (3, false), (3, true)] {
+            let settings = BmaSettings {
+                block_width: 8,
+                pyramid_levels,
+                use_predictors,
+                subpixel_precision: SubpixelPrecision::Quarter,
+                subpixel_method: SubpixelMethod::Bicubic,
+                ..settings(SearchStrategy::Full)
+            };
+            // Sub-pixel refinement may step up to a pixel past the search.
+            let reach = (settings.padding() - settings.block_width) as f32 + 1.0;
+            let flow = block_matching(&settings).calc_flow(&frames[0], &frames[1], None);
+            for &(dx, dy, _) in flow.iter().flatten() {
+                assert!(dx.abs() <= reach && dy.abs() <= reach, "({}, {}) beyond {}", dx, dy, reach);
+            }
+        }
+    }
+
+    #[test]
+    fn predictors_cut_candidates_without_changing_the_flow() {
+        let frames = translation_frames(5.0, -4.0, 2);
+        let run = |settings: &BmaSettings| {
+            let bma = block_matching(settings);
+            let flow = bma.calc_flow(&frames[0], &frames[1], None);
+            (interior(&flow, 1), bma.search_stats().average_candidates())
+        };
+        let (expected, full_candidates) = run(&settings(SearchStrategy::Full));
+
+        let early_termination = BmaSettings {
+            use_predictors: true,
+            early_termination_cost: Some(1.0),
+            ..settings(SearchStrategy::Full)
+        };
+        let (flow, candidates) = run(&early_termination);
+        assert_eq!(flow, expected);
+        assert!(candidates < full_candidates / 2.0, "averaged {} candidates", candidates);
+
+        let (_, diamond_candidates) = run(&settings(SearchStrategy::Diamond));
+        let predicted_diamond = BmaSettings {
+            use_predictors: true,
+            ..settings(SearchStrategy::Diamond)
+        };
+        let (flow, candidates) = run(&predicted_diamond);
+        assert_eq!(flow, expected);
+        assert!(candidates < diamond_candidates, "averaged {} candidates", candidates);
+    }
+
+    #[test]
+    fn temporal_predictor_saves_candidates() {
+        let frames = translation_frames(5.0, -4.0, 3);
+        let settings = BmaSettings {
+            use_predictors: true,
+            early_termination_cost: Some(1.0),
+            ..settings(SearchStrategy::Full)
+        };
+        let bma = block_matching(&settings);
+        let previous = bma.calc_flow(&frames[0], &frames[1], None);
+        let vectors = previous.iter().flatten().map(|&(dx, dy, _)| (dx, dy)).collect();
+        let previous = FlowField::from_vectors(8, 6, 16, vectors).unwrap();
+
+        let spatial = block_matching(&settings);
+        let expected = spatial.calc_flow(&frames[1], &frames[2], None);
+        let temporal = block_matching(&settings);
+        let flow = temporal.calc_flow(&frames[1], &frames[2], Some(&previous));
+        assert_eq!(interior(&flow, 1), interior(&expected, 1));
+        assert!(interior(&flow, 1).iter().all(|&vector| vector == (5.0, -4.0)));
+        assert!(temporal.search_stats().candidates < spatial.search_stats().candidates);
+    }
 }
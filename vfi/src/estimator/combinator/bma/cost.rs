@@ -0,0 +1,255 @@
+/// Block matching criterion. Both blocks hold `block_width * block_width`
+/// RGB samples in row-major order; lower values mean a better match.
+pub trait MatchCost {
+    fn cost(&self, reference: &[[f32; 3]], candidate: &[[f32; 3]], block_width: u32) -> f32;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchCostKind {
+    Euclidean,
+    Sad,
+    Ssd,
+    ZeroMeanSad,
+    Ncc,
+    Census,
+}
+
+impl MatchCostKind {
+    pub fn match_cost(&self) -> Box<dyn MatchCost> {
+        match self {
+            MatchCostKind::Euclidean => Box::new(EuclideanCost),
+            MatchCostKind::Sad => Box::new(SadCost),
+            MatchCostKind::Ssd => Box::new(SsdCost),
+            MatchCostKind::ZeroMeanSad => Box::new(ZeroMeanSadCost),
+            MatchCostKind::Ncc => Box::new(NccCost),
+            MatchCostKind::Census => Box::new(CensusCost),
+        }
+    }
+
+    /// Upper bound of the cost, for the criteria that have one: `1 - NCC`
+    /// stays within 2 and the census distance within the 8 neighbour bits.
+    pub fn max_cost(&self) -> Option<f32> {
+        match self {
+            MatchCostKind::Ncc => Some(2.0),
+            MatchCostKind::Census => Some(8.0),
+            _ => None,
+        }
+    }
+}
+
+// Mean squared RGB distance, the original BMA criterion. It used to be
+// truncated to integers, which flattened the costs of sub-pixel candidates.
+pub struct EuclideanCost;
+
+impl MatchCost for EuclideanCost {
+    fn cost(&self, reference: &[[f32; 3]], candidate: &[[f32; 3]], _block_width: u32) -> f32 {
+        let error: f64 = reference
+            .iter()
+            .zip(candidate.iter())
+            .map(|(p1, p2)| (0..3).map(|c| ((p1[c] - p2[c]) as f64).powi(2)).sum::<f64>())
+            .sum();
+        (error / reference.len() as f64) as f32
+    }
+}
+
+pub struct SadCost;
+
+impl MatchCost for SadCost {
+    fn cost(&self, reference: &[[f32; 3]], candidate: &[[f32; 3]], _block_width: u32) -> f32 {
+        let sum: f32 = reference
+            .iter()
+            .zip(candidate.iter())
+            .map(|(p1, p2)| (0..3).map(|c| (p1[c] - p2[c]).abs()).sum::<f32>())
+            .sum();
+        sum / reference.len() as f32
+    }
+}
+
+pub struct SsdCost;
+
+impl MatchCost for SsdCost {
+    fn cost(&self, reference: &[[f32; 3]], candidate: &[[f32; 3]], _block_width: u32) -> f32 {
+        let sum: f32 = reference
+            .iter()
+            .zip(candidate.iter())
+            .map(|(p1, p2)| (0..3).map(|c| (p1[c] - p2[c]).powi(2)).sum::<f32>())
+            .sum();
+        sum / reference.len() as f32
+    }
+}
+
+fn channel_means(block: &[[f32; 3]]) -> [f32; 3] {
+    let mut means = [0f32; 3];
+    for pixel in block {
+        for c in 0..3 {
+            means[c] += pixel[c];
+        }
+    }
+    means.map(|sum| sum / block.len() as f32)
+}
+
+pub struct ZeroMeanSadCost;
+
+impl MatchCost for ZeroMeanSadCost {
+    fn cost(&self, reference: &[[f32; 3]], candidate: &[[f32; 3]], _block_width: u32) -> f32 {
+        let m1 = channel_means(reference);
+        let m2 = channel_means(candidate);
+        let sum: f32 = reference
+            .iter()
+            .zip(candidate.iter())
+            .map(|(p1, p2)| {
+                (0..3)
+                    .map(|c| ((p1[c] - m1[c]) - (p2[c] - m2[c])).abs())
+                    .sum::<f32>()
+            })
+            .sum();
+        sum / reference.len() as f32
+    }
+}
+
+// Reported as `1 - NCC`, so identical blocks cost 0 and inverted ones cost 2.
+pub struct NccCost;
+
+impl MatchCost for NccCost {
+    fn cost(&self, reference: &[[f32; 3]], candidate: &[[f32; 3]], _block_width: u32) -> f32 {
+        let m1 = channel_means(reference);
+        let m2 = channel_means(candidate);
+        let (mut cross, mut var_1, mut var_2) = (0f64, 0f64, 0f64);
+        for (p1, p2) in reference.iter().zip(candidate.iter()) {
+            for c in 0..3 {
+                let a = (p1[c] - m1[c]) as f64;
+                let b = (p2[c] - m2[c]) as f64;
+                cross += a * b;
+                var_1 += a * a;
+                var_2 += b * b;
+            }
+        }
+        if var_1 == 0.0 || var_2 == 0.0 {
+            return if var_1 == var_2 { 0.0 } else { 1.0 };
+        }
+        (1.0 - cross / (var_1 * var_2).sqrt()) as f32
+    }
+}
+
+fn census_signatures(block: &[[f32; 3]], block_width: u32) -> Vec<u8> {
+    let width = block_width as i32;
+    let luma = |x: i32, y: i32| {
+        let pixel = block[(y.clamp(0, width - 1) * width + x.clamp(0, width - 1)) as usize];
+        pixel[0] + pixel[1] + pixel[2]
+    };
+    let mut signatures = Vec::with_capacity(block.len());
+    for y in 0..width {
+        for x in 0..width {
+            let center = luma(x, y);
+            let mut signature = 0u8;
+            for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+                signature = (signature << 1) | (luma(x + dx, y + dy) > center) as u8;
+            }
+            signatures.push(signature);
+        }
+    }
+    signatures
+}
+
+// Mean Hamming distance between 3x3 census signatures of the block luminance.
+pub struct CensusCost;
+
+impl MatchCost for CensusCost {
+    fn cost(&self, reference: &[[f32; 3]], candidate: &[[f32; 3]], block_width: u32) -> f32 {
+        let s1 = census_signatures(reference, block_width);
+        let s2 = census_signatures(candidate, block_width);
+        let distance: u32 = s1.iter().zip(s2.iter()).map(|(a, b)| (a ^ b).count_ones()).sum();
+        distance as f32 / reference.len() as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 4;
+
+    // A textured 4x4 block with distinct neighbours everywhere.
+    fn block() -> Vec<[f32; 3]> {
+        (0..WIDTH * WIDTH)
+            .map(|i| {
+                let v = ((i * 37) % 101) as f32;
+                [v, v * 0.5 + 10.0, 200.0 - v]
+            })
+            .collect()
+    }
+
+    fn offset(block: &[[f32; 3]], offset: f32) -> Vec<[f32; 3]> {
+        block.iter().map(|pixel| pixel.map(|v| v + offset)).collect()
+    }
+
+    fn cost(kind: MatchCostKind, reference: &[[f32; 3]], candidate: &[[f32; 3]]) -> f32 {
+        kind.match_cost().cost(reference, candidate, WIDTH)
+    }
+
+    const KINDS: [MatchCostKind; 6] = [
+        MatchCostKind::Euclidean,
+        MatchCostKind::Sad,
+        MatchCostKind::Ssd,
+        MatchCostKind::ZeroMeanSad,
+        MatchCostKind::Ncc,
+        MatchCostKind::Census,
+    ];
+
+    #[test]
+    fn identical_blocks_cost_nothing() {
+        let block = block();
+        for kind in KINDS {
+            assert_eq!(cost(kind, &block, &block), 0.0, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn distance_costs_of_a_constant_difference() {
+        let reference = block();
+        let candidate = offset(&reference, 2.0);
+        assert_eq!(cost(MatchCostKind::Sad, &reference, &candidate), 6.0);
+        assert_eq!(cost(MatchCostKind::Ssd, &reference, &candidate), 12.0);
+        assert_eq!(cost(MatchCostKind::Euclidean, &reference, &candidate), 12.0);
+    }
+
+    #[test]
+    fn euclidean_cost_is_not_quantized() {
+        let reference = block();
+        let candidate = offset(&reference, 0.25);
+        let quarter = cost(MatchCostKind::Euclidean, &reference, &candidate);
+        assert!((quarter - 0.1875).abs() < 1e-6, "{}", quarter);
+        let eighth = cost(MatchCostKind::Euclidean, &reference, &offset(&reference, 0.125));
+        assert!(eighth < quarter);
+    }
+
+    #[test]
+    fn zero_mean_costs_ignore_brightness_offsets() {
+        let reference = block();
+        let brighter = offset(&reference, 30.0);
+        for kind in [MatchCostKind::ZeroMeanSad, MatchCostKind::Ncc, MatchCostKind::Census] {
+            assert!(cost(kind, &reference, &brighter).abs() < 1e-5, "{:?}", kind);
+        }
+        assert!(cost(MatchCostKind::Sad, &reference, &brighter) > 0.0);
+    }
+
+    #[test]
+    fn ncc_of_inverted_and_flat_blocks() {
+        let reference = block();
+        let inverted: Vec<[f32; 3]> = reference.iter().map(|pixel| pixel.map(|v| 255.0 - v)).collect();
+        assert!((cost(MatchCostKind::Ncc, &reference, &inverted) - 2.0).abs() < 1e-5);
+        let flat = vec![[50.0; 3]; reference.len()];
+        assert_eq!(cost(MatchCostKind::Ncc, &flat, &offset(&flat, 10.0)), 0.0);
+        assert_eq!(cost(MatchCostKind::Ncc, &reference, &flat), 1.0);
+    }
+
+    #[test]
+    fn census_counts_flipped_comparisons() {
+        let flat = vec![[50.0; 3]; 9];
+        let mut peak = flat.clone();
+        peak[4] = [60.0; 3];
+        // Each of the 8 neighbours now sees one brighter pixel, while the
+        // center still sees none.
+        assert_eq!(CensusCost.cost(&flat, &peak, 3), 8.0 / 9.0);
+    }
+}
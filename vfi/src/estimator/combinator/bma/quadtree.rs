@@ -0,0 +1,273 @@
+use image::{ImageBuffer, Rgb};
+
+use crate::error::{Result, VfiError};
+use crate::flow::FlowField;
+use crate::operations::add_padding;
+
+use super::{unique_predictors, BlockRegion, BlockSearch, BlockVector, MatchCostKind, BMA};
+
+pub struct QuadtreeSettings {
+    pub min_block_width: u32,
+    pub split_error: f32,
+    pub split_disagreement: f32,
+}
+
+impl Default for QuadtreeSettings {
+    fn default() -> QuadtreeSettings {
+        QuadtreeSettings {
+            min_block_width: 4,
+            split_error: 100.0,
+            split_disagreement: 1.0,
+        }
+    }
+}
+
+impl QuadtreeSettings {
+    pub fn validate_for_block_width(&self, block_width: u32) -> Result<()> {
+        if self.min_block_width == 0 || self.min_block_width > block_width {
+            return Err(VfiError::InvalidSettings(format!(
+                "min_block_width must be between 1 and block_width ({}), got {}",
+                block_width, self.min_block_width
+            )));
+        }
+        if !block_width.is_multiple_of(self.min_block_width)
+            || !(block_width / self.min_block_width).is_power_of_two()
+        {
+            return Err(VfiError::InvalidSettings(format!(
+                "block_width {} must be min_block_width {} times a power of two",
+                block_width, self.min_block_width
+            )));
+        }
+        for (name, value) in [
+            ("split_error", self.split_error),
+            ("split_disagreement", self.split_disagreement),
+        ] {
+            if value.is_nan() || value < 0.0 {
+                return Err(VfiError::InvalidSettings(format!(
+                    "{} must be non-negative, got {}",
+                    name, value
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // `split_error` is in the units of the match cost, so the default suits
+    // the unbounded criteria but would never split an NCC or census block.
+    pub fn validate_for_match_cost(&self, match_cost: MatchCostKind) -> Result<()> {
+        if let Some(max_cost) = match_cost.max_cost() {
+            if self.split_error >= max_cost {
+                return Err(VfiError::InvalidSettings(format!(
+                    "split_error must be below {} for {:?} costs, got {}",
+                    max_cost, match_cost, self.split_error
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct QuadtreeContext<'a> {
+    padded_1: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    padded_2: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    settings: &'a QuadtreeSettings,
+    temporal_flow: Option<&'a FlowField>,
+    flow: FlowField,
+    partition: Vec<BlockRegion>,
+}
+
+impl BMA {
+    /// Matches `block_width` blocks and recursively splits those whose error
+    /// or children's disagreement is too high. Returns a per-pixel flow and
+    /// the leaf blocks of the partition.
+    pub fn calc_quadtree_flow(
+        &self,
+        frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        settings: &QuadtreeSettings,
+        temporal_flow: Option<&FlowField>,
+    ) -> (FlowField, Vec<BlockRegion>) {
+        let (width, height) = frame_1.dimensions();
+        let mut context = QuadtreeContext {
+            padded_1: add_padding(frame_1, self.padding),
+            padded_2: add_padding(frame_2, self.padding),
+            settings,
+            temporal_flow,
+            flow: FlowField::new(width, height, 1),
+            partition: Vec::new(),
+        };
+
+        for y in (0..height).step_by(self.block_width as usize) {
+            for x in (0..width).step_by(self.block_width as usize) {
+                let region = BlockRegion {
+                    x,
+                    y,
+                    size: self.block_width,
+                };
+                if self.use_movement_map && !self.has_block_moved(&region, frame_1, frame_2) {
+                    context.partition.push(region);
+                    continue;
+                }
+                let vector = self.match_region(&context, &region, None);
+                self.split_region(&mut context, region, vector);
+            }
+        }
+
+        (context.flow, context.partition)
+    }
+
+    fn match_region(
+        &self,
+        context: &QuadtreeContext,
+        region: &BlockRegion,
+        parent: Option<BlockVector>,
+    ) -> BlockVector {
+        let predictors = if self.use_predictors {
+            self.region_predictors(context, region, parent)
+        } else {
+            Vec::new()
+        };
+        let block_search = BlockSearch {
+            center: (0, 0),
+            predictors,
+            radius: self.search_radius,
+            refine_subpixel: true,
+        };
+        self.get_motion_vector(&context.padded_1, &context.padded_2, region, &block_search)
+    }
+
+    // Vectors already painted left, above and above-right of the region, the
+    // parent block's vector and the co-located vector of the previous pair.
+    fn region_predictors(
+        &self,
+        context: &QuadtreeContext,
+        region: &BlockRegion,
+        parent: Option<BlockVector>,
+    ) -> Vec<(i16, i16)> {
+        let (width, height) = context.flow.dimensions();
+        let mut predictors = Vec::with_capacity(5);
+        let mut painted = |x: i64, y: i64| {
+            if (0..width as i64).contains(&x) && (0..height as i64).contains(&y) {
+                let (dx, dy) = context.flow[(x as u32, y as u32)];
+                predictors.push(self.clamp_vector(dx, dy));
+            }
+        };
+        painted(region.x as i64 - 1, region.y as i64);
+        painted(region.x as i64, region.y as i64 - 1);
+        painted((region.x + region.size) as i64, region.y as i64 - 1);
+        if let Some((dx, dy, _)) = parent {
+            predictors.push(self.clamp_vector(dx, dy));
+        }
+        if let Some(temporal_flow) = context.temporal_flow {
+            let (dx, dy) = temporal_flow.at_pixel(region.x + region.size / 2, region.y + region.size / 2);
+            predictors.push(self.clamp_vector(dx, dy));
+        }
+        unique_predictors(predictors, (0, 0))
+    }
+
+    fn split_region(&self, context: &mut QuadtreeContext, region: BlockRegion, vector: BlockVector) {
+        let half = region.size / 2;
+        if half >= context.settings.min_block_width {
+            let (width, height) = context.flow.dimensions();
+            let children: Vec<(BlockRegion, BlockVector)> = [(0, 0), (half, 0), (0, half), (half, half)]
+                .iter()
+                .map(|&(x, y)| BlockRegion {
+                    x: region.x + x,
+                    y: region.y + y,
+                    size: half,
+                })
+                .filter(|child| child.x < width && child.y < height)
+                .map(|child| (child, self.match_region(context, &child, Some(vector))))
+                .collect();
+            let disagreement = children
+                .iter()
+                .map(|(_, (dx, dy, _))| ((dx - vector.0).powi(2) + (dy - vector.1).powi(2)).sqrt())
+                .fold(0.0, f32::max);
+            if vector.2 > context.settings.split_error
+                || disagreement > context.settings.split_disagreement
+            {
+                for (child, child_vector) in children {
+                    self.split_region(context, child, child_vector);
+                }
+                return;
+            }
+        }
+
+        let (width, height) = context.flow.dimensions();
+        for y in region.y..(region.y + region.size).min(height) {
+            for x in region.x..(region.x + region.size).min(width) {
+                context.flow[(x, y)] = (vector.0, vector.1);
+            }
+        }
+        context.partition.push(region);
+
+        if self.verbose && (vector.0, vector.1) != (0.0, 0.0) {
+            println!(
+                "Block {},{} ({}px) -> ({}, {}), error: {}",
+                region.x, region.y, region.size, vector.0, vector.1, vector.2
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::estimator::combinator::BmaSettings;
+    use crate::synthetic::{generate, Motion, MovingRectangle, SceneSettings};
+
+    #[test]
+    fn split_error_must_fit_bounded_costs() {
+        let settings = QuadtreeSettings::default();
+        assert!(settings.validate_for_match_cost(MatchCostKind::Euclidean).is_ok());
+        assert!(settings.validate_for_match_cost(MatchCostKind::Ncc).is_err());
+        assert!(settings.validate_for_match_cost(MatchCostKind::Census).is_err());
+        let settings = QuadtreeSettings {
+            split_error: 0.5,
+            ..settings
+        };
+        assert!(settings.validate_for_match_cost(MatchCostKind::Ncc).is_ok());
+        assert!(settings.validate_for_match_cost(MatchCostKind::Census).is_ok());
+    }
+
+    #[test]
+    fn moving_region_splits_and_static_background_does_not() {
+        // The rectangle covers x 40..64, y 24..48 and moves onto x 43..67, y 25..49.
+        let scene = SceneSettings {
+            width: 128,
+            height: 96,
+            background_motion: Motion::Translation { dx: 0.0, dy: 0.0 },
+            rectangles: vec![MovingRectangle { x: 40.0, y: 24.0, width: 24.0, height: 24.0, dx: 3.0, dy: 1.0 }],
+            ..Default::default()
+        };
+        let sequence = generate(&scene, 2).unwrap();
+        let settings = BmaSettings {
+            use_movement_map: false,
+            ..Default::default()
+        };
+        let mut bma = BMA::new();
+        bma.apply_settings(&settings);
+        let (flow, partition) =
+            bma.calc_quadtree_flow(&sequence.frames[0], &sequence.frames[1], &QuadtreeSettings::default(), None);
+
+        let area: u32 = partition.iter().map(|block| block.size * block.size).sum();
+        assert_eq!(area, 128 * 96);
+        let near_rectangle = |block: &BlockRegion| {
+            block.x < 67 && block.x + block.size > 40 && block.y < 49 && block.y + block.size > 24
+        };
+        for block in &partition {
+            let top_level = BlockRegion {
+                x: block.x / 16 * 16,
+                y: block.y / 16 * 16,
+                size: 16,
+            };
+            if !near_rectangle(&top_level) {
+                assert_eq!(block.size, 16, "static block {:?} was split", block);
+            }
+        }
+        // Blocks straddling the rectangle's edges hold two motions.
+        assert!(partition.iter().filter(|block| near_rectangle(block)).any(|block| block.size < 16));
+        assert_eq!(flow[(52, 36)], (3.0, 1.0));
+        assert_eq!(flow[(100, 80)], (0.0, 0.0));
+    }
+}
@@ -0,0 +1,271 @@
+use std::ops::AddAssign;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchStrategy {
+    Full,
+    ThreeStep,
+    NewThreeStep,
+    Diamond,
+    Hexagon,
+    Cross,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SearchStats {
+    pub blocks: u64,
+    pub candidates: u64,
+}
+
+impl SearchStats {
+    pub fn average_candidates(&self) -> f64 {
+        if self.blocks == 0 {
+            return 0.0;
+        }
+        self.candidates as f64 / self.blocks as f64
+    }
+}
+
+impl AddAssign for SearchStats {
+    fn add_assign(&mut self, other: SearchStats) {
+        self.blocks += other.blocks;
+        self.candidates += other.candidates;
+    }
+}
+
+const LARGE_DIAMOND: [(i16, i16); 8] = [
+    (0, -2),
+    (1, -1),
+    (2, 0),
+    (1, 1),
+    (0, 2),
+    (-1, 1),
+    (-2, 0),
+    (-1, -1),
+];
+const SMALL_DIAMOND: [(i16, i16); 4] = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+const LARGE_HEXAGON: [(i16, i16); 6] = [(-2, 0), (-1, -2), (1, -2), (2, 0), (1, 2), (-1, 2)];
+const SQUARE: [(i16, i16); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+const DIAGONALS: [(i16, i16); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+// Caches every candidate inside the `[-radius, radius]` window so that
+// patterns revisiting a point neither pay for it twice nor count it twice.
+struct Evaluator<F: FnMut(i16, i16) -> f32> {
+    cost: F,
+    radius: i16,
+    cache: Vec<Option<f32>>,
+    candidates: u64,
+    best: (i16, i16, f32),
+}
+
+impl<F: FnMut(i16, i16) -> f32> Evaluator<F> {
+    fn new(radius: i16, cost: F) -> Evaluator<F> {
+        let side = 2 * radius as usize + 1;
+        Evaluator {
+            cost,
+            radius,
+            cache: vec![None; side * side],
+            candidates: 0,
+            best: (0, 0, f32::INFINITY),
+        }
+    }
+
+    fn evaluate(&mut self, dx: i16, dy: i16) -> Option<f32> {
+        if dx < -self.radius || dx > self.radius || dy < -self.radius || dy > self.radius {
+            return None;
+        }
+        let side = 2 * self.radius as usize + 1;
+        let index = (dy + self.radius) as usize * side + (dx + self.radius) as usize;
+        let error = match self.cache[index] {
+            Some(error) => error,
+            None => {
+                let error = (self.cost)(dx, dy);
+                self.cache[index] = Some(error);
+                self.candidates += 1;
+                error
+            }
+        };
+        if error < self.best.2 {
+            self.best = (dx, dy, error);
+        }
+        Some(error)
+    }
+
+    fn evaluate_pattern(&mut self, center: (i16, i16), pattern: &[(i16, i16)], scale: i16) {
+        for &(px, py) in pattern {
+            self.evaluate(center.0 + px * scale, center.1 + py * scale);
+        }
+    }
+
+    fn best_vector(&self) -> (i16, i16) {
+        (self.best.0, self.best.1)
+    }
+}
+
+fn initial_step(radius: i16) -> i16 {
+    let mut step = 1;
+    while step * 2 <= radius / 2 + radius % 2 {
+        step *= 2;
+    }
+    step.max(1)
+}
+
+fn full_search<F: FnMut(i16, i16) -> f32>(evaluator: &mut Evaluator<F>) {
+    let radius = evaluator.radius;
+    for x_offset in -radius..=radius {
+        for y_offset in -radius..=radius {
+            evaluator.evaluate(x_offset, y_offset);
+        }
+    }
+}
+
+fn three_step_search<F: FnMut(i16, i16) -> f32>(evaluator: &mut Evaluator<F>, mut step: i16) {
+    evaluator.evaluate(0, 0);
+    while step >= 1 {
+        let center = evaluator.best_vector();
+        evaluator.evaluate_pattern(center, &SQUARE, step);
+        step /= 2;
+    }
+}
+
+fn new_three_step_search<F: FnMut(i16, i16) -> f32>(evaluator: &mut Evaluator<F>) {
+    let step = initial_step(evaluator.radius);
+    evaluator.evaluate(0, 0);
+    evaluator.evaluate_pattern((0, 0), &SQUARE, step);
+    evaluator.evaluate_pattern((0, 0), &SQUARE, 1);
+    let best = evaluator.best_vector();
+    if best == (0, 0) {
+        return;
+    }
+    if best.0.abs() <= 1 && best.1.abs() <= 1 {
+        evaluator.evaluate_pattern(best, &SQUARE, 1);
+        return;
+    }
+    three_step_search(evaluator, step / 2);
+}
+
+fn diamond_search<F: FnMut(i16, i16) -> f32>(evaluator: &mut Evaluator<F>) {
+    evaluator.evaluate(0, 0);
+    loop {
+        let center = evaluator.best_vector();
+        evaluator.evaluate_pattern(center, &LARGE_DIAMOND, 1);
+        if evaluator.best_vector() == center {
+            break;
+        }
+    }
+    let center = evaluator.best_vector();
+    evaluator.evaluate_pattern(center, &SMALL_DIAMOND, 1);
+}
+
+fn hexagon_search<F: FnMut(i16, i16) -> f32>(evaluator: &mut Evaluator<F>) {
+    evaluator.evaluate(0, 0);
+    loop {
+        let center = evaluator.best_vector();
+        evaluator.evaluate_pattern(center, &LARGE_HEXAGON, 1);
+        if evaluator.best_vector() == center {
+            break;
+        }
+    }
+    let center = evaluator.best_vector();
+    evaluator.evaluate_pattern(center, &SMALL_DIAMOND, 1);
+}
+
+fn cross_search<F: FnMut(i16, i16) -> f32>(evaluator: &mut Evaluator<F>) {
+    let mut step = initial_step(evaluator.radius);
+    evaluator.evaluate(0, 0);
+    while step > 1 {
+        let center = evaluator.best_vector();
+        evaluator.evaluate_pattern(center, &DIAGONALS, step);
+        step /= 2;
+    }
+    let center = evaluator.best_vector();
+    evaluator.evaluate_pattern(center, &DIAGONALS, 1);
+    evaluator.evaluate_pattern(center, &SMALL_DIAMOND, 1);
+}
+
+/// Runs `strategy` inside the `[-radius, radius]` window and returns the best
+/// `(dx, dy, error)` together with the number of distinct candidates evaluated.
+pub fn search<F>(strategy: SearchStrategy, radius: i16, cost: F) -> ((i16, i16, f32), u64)
+where
+    F: FnMut(i16, i16) -> f32,
+{
+    let mut evaluator = Evaluator::new(radius, cost);
+    match strategy {
+        SearchStrategy::Full => full_search(&mut evaluator),
+        SearchStrategy::ThreeStep => {
+            let step = initial_step(radius);
+            three_step_search(&mut evaluator, step)
+        }
+        SearchStrategy::NewThreeStep => new_three_step_search(&mut evaluator),
+        SearchStrategy::Diamond => diamond_search(&mut evaluator),
+        SearchStrategy::Hexagon => hexagon_search(&mut evaluator),
+        SearchStrategy::Cross => cross_search(&mut evaluator),
+    }
+    (evaluator.best, evaluator.candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRATEGIES: [SearchStrategy; 6] = [
+        SearchStrategy::Full,
+        SearchStrategy::ThreeStep,
+        SearchStrategy::NewThreeStep,
+        SearchStrategy::Diamond,
+        SearchStrategy::Hexagon,
+        SearchStrategy::Cross,
+    ];
+
+    // Unimodal error surface of a block translated by `offset`.
+    fn bowl(offset: (i16, i16), radius: i16) -> impl FnMut(i16, i16) -> f32 {
+        move |dx, dy| {
+            assert!(dx.abs() <= radius && dy.abs() <= radius, "({}, {}) is outside the window", dx, dy);
+            ((dx - offset.0) as f32).powi(2) + ((dy - offset.1) as f32).powi(2)
+        }
+    }
+
+    #[test]
+    fn full_search_covers_the_symmetric_window() {
+        for radius in [0, 1, 7] {
+            let (_, candidates) = search(SearchStrategy::Full, radius, bowl((0, 0), radius));
+            assert_eq!(candidates, ((2 * radius + 1) as u64).pow(2));
+        }
+        for offset in [(7, 7), (-7, -7), (7, -7), (-7, 7)] {
+            let (best, _) = search(SearchStrategy::Full, 7, bowl(offset, 7));
+            assert_eq!(best, (offset.0, offset.1, 0.0));
+        }
+    }
+
+    #[test]
+    fn strategies_recover_the_offset_with_fewer_candidates() {
+        let (_, full_candidates) = search(SearchStrategy::Full, 7, bowl((0, 0), 7));
+        for offset in [(0, 0), (3, -2), (-5, 4), (1, 1), (7, -3)] {
+            for strategy in STRATEGIES {
+                let (best, candidates) = search(strategy, 7, bowl(offset, 7));
+                assert_eq!((best.0, best.1), offset, "{:?}", strategy);
+                if strategy != SearchStrategy::Full {
+                    assert!(candidates < full_candidates, "{:?} evaluated {} candidates", strategy, candidates);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn revisited_candidates_are_counted_once() {
+        let mut calls = 0;
+        let (_, candidates) = search(SearchStrategy::Diamond, 7, |dx, dy| {
+            calls += 1;
+            ((dx - 4) as f32).powi(2) + ((dy - 4) as f32).powi(2)
+        });
+        assert_eq!(candidates, calls);
+    }
+}
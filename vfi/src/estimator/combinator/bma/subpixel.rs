@@ -0,0 +1,172 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubpixelPrecision {
+    Integer,
+    Half,
+    Quarter,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubpixelMethod {
+    Bilinear,
+    Bicubic,
+    Parabolic,
+}
+
+impl SubpixelPrecision {
+    fn steps(&self) -> &'static [f32] {
+        match self {
+            SubpixelPrecision::Integer => &[],
+            SubpixelPrecision::Half => &[0.5],
+            SubpixelPrecision::Quarter => &[0.5, 0.25],
+        }
+    }
+
+    fn finest_step(&self) -> f32 {
+        self.steps().last().copied().unwrap_or(1.0)
+    }
+}
+
+const NEIGHBOURS: [(f32, f32); 8] = [
+    (-1.0, -1.0),
+    (0.0, -1.0),
+    (1.0, -1.0),
+    (-1.0, 0.0),
+    (1.0, 0.0),
+    (-1.0, 1.0),
+    (0.0, 1.0),
+    (1.0, 1.0),
+];
+
+fn parabola_vertex(left: f32, center: f32, right: f32) -> f32 {
+    let curvature = left - 2.0 * center + right;
+    if curvature <= 0.0 || !curvature.is_finite() {
+        return 0.0;
+    }
+    ((left - right) / (2.0 * curvature)).clamp(-0.5, 0.5)
+}
+
+/// Refines an integer `(dx, dy, error)` match around its position.
+///
+/// `cost` evaluates the block at a possibly fractional offset. Returns the
+/// refined vector and the number of extra candidates that were evaluated.
+pub fn refine<F>(
+    precision: SubpixelPrecision,
+    method: SubpixelMethod,
+    best: (i16, i16, f32),
+    mut cost: F,
+) -> ((f32, f32, f32), u64)
+where
+    F: FnMut(f32, f32) -> f32,
+{
+    let mut refined = (best.0 as f32, best.1 as f32, best.2);
+    if precision == SubpixelPrecision::Integer {
+        return (refined, 0);
+    }
+
+    match method {
+        SubpixelMethod::Bilinear | SubpixelMethod::Bicubic => {
+            let mut candidates = 0;
+            for &step in precision.steps() {
+                let center = (refined.0, refined.1);
+                for (nx, ny) in NEIGHBOURS {
+                    let (dx, dy) = (center.0 + nx * step, center.1 + ny * step);
+                    let error = cost(dx, dy);
+                    candidates += 1;
+                    if error < refined.2 {
+                        refined = (dx, dy, error);
+                    }
+                }
+            }
+            (refined, candidates)
+        }
+        SubpixelMethod::Parabolic => {
+            let (dx, dy) = (refined.0, refined.1);
+            let left = cost(dx - 1.0, dy);
+            let right = cost(dx + 1.0, dy);
+            let top = cost(dx, dy - 1.0);
+            let bottom = cost(dx, dy + 1.0);
+            let step = precision.finest_step();
+            let offset_x = (parabola_vertex(left, refined.2, right) / step).round() * step;
+            let offset_y = (parabola_vertex(top, refined.2, bottom) / step).round() * step;
+            ((dx + offset_x, dy + offset_y, refined.2), 4)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::estimator::combinator::bma::MatchCostKind;
+    use crate::operations::{sample_bicubic, sample_bilinear};
+    use image::{ImageBuffer, Rgb};
+
+    // Smooth texture whose second frame is moved by exactly `shift`.
+    fn frame(shift: (f32, f32)) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(32, 32, |x, y| {
+            let (u, v) = (x as f32 - shift.0, y as f32 - shift.1);
+            let value = 128.0 + 50.0 * (0.4 * u + 0.15 * v).sin() + 40.0 * (0.35 * v - 0.1 * u).cos();
+            Rgb([value as u8, (255.0 - value) as u8, (value * 0.5) as u8])
+        })
+    }
+
+    // Matches the 8x8 block at (12, 12) of the unshifted frame and refines
+    // the best integer vector.
+    fn refined_shift(shift: (f32, f32), precision: SubpixelPrecision, method: SubpixelMethod) -> (f32, f32) {
+        let (frame_1, frame_2) = (frame((0.0, 0.0)), frame(shift));
+        let match_cost = MatchCostKind::Euclidean.match_cost();
+        let block = |img: &ImageBuffer<Rgb<u8>, Vec<u8>>, dx: f32, dy: f32| {
+            let mut pixels = Vec::with_capacity(64);
+            for y in 12..20 {
+                for x in 12..20 {
+                    let (x, y) = (x as f32 + dx, y as f32 + dy);
+                    pixels.push(match method {
+                        SubpixelMethod::Bicubic => sample_bicubic(img, x, y),
+                        _ => sample_bilinear(img, x, y),
+                    });
+                }
+            }
+            pixels
+        };
+        let reference = block(&frame_1, 0.0, 0.0);
+        let cost = |dx: f32, dy: f32| match_cost.cost(&reference, &block(&frame_2, dx, dy), 8);
+
+        let mut best = (0, 0, f32::INFINITY);
+        for dy in -3..=3 {
+            for dx in -3..=3 {
+                let error = cost(dx as f32, dy as f32);
+                if error < best.2 {
+                    best = (dx, dy, error);
+                }
+            }
+        }
+        let ((dx, dy, _), _) = refine(precision, method, best, cost);
+        (dx, dy)
+    }
+
+    #[test]
+    fn integer_precision_keeps_the_integer_vector() {
+        let ((dx, dy, error), candidates) =
+            refine(SubpixelPrecision::Integer, SubpixelMethod::Bicubic, (2, -1, 3.0), |_, _| 0.0);
+        assert_eq!(((dx, dy, error), candidates), ((2.0, -1.0, 3.0), 0));
+    }
+
+    #[test]
+    fn methods_recover_fractional_shifts() {
+        for method in [SubpixelMethod::Bilinear, SubpixelMethod::Bicubic, SubpixelMethod::Parabolic] {
+            for shift in [(0.5, -1.5), (1.5, 0.0), (-2.0, 0.5)] {
+                assert_eq!(refined_shift(shift, SubpixelPrecision::Half, method), shift, "{:?}", method);
+            }
+            for shift in [(0.25, -0.75), (1.25, 0.5), (-1.75, 1.0)] {
+                assert_eq!(refined_shift(shift, SubpixelPrecision::Quarter, method), shift, "{:?}", method);
+            }
+        }
+    }
+
+    #[test]
+    fn parabola_vertex_stays_between_the_neighbours() {
+        assert_eq!(parabola_vertex(4.0, 1.0, 4.0), 0.0);
+        assert_eq!(parabola_vertex(3.0, 1.0, 1.0), 0.5);
+        assert_eq!(parabola_vertex(9.0, 2.0, 1.0), 0.5);
+        assert_eq!(parabola_vertex(1.0, 1.0, 1.0), 0.0);
+    }
+}
@@ -0,0 +1,324 @@
+mod json;
+
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::error::{Result, VfiError};
+use crate::flow::{flo, FlowField};
+
+// How often a plugin with a timeout is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Encoding of the flow an external estimator writes to stdout. Vectors are
+/// listed row by row, one per pixel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowPayload {
+    /// `height width` on the first line, then one `dx dy` line per pixel.
+    Text,
+    /// Middlebury `.flo`: the bytes `PIEH`, width and height as little-endian
+    /// `i32`, then `dx dy` pairs as little-endian `f32`.
+    Binary,
+    /// `{"width": w, "height": h, "flow": [[dx, dy], ...]}`.
+    Json,
+}
+
+pub(super) struct ExternalCommand<'a> {
+    pub executable: &'a Path,
+    pub arguments: &'a [String],
+    pub payload: FlowPayload,
+    pub timeout: Option<Duration>,
+}
+
+impl ExternalCommand<'_> {
+    pub fn validate(&self) -> Result<()> {
+        if self.executable.as_os_str().is_empty() {
+            return Err(VfiError::InvalidSettings(
+                "external estimator needs an executable".to_string(),
+            ));
+        }
+        if self.timeout == Some(Duration::ZERO) {
+            return Err(VfiError::InvalidSettings(
+                "external estimator timeout must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        self.executable.display().to_string()
+    }
+
+    // `{frame_1}` and `{frame_2}` in the arguments are replaced by the frame
+    // paths; without arguments the two paths are passed on their own.
+    fn arguments_for(&self, frame_1_filename: &str, frame_2_filename: &str) -> Vec<String> {
+        if self.arguments.is_empty() {
+            return vec![frame_1_filename.to_string(), frame_2_filename.to_string()];
+        }
+        self.arguments
+            .iter()
+            .map(|argument| {
+                argument
+                    .replace("{frame_1}", frame_1_filename)
+                    .replace("{frame_2}", frame_2_filename)
+            })
+            .collect()
+    }
+
+    pub fn run(
+        &self,
+        frame_1_filename: Option<&str>,
+        frame_2_filename: Option<&str>,
+    ) -> Result<FlowField> {
+        let name = self.name();
+        let (frame_1_filename, frame_2_filename) = match (frame_1_filename, frame_2_filename) {
+            (Some(frame_1_filename), Some(frame_2_filename)) => (frame_1_filename, frame_2_filename),
+            _ => {
+                return Err(VfiError::MissingInput(format!(
+                    "{} needs frames loaded from files",
+                    name
+                )))
+            }
+        };
+        let mut child = Command::new(self.executable)
+            .args(self.arguments_for(frame_1_filename, frame_2_filename))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| {
+                VfiError::ExternalEstimator(format!("failed to execute {}: {}", name, err))
+            })?;
+        // Both pipes are drained while waiting so a chatty plugin cannot
+        // block on a full pipe.
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+        let status = match self.timeout {
+            Some(timeout) => wait_with_timeout(&mut child, timeout, &name)?,
+            None => child.wait()?,
+        };
+        let stdout = join_output(stdout, &name)?;
+        let stderr = join_output(stderr, &name)?;
+
+        if !status.success() {
+            return Err(VfiError::ExternalEstimator(format!(
+                "{} exited with {}: {}",
+                name,
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            )));
+        }
+        match self.payload {
+            FlowPayload::Text => parse_text(&stdout, &name),
+            FlowPayload::Binary => parse_binary(&stdout, &name),
+            FlowPayload::Json => parse_json(&stdout, &name),
+        }
+    }
+}
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut output)?;
+        }
+        Ok(output)
+    })
+}
+
+fn join_output(handle: JoinHandle<io::Result<Vec<u8>>>, name: &str) -> Result<Vec<u8>> {
+    handle
+        .join()
+        .map_err(|_| VfiError::ExternalEstimator(format!("failed to read output of {}", name)))?
+        .map_err(VfiError::from)
+}
+
+fn wait_with_timeout(child: &mut Child, timeout: Duration, name: &str) -> Result<ExitStatus> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if start.elapsed() >= timeout {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(VfiError::ExternalEstimator(format!(
+                "{} timed out after {:?}",
+                name, timeout
+            )));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn malformed_output(name: &str, reason: &str) -> VfiError {
+    VfiError::ExternalEstimator(format!("{} produced malformed output: {}", name, reason))
+}
+
+fn empty_flow(name: &str, width: u32, height: u32) -> VfiError {
+    malformed_output(name, &format!("a {}x{} flow field holds no vectors", width, height))
+}
+
+fn parse_text(output: &[u8], name: &str) -> Result<FlowField> {
+    let output = String::from_utf8_lossy(output);
+    let mut lines = output.lines();
+    let at_line = |line: usize| malformed_output(name, &format!("line {}", line));
+
+    let mut parts = lines
+        .next()
+        .ok_or_else(|| at_line(1))?
+        .split_whitespace()
+        .map(|s| s.parse::<u32>());
+    let height = parts.next().and_then(|p| p.ok()).ok_or_else(|| at_line(1))?;
+    let width = parts.next().and_then(|p| p.ok()).ok_or_else(|| at_line(1))?;
+    if width == 0 || height == 0 {
+        return Err(empty_flow(name, width, height));
+    }
+    // The header is checked against the output before anything is allocated
+    // for it.
+    let lines_left = output.lines().count() - 1;
+    if (width as u64) * (height as u64) > lines_left as u64 {
+        return Err(malformed_output(
+            name,
+            &format!("a {}x{} header with {} vector lines", width, height, lines_left),
+        ));
+    }
+
+    let mut flow = FlowField::new(width, height, 1);
+    for (index, vector) in flow.iter_mut().enumerate() {
+        let line_number = index + 2;
+        let mut offset = lines
+            .next()
+            .ok_or_else(|| at_line(line_number))?
+            .split_whitespace()
+            .map(|s| s.parse::<f32>());
+        let offset_x = offset.next().and_then(|o| o.ok());
+        let offset_y = offset.next().and_then(|o| o.ok());
+        match (offset_x, offset_y) {
+            (Some(offset_x), Some(offset_y)) => *vector = (offset_x, offset_y),
+            _ => return Err(at_line(line_number)),
+        }
+    }
+
+    Ok(flow)
+}
+
+fn parse_binary(output: &[u8], name: &str) -> Result<FlowField> {
+    flo::decode(output).map_err(|err| match err {
+        VfiError::MalformedFlow(reason) => malformed_output(name, &reason),
+        err => err,
+    })
+}
+
+fn parse_json(output: &[u8], name: &str) -> Result<FlowField> {
+    let value = json::parse(output).map_err(|reason| malformed_output(name, &reason))?;
+    let dimension = |key: &str| {
+        value
+            .get(key)
+            .and_then(|value| value.as_f64())
+            .filter(|number| *number >= 0.0 && number.fract() == 0.0 && *number <= u32::MAX as f64)
+            .map(|number| number as u32)
+            .ok_or_else(|| malformed_output(name, &format!("missing or invalid \"{}\"", key)))
+    };
+    let width = dimension("width")?;
+    let height = dimension("height")?;
+    if width == 0 || height == 0 {
+        return Err(empty_flow(name, width, height));
+    }
+    let vectors = value
+        .get("flow")
+        .and_then(|flow| flow.as_array())
+        .ok_or_else(|| malformed_output(name, "missing \"flow\" array"))?
+        .iter()
+        .enumerate()
+        .map(|(index, vector)| {
+            match vector.as_array().map(|pair| (pair.len(), pair)) {
+                Some((2, pair)) => match (pair[0].as_f64(), pair[1].as_f64()) {
+                    (Some(dx), Some(dy)) => Ok((dx as f32, dy as f32)),
+                    _ => Err(malformed_output(name, &format!("vector {} is not numeric", index))),
+                },
+                _ => Err(malformed_output(name, &format!("vector {} is not a pair", index))),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if (width as usize).checked_mul(height as usize) != Some(vectors.len()) {
+        return Err(malformed_output(
+            name,
+            &format!("expected {}x{} vectors, got {}", width, height, vectors.len()),
+        ));
+    }
+    FlowField::from_vectors(width, height, 1, vectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_malformed(result: Result<FlowField>, reason: &str) {
+        match result {
+            Err(VfiError::ExternalEstimator(message)) => {
+                assert!(message.contains(reason), "{:?} does not mention {:?}", message, reason)
+            }
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(flow) => panic!("parsed a {:?} flow", flow.dimensions()),
+        }
+    }
+
+    #[test]
+    fn parses_text() {
+        let flow = parse_text(b"1 2\n0.5 -1\n2 3\n", "plugin").unwrap();
+        assert_eq!(flow.dimensions(), (2, 1));
+        assert_eq!((flow[(0, 0)], flow[(1, 0)]), ((0.5, -1.0), (2.0, 3.0)));
+    }
+
+    #[test]
+    fn rejects_text_header_larger_than_output() {
+        assert_malformed(parse_text(b"70000 70000\n0 0\n", "plugin"), "70000x70000 header");
+        assert_malformed(parse_text(b"4294967295 4294967295\n", "plugin"), "header");
+        assert_malformed(parse_text(b"2 2\n0 0\n0 0\n0 0\n", "plugin"), "2x2 header");
+    }
+
+    #[test]
+    fn rejects_bad_text_lines() {
+        assert_malformed(parse_text(b"", "plugin"), "line 1");
+        assert_malformed(parse_text(b"1\n", "plugin"), "line 1");
+        assert_malformed(parse_text(b"0 3\n", "plugin"), "holds no vectors");
+        assert_malformed(parse_text(b"1 2\n0 0\n1\n", "plugin"), "line 3");
+        assert_malformed(parse_text(b"1 1\nx 0\n", "plugin"), "line 2");
+    }
+
+    #[test]
+    fn parses_json() {
+        let flow = parse_json(br#"{"width": 2, "height": 1, "flow": [[0.5, -1], [2, 3]]}"#, "plugin").unwrap();
+        assert_eq!(flow.dimensions(), (2, 1));
+        assert_eq!((flow[(0, 0)], flow[(1, 0)]), ((0.5, -1.0), (2.0, 3.0)));
+    }
+
+    #[test]
+    fn rejects_json_vectors_that_are_not_pairs() {
+        let payload = |flow: &str| format!(r#"{{"width": 1, "height": 1, "flow": {}}}"#, flow);
+        assert_malformed(parse_json(payload("[[1, 2, 3]]").as_bytes(), "plugin"), "vector 0 is not a pair");
+        assert_malformed(parse_json(payload("[1]").as_bytes(), "plugin"), "vector 0 is not a pair");
+        assert_malformed(parse_json(payload(r#"[[1, "2"]]"#).as_bytes(), "plugin"), "vector 0 is not numeric");
+        assert_malformed(parse_json(payload("{}").as_bytes(), "plugin"), "missing \"flow\"");
+    }
+
+    #[test]
+    fn rejects_wrong_json_vector_counts() {
+        let payload = br#"{"width": 2, "height": 2, "flow": [[0, 0], [0, 0], [0, 0]]}"#;
+        assert_malformed(parse_json(payload, "plugin"), "expected 2x2 vectors, got 3");
+        let payload = br#"{"width": 0, "height": 0, "flow": []}"#;
+        assert_malformed(parse_json(payload, "plugin"), "holds no vectors");
+    }
+
+    #[test]
+    fn rejects_bad_json_dimensions_and_syntax() {
+        assert_malformed(parse_json(br#"{"width": 1.5, "height": 1, "flow": []}"#, "plugin"), "\"width\"");
+        assert_malformed(parse_json(br#"{"width": -1, "height": 1, "flow": []}"#, "plugin"), "\"width\"");
+        assert_malformed(parse_json(br#"{"height": 1, "flow": []}"#, "plugin"), "\"width\"");
+        assert_malformed(parse_json(b"{} {}", "plugin"), "trailing data");
+        assert_malformed(parse_json("[".repeat(1000).as_bytes(), "plugin"), "nested deeper");
+    }
+}
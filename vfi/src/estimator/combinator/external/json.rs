@@ -0,0 +1,278 @@
+// Minimal JSON reader for plugin payloads. Booleans and strings are checked
+// for syntax but their values are dropped, since the payload never uses them.
+
+// The payload nests three levels deep; the limit leaves some room for extra
+// members while keeping the recursion far from the stack limit.
+const MAX_DEPTH: usize = 8;
+
+pub enum JsonValue {
+    Null,
+    Bool,
+    Number(f64),
+    String,
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[JsonValue]> {
+        match self {
+            JsonValue::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .bytes
+            .get(self.position)
+            .is_some_and(|byte| byte.is_ascii_whitespace())
+        {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.bytes.get(self.position).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        if self.peek() == Some(byte) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}' at byte {}", byte as char, self.position))
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: JsonValue) -> Result<JsonValue, String> {
+        if self.bytes[self.position..].starts_with(word.as_bytes()) {
+            self.position += word.len();
+            Ok(value)
+        } else {
+            Err(format!("unexpected token at byte {}", self.position))
+        }
+    }
+
+    fn value(&mut self) -> Result<JsonValue, String> {
+        match self.peek() {
+            Some(b'{') => self.nested(Parser::object),
+            Some(b'[') => self.nested(Parser::array),
+            Some(b'"') => self.string().map(|_| JsonValue::String),
+            Some(b't') => self.keyword("true", JsonValue::Bool),
+            Some(b'f') => self.keyword("false", JsonValue::Bool),
+            Some(b'n') => self.keyword("null", JsonValue::Null),
+            Some(_) => self.number(),
+            None => Err("unexpected end of input".to_string()),
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<JsonValue, String>) -> Result<JsonValue, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(format!("nested deeper than {} levels at byte {}", MAX_DEPTH, self.position));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn object(&mut self) -> Result<JsonValue, String> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+            return Ok(JsonValue::Object(members));
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(b':')?;
+            members.push((key, self.value()?));
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                _ => break,
+            }
+        }
+        self.expect(b'}')?;
+        Ok(JsonValue::Object(members))
+    }
+
+    fn array(&mut self) -> Result<JsonValue, String> {
+        self.expect(b'[')?;
+        let mut values = Vec::new();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+            return Ok(JsonValue::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                _ => break,
+            }
+        }
+        self.expect(b']')?;
+        Ok(JsonValue::Array(values))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut result = String::new();
+        loop {
+            let start = self.position;
+            while self
+                .bytes
+                .get(self.position)
+                .is_some_and(|&byte| byte != b'"' && byte != b'\\')
+            {
+                self.position += 1;
+            }
+            result.push_str(
+                std::str::from_utf8(&self.bytes[start..self.position])
+                    .map_err(|_| format!("invalid UTF-8 in string at byte {}", start))?,
+            );
+            match self.bytes.get(self.position) {
+                Some(b'"') => {
+                    self.position += 1;
+                    return Ok(result);
+                }
+                Some(b'\\') => {
+                    let escaped = self.bytes.get(self.position + 1).copied();
+                    self.position += 2;
+                    result.push(match escaped {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let digits = self
+                                .bytes
+                                .get(self.position..self.position + 4)
+                                .and_then(|digits| std::str::from_utf8(digits).ok())
+                                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                                .ok_or_else(|| format!("invalid escape at byte {}", self.position))?;
+                            self.position += 4;
+                            char::from_u32(digits).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => return Err(format!("invalid escape at byte {}", self.position - 1)),
+                    });
+                }
+                _ => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<JsonValue, String> {
+        let start = self.position;
+        while self
+            .bytes
+            .get(self.position)
+            .is_some_and(|byte| matches!(byte, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'))
+        {
+            self.position += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.position])
+            .ok()
+            .and_then(|text| text.parse().ok())
+            .map(JsonValue::Number)
+            .ok_or_else(|| format!("invalid number at byte {}", start))
+    }
+}
+
+pub fn parse(text: &[u8]) -> Result<JsonValue, String> {
+    let mut parser = Parser {
+        bytes: text,
+        position: 0,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    match parser.peek() {
+        None => Ok(value),
+        Some(_) => Err(format!("trailing data at byte {}", parser.position)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested_arrays(depth: usize) -> String {
+        format!("{}{}", "[".repeat(depth), "]".repeat(depth))
+    }
+
+    #[test]
+    fn parses_a_payload() {
+        let value = parse(br#" {"width": 2, "height": 1, "flow": [[0.5, -1e-1], [3, 4]], "note": null} "#).unwrap();
+        assert_eq!(value.get("width").and_then(JsonValue::as_f64), Some(2.0));
+        let flow = value.get("flow").and_then(JsonValue::as_array).unwrap();
+        let first = flow[0].as_array().unwrap();
+        assert_eq!((first[0].as_f64(), first[1].as_f64()), (Some(0.5), Some(-0.1)));
+        assert!(value.get("missing").is_none());
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        assert!(parse(nested_arrays(MAX_DEPTH).as_bytes()).is_ok());
+        let error = parse(nested_arrays(MAX_DEPTH + 1).as_bytes()).err().unwrap();
+        assert!(error.contains("nested deeper"), "{}", error);
+        assert!(parse("[".repeat(100_000).as_bytes()).is_err());
+    }
+
+    #[test]
+    fn decodes_escapes_in_keys() {
+        let value = parse(br#"{"ab\n\"\\\/\t": 1}"#).unwrap();
+        assert_eq!(value.get("ab\n\"\\/\t").and_then(JsonValue::as_f64), Some(1.0));
+    }
+
+    #[test]
+    fn rejects_bad_escapes_and_strings() {
+        assert!(parse(br#"{"\x": 1}"#).is_err());
+        assert!(parse(br#"{"\u00g0": 1}"#).is_err());
+        assert!(parse(br#"{"open: 1}"#).is_err());
+        assert!(parse(b"{\"\xff\": 1}").is_err());
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let error = parse(b"[1, 2] 3").err().unwrap();
+        assert!(error.contains("trailing data"), "{}", error);
+        assert!(parse(b"[1, 2]]").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        for text in ["", "[1, 2", "[1 2]", "{\"a\" 1}", "{\"a\": }", "tru", "1.2.3", "[-]"] {
+            assert!(parse(text.as_bytes()).is_err(), "{:?} parsed", text);
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use image::{ImageBuffer, Rgb};
+
+use crate::error::{Result, VfiError};
+use crate::flow::FlowField;
+
+use super::float_image::{gaussian_kernel, resize_flow, to_flow_field, FloatImage};
+
+const MAX_PYRAMID_LEVELS: u8 = 8;
+// Coarser levels are skipped once either side would drop below this.
+const MIN_LEVEL_SIZE: u32 = 32;
+// Keeps the per-pixel 2x2 solve finite in flat regions.
+const REGULARIZATION: f32 = 1e-3;
+
+pub struct FarnebackSettings {
+    pub pyramid_scale: f32,
+    pub levels: u8,
+    pub window_size: u32,
+    pub iterations: u32,
+    pub poly_n: u32,
+    pub poly_sigma: f32,
+}
+
+impl Default for FarnebackSettings {
+    fn default() -> FarnebackSettings {
+        FarnebackSettings {
+            pyramid_scale: 0.5,
+            levels: 3,
+            window_size: 15,
+            iterations: 3,
+            poly_n: 5,
+            poly_sigma: 1.2,
+        }
+    }
+}
+
+impl FarnebackSettings {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.pyramid_scale > 0.0 && self.pyramid_scale < 1.0) {
+            return Err(VfiError::InvalidSettings(format!(
+                "pyramid_scale must be between 0 and 1, got {}",
+                self.pyramid_scale
+            )));
+        }
+        if self.levels == 0 || self.levels > MAX_PYRAMID_LEVELS {
+            return Err(VfiError::InvalidSettings(format!(
+                "levels must be between 1 and {}, got {}",
+                MAX_PYRAMID_LEVELS, self.levels
+            )));
+        }
+        if self.window_size.is_multiple_of(2) {
+            return Err(VfiError::InvalidSettings(format!(
+                "window_size must be odd, got {}",
+                self.window_size
+            )));
+        }
+        if self.iterations == 0 {
+            return Err(VfiError::InvalidSettings(
+                "iterations must be positive".to_string(),
+            ));
+        }
+        if self.poly_n < 3 || self.poly_n.is_multiple_of(2) {
+            return Err(VfiError::InvalidSettings(format!(
+                "poly_n must be an odd number of at least 3, got {}",
+                self.poly_n
+            )));
+        }
+        if self.poly_sigma.is_nan() || self.poly_sigma <= 0.0 {
+            return Err(VfiError::InvalidSettings(format!(
+                "poly_sigma must be positive, got {}",
+                self.poly_sigma
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Per-pixel coefficients of `x^T A x + b^T x + c` fitted around every pixel,
+// with `A = [[a_xx, a_xy], [a_xy, a_yy]]`.
+struct Expansion {
+    b_x: FloatImage,
+    b_y: FloatImage,
+    a_xx: FloatImage,
+    a_yy: FloatImage,
+    a_xy: FloatImage,
+}
+
+// Fitting `c + b_x x + b_y y + a_xx x² + a_yy y² + e xy` to a Gaussian-weighted
+// neighbourhood by least squares is the same linear filter at every pixel.
+// Returns each neighbour's offset and its contribution to
+// `(b_x, b_y, a_xx, a_yy, e)`.
+fn expansion_filters(poly_n: u32, poly_sigma: f32) -> Vec<((i64, i64), [f32; 5])> {
+    let radius = (poly_n / 2) as i64;
+    let weights_1d = gaussian_kernel(poly_sigma, radius as u32);
+    let mut neighbours = Vec::new();
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let (x, y) = (dx as f64, dy as f64);
+            let weight =
+                weights_1d[(dx + radius) as usize] as f64 * weights_1d[(dy + radius) as usize] as f64;
+            neighbours.push(((dx, dy), weight, [1.0, x, y, x * x, y * y, x * y]));
+        }
+    }
+
+    let mut normal = [[0f64; 6]; 6];
+    for (_, weight, basis) in neighbours.iter() {
+        for row in 0..6 {
+            for column in 0..6 {
+                normal[row][column] += weight * basis[row] * basis[column];
+            }
+        }
+    }
+    let inverse = invert(normal);
+
+    neighbours
+        .iter()
+        .map(|&(offset, weight, basis)| {
+            let coefficient = |row: usize| {
+                (0..6).map(|k| inverse[row][k] * weight * basis[k]).sum::<f64>() as f32
+            };
+            (offset, [coefficient(1), coefficient(2), coefficient(3), coefficient(4), coefficient(5)])
+        })
+        .collect()
+}
+
+// Gauss-Jordan elimination with partial pivoting. The normal matrix of the
+// polynomial basis is symmetric positive definite, so pivots never vanish.
+fn invert(mut matrix: [[f64; 6]; 6]) -> [[f64; 6]; 6] {
+    let mut inverse = [[0f64; 6]; 6];
+    for (i, row) in inverse.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+    for column in 0..6 {
+        let pivot = (column..6)
+            .max_by(|&a, &b| matrix[a][column].abs().total_cmp(&matrix[b][column].abs()))
+            .unwrap();
+        matrix.swap(column, pivot);
+        inverse.swap(column, pivot);
+        let scale = matrix[column][column];
+        for k in 0..6 {
+            matrix[column][k] /= scale;
+            inverse[column][k] /= scale;
+        }
+        for row in 0..6 {
+            if row != column {
+                let factor = matrix[row][column];
+                for k in 0..6 {
+                    matrix[row][k] -= factor * matrix[column][k];
+                    inverse[row][k] -= factor * inverse[column][k];
+                }
+            }
+        }
+    }
+    inverse
+}
+
+fn expand(image: &FloatImage, filters: &[((i64, i64), [f32; 5])]) -> Expansion {
+    let (width, height) = image.dimensions();
+    let channel = |c: usize, scale: f32| {
+        FloatImage::from_fn(width, height, |x, y| {
+            let sum: f32 = filters
+                .iter()
+                .map(|&((dx, dy), weights)| {
+                    weights[c] * image.get_clamped(x as i64 + dx, y as i64 + dy)
+                })
+                .sum();
+            sum * scale
+        })
+    };
+    Expansion {
+        b_x: channel(0, 1.0),
+        b_y: channel(1, 1.0),
+        a_xx: channel(2, 1.0),
+        a_yy: channel(3, 1.0),
+        a_xy: channel(4, 0.5),
+    }
+}
+
+// Smooths `image` and resamples it to the size of a pyramid level `scale`
+// times smaller than the original.
+fn pyramid_level(image: &FloatImage, scale: f32, width: u32, height: u32) -> FloatImage {
+    if (width, height) == image.dimensions() {
+        return image.clone();
+    }
+    let sigma = (1.0 / scale - 1.0) * 0.5;
+    let radius = ((sigma * 5.0).round() as u32 | 1) / 2;
+    image.blur(&gaussian_kernel(sigma, radius)).resize(width, height)
+}
+
+// Level sizes and scales, finest first.
+fn level_sizes(width: u32, height: u32, settings: &FarnebackSettings) -> Vec<(u32, u32, f32)> {
+    let mut sizes = vec![(width, height, 1.0)];
+    let mut scale = 1.0;
+    for _ in 1..settings.levels {
+        scale *= settings.pyramid_scale;
+        let level_width = (width as f32 * scale).round() as u32;
+        let level_height = (height as f32 * scale).round() as u32;
+        if level_width < MIN_LEVEL_SIZE || level_height < MIN_LEVEL_SIZE {
+            break;
+        }
+        sizes.push((level_width, level_height, scale));
+    }
+    sizes
+}
+
+// One Farnebäck iteration. Around the current estimate `d` both expansions
+// give `A d = Δb` with `A` the mean of the two quadratic terms and
+// `Δb = -(b_2(x + d) - b_1(x)) / 2 + A d`; the system is averaged over the
+// window and solved for the new `d` at every pixel.
+fn update_flow(
+    expansion_1: &Expansion,
+    expansion_2: &Expansion,
+    flow_x: &FloatImage,
+    flow_y: &FloatImage,
+    window_size: u32,
+) -> (FloatImage, FloatImage) {
+    let (width, height) = flow_x.dimensions();
+    let mut terms = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let (dx, dy) = (flow_x.get(x, y), flow_y.get(x, y));
+            let (sx, sy) = (x as f32 + dx, y as f32 + dy);
+            let a_xx = (expansion_1.a_xx.get(x, y) + expansion_2.a_xx.sample(sx, sy)) / 2.0;
+            let a_yy = (expansion_1.a_yy.get(x, y) + expansion_2.a_yy.sample(sx, sy)) / 2.0;
+            let a_xy = (expansion_1.a_xy.get(x, y) + expansion_2.a_xy.sample(sx, sy)) / 2.0;
+            let delta_b_x = -(expansion_2.b_x.sample(sx, sy) - expansion_1.b_x.get(x, y)) / 2.0
+                + a_xx * dx
+                + a_xy * dy;
+            let delta_b_y = -(expansion_2.b_y.sample(sx, sy) - expansion_1.b_y.get(x, y)) / 2.0
+                + a_xy * dx
+                + a_yy * dy;
+            terms.push([
+                a_xx * a_xx + a_xy * a_xy,
+                a_xy * (a_xx + a_yy),
+                a_yy * a_yy + a_xy * a_xy,
+                a_xx * delta_b_x + a_xy * delta_b_y,
+                a_xy * delta_b_x + a_yy * delta_b_y,
+            ]);
+        }
+    }
+
+    let window = vec![1.0 / window_size as f32; window_size as usize];
+    let channel = |c: usize| {
+        FloatImage::from_fn(width, height, |x, y| terms[(y * width + x) as usize][c]).blur(&window)
+    };
+    let (g_xx, g_xy, g_yy, h_x, h_y) = (channel(0), channel(1), channel(2), channel(3), channel(4));
+    let determinant = |x: u32, y: u32| {
+        g_xx.get(x, y) * g_yy.get(x, y) - g_xy.get(x, y) * g_xy.get(x, y) + REGULARIZATION
+    };
+    (
+        FloatImage::from_fn(width, height, |x, y| {
+            (g_yy.get(x, y) * h_x.get(x, y) - g_xy.get(x, y) * h_y.get(x, y)) / determinant(x, y)
+        }),
+        FloatImage::from_fn(width, height, |x, y| {
+            (g_xx.get(x, y) * h_y.get(x, y) - g_xy.get(x, y) * h_x.get(x, y)) / determinant(x, y)
+        }),
+    )
+}
+
+/// Farnebäck polynomial-expansion flow from `frame_1` to `frame_2` at full
+/// resolution, refined coarse to fine.
+pub fn calc_flow(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    settings: &FarnebackSettings,
+) -> FlowField {
+    let image_1 = FloatImage::from_rgb(frame_1);
+    let image_2 = FloatImage::from_rgb(frame_2);
+    let (width, height) = image_1.dimensions();
+    let filters = expansion_filters(settings.poly_n, settings.poly_sigma);
+
+    let mut flow: Option<(FloatImage, FloatImage)> = None;
+    for &(level_width, level_height, scale) in level_sizes(width, height, settings).iter().rev() {
+        let level_1 = pyramid_level(&image_1, scale, level_width, level_height);
+        let level_2 = pyramid_level(&image_2, scale, level_width, level_height);
+        let (mut flow_x, mut flow_y) = match flow {
+            None => (
+                FloatImage::new(level_width, level_height),
+                FloatImage::new(level_width, level_height),
+            ),
+            Some((coarse_x, coarse_y)) => resize_flow(&coarse_x, &coarse_y, level_width, level_height),
+        };
+        let expansion_1 = expand(&level_1, &filters);
+        let expansion_2 = expand(&level_2, &filters);
+        for _ in 0..settings.iterations {
+            (flow_x, flow_y) =
+                update_flow(&expansion_1, &expansion_2, &flow_x, &flow_y, settings.window_size);
+        }
+        flow = Some((flow_x, flow_y));
+    }
+
+    let (flow_x, flow_y) = flow.unwrap();
+    to_flow_field(&flow_x, &flow_y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{interior_error, translation_frames};
+
+    // FNV-1a over the bits of every vector.
+    fn fingerprint(flow: &FlowField) -> u64 {
+        let mut hash = 0xcbf29ce484222325u64;
+        for &(dx, dy) in flow.iter() {
+            for bits in [dx.to_bits(), dy.to_bits()] {
+                for byte in bits.to_le_bytes() {
+                    hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+                }
+            }
+        }
+        hash
+    }
+
+    #[test]
+    fn recovers_translations() {
+        for (dx, dy) in [(2.5, -1.25), (3.0, 2.0), (-0.5, 0.75)] {
+            let frames = translation_frames(dx, dy, 2);
+            let flow = calc_flow(&frames[0], &frames[1], &FarnebackSettings::default());
+            let error = interior_error(&flow, (dx, dy), 16);
+            assert!(error < 0.1, "EPE {} for ({}, {})", error, dx, dy);
+        }
+    }
+
+    // The flow must not change between runs, builds or machines. Update the
+    // fingerprint only together with a deliberate change to the algorithm.
+    #[test]
+    fn flow_is_bit_identical() {
+        let frames = translation_frames(2.5, -1.25, 2);
+        let settings = FarnebackSettings::default();
+        let flow = calc_flow(&frames[0], &frames[1], &settings);
+        assert_eq!(flow, calc_flow(&frames[0], &frames[1], &settings));
+        assert_eq!(fingerprint(&flow), 0xd309a38ae707519f);
+    }
+}
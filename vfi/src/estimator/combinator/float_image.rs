@@ -0,0 +1,203 @@
+use image::{ImageBuffer, Rgb};
+
+use crate::flow::FlowField;
+
+/// Single-channel `f32` image used by the differential flow estimators.
+#[derive(Clone, Debug)]
+pub struct FloatImage {
+    width: u32,
+    height: u32,
+    data: Vec<f32>,
+}
+
+impl FloatImage {
+    pub fn new(width: u32, height: u32) -> FloatImage {
+        FloatImage::from_data(width, height, vec![0.0; (width * height) as usize])
+    }
+
+    pub fn from_data(width: u32, height: u32, data: Vec<f32>) -> FloatImage {
+        assert_eq!(data.len(), (width * height) as usize);
+        FloatImage { width, height, data }
+    }
+
+    pub fn from_fn<F>(width: u32, height: u32, mut f: F) -> FloatImage
+    where
+        F: FnMut(u32, u32) -> f32,
+    {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(f(x, y));
+            }
+        }
+        FloatImage { width, height, data }
+    }
+
+    /// Luminance of an RGB frame in grey levels (0-255).
+    pub fn from_rgb(img: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> FloatImage {
+        let (width, height) = img.dimensions();
+        FloatImage::from_fn(width, height, |x, y| {
+            let pixel = img[(x, y)];
+            0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> f32 {
+        self.data[(y * self.width + x) as usize]
+    }
+
+    /// Value at `(x, y)` with coordinates clamped to the image border.
+    pub fn get_clamped(&self, x: i64, y: i64) -> f32 {
+        self.get(
+            x.clamp(0, self.width as i64 - 1) as u32,
+            y.clamp(0, self.height as i64 - 1) as u32,
+        )
+    }
+
+    pub fn sample(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.get_clamped(x0, y0) * (1.0 - fx) + self.get_clamped(x0 + 1, y0) * fx;
+        let bottom =
+            self.get_clamped(x0, y0 + 1) * (1.0 - fx) + self.get_clamped(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    /// Convolves rows and then columns with the same symmetric `kernel`.
+    pub fn blur(&self, kernel: &[f32]) -> FloatImage {
+        let radius = (kernel.len() / 2) as i64;
+        let horizontal = FloatImage::from_fn(self.width, self.height, |x, y| {
+            kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * self.get_clamped(x as i64 + k as i64 - radius, y as i64))
+                .sum()
+        });
+        FloatImage::from_fn(self.width, self.height, |x, y| {
+            kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * horizontal.get_clamped(x as i64, y as i64 + k as i64 - radius))
+                .sum()
+        })
+    }
+
+    /// Next pyramid level: a 5-tap binomial blur followed by dropping every
+    /// other row and column.
+    pub fn pyr_down(&self) -> FloatImage {
+        let blurred = self.blur(&[1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0]);
+        FloatImage::from_fn((self.width / 2).max(1), (self.height / 2).max(1), |x, y| {
+            blurred.get((2 * x).min(self.width - 1), (2 * y).min(self.height - 1))
+        })
+    }
+
+    /// Bilinear resampling to `width`×`height`, aligning pixel centers.
+    pub fn resize(&self, width: u32, height: u32) -> FloatImage {
+        let scale_x = self.width as f32 / width as f32;
+        let scale_y = self.height as f32 / height as f32;
+        FloatImage::from_fn(width, height, |x, y| {
+            self.sample(
+                (x as f32 + 0.5) * scale_x - 0.5,
+                (y as f32 + 0.5) * scale_y - 0.5,
+            )
+        })
+    }
+
+    /// `levels` images, starting with a copy of `self` at full resolution.
+    pub fn pyramid(&self, levels: u8) -> Vec<FloatImage> {
+        let mut pyramid = vec![self.clone()];
+        for _ in 1..levels {
+            let next = pyramid.last().unwrap().pyr_down();
+            pyramid.push(next);
+        }
+        pyramid
+    }
+
+    /// Horizontal and vertical central differences.
+    pub fn gradients(&self) -> (FloatImage, FloatImage) {
+        let gradient_x = FloatImage::from_fn(self.width, self.height, |x, y| {
+            (self.get_clamped(x as i64 + 1, y as i64) - self.get_clamped(x as i64 - 1, y as i64))
+                / 2.0
+        });
+        let gradient_y = FloatImage::from_fn(self.width, self.height, |x, y| {
+            (self.get_clamped(x as i64, y as i64 + 1) - self.get_clamped(x as i64, y as i64 - 1))
+                / 2.0
+        });
+        (gradient_x, gradient_y)
+    }
+}
+
+// `e^x` for `x <= 0` from a Taylor series after halving `x` below 1/2, using
+// only basic arithmetic so results do not depend on the platform's libm.
+fn exp_non_positive(x: f64) -> f64 {
+    let mut halvings = 0;
+    let mut reduced = x;
+    while reduced < -0.5 {
+        reduced /= 2.0;
+        halvings += 1;
+    }
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    for n in 1..20 {
+        term *= reduced / n as f64;
+        sum += term;
+    }
+    for _ in 0..halvings {
+        sum *= sum;
+    }
+    sum
+}
+
+/// Normalized Gaussian weights for offsets `-radius..=radius`.
+pub fn gaussian_kernel(sigma: f32, radius: u32) -> Vec<f32> {
+    let sigma = sigma as f64;
+    let weights: Vec<f64> = (-(radius as i64)..=radius as i64)
+        .map(|offset| exp_non_positive(-((offset * offset) as f64) / (2.0 * sigma * sigma)))
+        .collect();
+    let total: f64 = weights.iter().sum();
+    weights.iter().map(|weight| (weight / total) as f32).collect()
+}
+
+/// Resizes a flow given as separate `x` and `y` components to `width`×`height`
+/// and rescales the vectors to the new size.
+pub fn resize_flow(
+    flow_x: &FloatImage,
+    flow_y: &FloatImage,
+    width: u32,
+    height: u32,
+) -> (FloatImage, FloatImage) {
+    let ratio_x = width as f32 / flow_x.width() as f32;
+    let ratio_y = height as f32 / flow_y.height() as f32;
+    let resized_x = flow_x.resize(width, height);
+    let resized_y = flow_y.resize(width, height);
+    (
+        FloatImage::from_fn(width, height, |x, y| resized_x.get(x, y) * ratio_x),
+        FloatImage::from_fn(width, height, |x, y| resized_y.get(x, y) * ratio_y),
+    )
+}
+
+pub fn to_flow_field(flow_x: &FloatImage, flow_y: &FloatImage) -> FlowField {
+    let (width, height) = flow_x.dimensions();
+    let mut field = FlowField::new(width, height, 1);
+    for (index, vector) in field.iter_mut().enumerate() {
+        *vector = (flow_x.data[index], flow_y.data[index]);
+    }
+    field
+}
@@ -0,0 +1,153 @@
+use image::{ImageBuffer, Rgb};
+
+use crate::error::{Result, VfiError};
+use crate::flow::FlowField;
+
+use super::float_image::{resize_flow, to_flow_field, FloatImage};
+
+const MAX_PYRAMID_LEVELS: u8 = 8;
+
+pub struct HornSchunckSettings {
+    pub alpha: f32,
+    pub iterations: u32,
+    pub pyramid_levels: u8,
+    pub warps: u32,
+}
+
+impl Default for HornSchunckSettings {
+    fn default() -> HornSchunckSettings {
+        HornSchunckSettings {
+            alpha: 15.0,
+            iterations: 100,
+            pyramid_levels: 4,
+            warps: 3,
+        }
+    }
+}
+
+impl HornSchunckSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.alpha.is_nan() || self.alpha <= 0.0 {
+            return Err(VfiError::InvalidSettings(format!(
+                "alpha must be positive, got {}",
+                self.alpha
+            )));
+        }
+        if self.iterations == 0 {
+            return Err(VfiError::InvalidSettings(
+                "iterations must be positive".to_string(),
+            ));
+        }
+        if self.pyramid_levels == 0 || self.pyramid_levels > MAX_PYRAMID_LEVELS {
+            return Err(VfiError::InvalidSettings(format!(
+                "pyramid_levels must be between 1 and {}, got {}",
+                MAX_PYRAMID_LEVELS, self.pyramid_levels
+            )));
+        }
+        if self.warps == 0 {
+            return Err(VfiError::InvalidSettings(
+                "warps must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Weighted mean of the eight neighbours used by Horn and Schunck: 1/6 for
+// direct and 1/12 for diagonal neighbours.
+fn neighbour_average(flow: &FloatImage) -> FloatImage {
+    let (width, height) = flow.dimensions();
+    FloatImage::from_fn(width, height, |x, y| {
+        let at = |dx: i64, dy: i64| flow.get_clamped(x as i64 + dx, y as i64 + dy);
+        (at(-1, 0) + at(1, 0) + at(0, -1) + at(0, 1)) / 6.0
+            + (at(-1, -1) + at(1, -1) + at(-1, 1) + at(1, 1)) / 12.0
+    })
+}
+
+// Horn-Schunck iterations on one level. The brightness constancy constraint
+// is linearized around the flow passed in, with `frame_2` warped back by it.
+fn refine_level(
+    image_1: &FloatImage,
+    image_2: &FloatImage,
+    initial_x: FloatImage,
+    initial_y: FloatImage,
+    settings: &HornSchunckSettings,
+) -> (FloatImage, FloatImage) {
+    let (width, height) = image_1.dimensions();
+    let (gradient_x_1, gradient_y_1) = image_1.gradients();
+    let (gradient_x_2, gradient_y_2) = image_2.gradients();
+
+    let mut derivatives = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let (sx, sy) = (x as f32 + initial_x.get(x, y), y as f32 + initial_y.get(x, y));
+            derivatives.push((
+                (gradient_x_1.get(x, y) + gradient_x_2.sample(sx, sy)) / 2.0,
+                (gradient_y_1.get(x, y) + gradient_y_2.sample(sx, sy)) / 2.0,
+                image_2.sample(sx, sy) - image_1.get(x, y),
+            ));
+        }
+    }
+
+    let alpha_squared = settings.alpha * settings.alpha;
+    let (mut flow_x, mut flow_y) = (initial_x.clone(), initial_y.clone());
+    for _ in 0..settings.iterations {
+        let average_x = neighbour_average(&flow_x);
+        let average_y = neighbour_average(&flow_y);
+        let mut next_x = Vec::with_capacity(derivatives.len());
+        let mut next_y = Vec::with_capacity(derivatives.len());
+        for (index, &(ix, iy, it)) in derivatives.iter().enumerate() {
+            let (u, v) = (average_x.data()[index], average_y.data()[index]);
+            let (u0, v0) = (initial_x.data()[index], initial_y.data()[index]);
+            let step = (ix * (u - u0) + iy * (v - v0) + it) / (alpha_squared + ix * ix + iy * iy);
+            next_x.push(u - ix * step);
+            next_y.push(v - iy * step);
+        }
+        flow_x = FloatImage::from_data(width, height, next_x);
+        flow_y = FloatImage::from_data(width, height, next_y);
+    }
+    (flow_x, flow_y)
+}
+
+/// Horn-Schunck flow from `frame_1` to `frame_2` at full resolution, refined
+/// coarse to fine.
+pub fn calc_flow(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    settings: &HornSchunckSettings,
+) -> FlowField {
+    let pyramid_1 = FloatImage::from_rgb(frame_1).pyramid(settings.pyramid_levels);
+    let pyramid_2 = FloatImage::from_rgb(frame_2).pyramid(settings.pyramid_levels);
+
+    let (coarse_width, coarse_height) = pyramid_1.last().unwrap().dimensions();
+    let mut flow = (
+        FloatImage::new(coarse_width, coarse_height),
+        FloatImage::new(coarse_width, coarse_height),
+    );
+    for (level_1, level_2) in pyramid_1.iter().zip(pyramid_2.iter()).rev() {
+        let (width, height) = level_1.dimensions();
+        if flow.0.dimensions() != (width, height) {
+            flow = resize_flow(&flow.0, &flow.1, width, height);
+        }
+        for _ in 0..settings.warps {
+            flow = refine_level(level_1, level_2, flow.0, flow.1, settings);
+        }
+    }
+    to_flow_field(&flow.0, &flow.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{interior_error, translation_frames};
+
+    #[test]
+    fn recovers_translations() {
+        for (dx, dy) in [(2.5, -1.25), (3.0, 2.0), (-0.5, 0.75)] {
+            let frames = translation_frames(dx, dy, 2);
+            let flow = calc_flow(&frames[0], &frames[1], &HornSchunckSettings::default());
+            let error = interior_error(&flow, (dx, dy), 16);
+            assert!(error < 0.1, "EPE {} for ({}, {})", error, dx, dy);
+        }
+    }
+}
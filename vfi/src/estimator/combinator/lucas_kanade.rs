@@ -0,0 +1,399 @@
+use image::{ImageBuffer, Rgb};
+
+use crate::error::{check_dimensions, Result, VfiError};
+use crate::flow::FlowField;
+
+use super::float_image::FloatImage;
+
+const MAX_PYRAMID_LEVELS: u8 = 8;
+// Iterations stop once the update is shorter than this, in pixels.
+const CONVERGENCE_THRESHOLD: f32 = 0.01;
+
+// Position of a tracked point in the first frame and its flow.
+type TrackedPoint = ((f32, f32), (f32, f32));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LucasKanadeMode {
+    /// Tracks corners of the first frame and spreads their vectors around them.
+    Sparse,
+    /// Tracks a point in the middle of every `grid_spacing` cell.
+    Dense,
+}
+
+pub struct LucasKanadeSettings {
+    pub mode: LucasKanadeMode,
+    pub window_radius: u32,
+    pub pyramid_levels: u8,
+    pub iterations: u32,
+    pub min_eigenvalue: f32,
+    pub grid_spacing: u32,
+    pub max_features: usize,
+    pub feature_quality: f32,
+    pub min_feature_distance: u32,
+}
+
+impl Default for LucasKanadeSettings {
+    fn default() -> LucasKanadeSettings {
+        LucasKanadeSettings {
+            mode: LucasKanadeMode::Dense,
+            window_radius: 7,
+            pyramid_levels: 3,
+            iterations: 10,
+            min_eigenvalue: 1.0,
+            grid_spacing: 4,
+            max_features: 1000,
+            feature_quality: 0.01,
+            min_feature_distance: 10,
+        }
+    }
+}
+
+impl LucasKanadeSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.window_radius == 0 {
+            return Err(VfiError::InvalidSettings(
+                "window_radius must be positive".to_string(),
+            ));
+        }
+        if self.pyramid_levels == 0 || self.pyramid_levels > MAX_PYRAMID_LEVELS {
+            return Err(VfiError::InvalidSettings(format!(
+                "pyramid_levels must be between 1 and {}, got {}",
+                MAX_PYRAMID_LEVELS, self.pyramid_levels
+            )));
+        }
+        if self.iterations == 0 {
+            return Err(VfiError::InvalidSettings(
+                "iterations must be positive".to_string(),
+            ));
+        }
+        if self.min_eigenvalue.is_nan() || self.min_eigenvalue < 0.0 {
+            return Err(VfiError::InvalidSettings(format!(
+                "min_eigenvalue must be non-negative, got {}",
+                self.min_eigenvalue
+            )));
+        }
+        if self.grid_spacing == 0 {
+            return Err(VfiError::InvalidSettings(
+                "grid_spacing must be positive".to_string(),
+            ));
+        }
+        if self.max_features == 0 {
+            return Err(VfiError::InvalidSettings(
+                "max_features must be positive".to_string(),
+            ));
+        }
+        if !(self.feature_quality > 0.0 && self.feature_quality <= 1.0) {
+            return Err(VfiError::InvalidSettings(format!(
+                "feature_quality must be in (0, 1], got {}",
+                self.feature_quality
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Pyramids of both frames and of the first frame's gradients, finest first.
+struct Tracker<'a> {
+    settings: &'a LucasKanadeSettings,
+    pyramid_1: Vec<FloatImage>,
+    gradients_1: Vec<(FloatImage, FloatImage)>,
+    pyramid_2: Vec<FloatImage>,
+}
+
+impl<'a> Tracker<'a> {
+    fn new(image_1: &FloatImage, image_2: &FloatImage, settings: &'a LucasKanadeSettings) -> Tracker<'a> {
+        let pyramid_1 = image_1.pyramid(settings.pyramid_levels);
+        let gradients_1 = pyramid_1.iter().map(|level| level.gradients()).collect();
+        Tracker {
+            settings,
+            pyramid_1,
+            gradients_1,
+            pyramid_2: image_2.pyramid(settings.pyramid_levels),
+        }
+    }
+
+    // Flow of the point at `(x, y)` and whether it was tracked at full
+    // resolution. Untracked points keep the estimate from the coarser levels.
+    fn track(&self, (x, y): (f32, f32)) -> ((f32, f32), bool) {
+        let mut flow = (0.0, 0.0);
+        let mut tracked = false;
+        for level in (0..self.pyramid_1.len()).rev() {
+            let scale = (1u32 << level) as f32;
+            if level + 1 < self.pyramid_1.len() {
+                flow = (flow.0 * 2.0, flow.1 * 2.0);
+            }
+            let refined = self.refine(level, (x / scale, y / scale), flow);
+            tracked = refined.is_some();
+            flow = refined.unwrap_or(flow);
+        }
+        let (width, height) = self.pyramid_1[0].dimensions();
+        let inside = (0.0..=(width - 1) as f32).contains(&(x + flow.0))
+            && (0.0..=(height - 1) as f32).contains(&(y + flow.1));
+        (flow, tracked && inside)
+    }
+
+    // Iterative Lucas-Kanade step on one level, starting from `guess`. Returns
+    // `None` when the window lacks texture in some direction.
+    fn refine(&self, level: usize, point: (f32, f32), guess: (f32, f32)) -> Option<(f32, f32)> {
+        let image_1 = &self.pyramid_1[level];
+        let (gradient_x, gradient_y) = &self.gradients_1[level];
+        let image_2 = &self.pyramid_2[level];
+        let radius = self.settings.window_radius as i32;
+
+        let mut window = Vec::with_capacity(((2 * radius + 1) * (2 * radius + 1)) as usize);
+        let (mut gxx, mut gxy, mut gyy) = (0f32, 0f32, 0f32);
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let (sx, sy) = (point.0 + dx as f32, point.1 + dy as f32);
+                let ix = gradient_x.sample(sx, sy);
+                let iy = gradient_y.sample(sx, sy);
+                gxx += ix * ix;
+                gxy += ix * iy;
+                gyy += iy * iy;
+                window.push((dx as f32, dy as f32, image_1.sample(sx, sy), ix, iy));
+            }
+        }
+
+        let determinant = gxx * gyy - gxy * gxy;
+        let min_eigenvalue =
+            (gxx + gyy - ((gxx - gyy).powi(2) + 4.0 * gxy * gxy).sqrt()) / (2.0 * window.len() as f32);
+        if min_eigenvalue < self.settings.min_eigenvalue || determinant <= f32::EPSILON {
+            return None;
+        }
+
+        let mut flow = guess;
+        for _ in 0..self.settings.iterations {
+            let (mut bx, mut by) = (0f32, 0f32);
+            for &(dx, dy, value, ix, iy) in window.iter() {
+                let difference =
+                    value - image_2.sample(point.0 + dx + flow.0, point.1 + dy + flow.1);
+                bx += difference * ix;
+                by += difference * iy;
+            }
+            let step = (
+                (gyy * bx - gxy * by) / determinant,
+                (gxx * by - gxy * bx) / determinant,
+            );
+            flow = (flow.0 + step.0, flow.1 + step.1);
+            if step.0 * step.0 + step.1 * step.1 < CONVERGENCE_THRESHOLD * CONVERGENCE_THRESHOLD {
+                break;
+            }
+        }
+        Some(flow)
+    }
+}
+
+// Shi-Tomasi corners: local maxima of the structure tensor's smaller
+// eigenvalue, strongest first, at least `min_feature_distance` apart.
+fn good_features(image: &FloatImage, settings: &LucasKanadeSettings) -> Vec<(f32, f32)> {
+    let (width, height) = image.dimensions();
+    let (gradient_x, gradient_y) = image.gradients();
+    let product = |f: &dyn Fn(f32, f32) -> f32| {
+        FloatImage::from_fn(width, height, |x, y| f(gradient_x.get(x, y), gradient_y.get(x, y)))
+            .blur(&[1.0, 1.0, 1.0])
+    };
+    let xx = product(&|ix, _| ix * ix);
+    let xy = product(&|ix, iy| ix * iy);
+    let yy = product(&|_, iy| iy * iy);
+    let response = FloatImage::from_fn(width, height, |x, y| {
+        let (a, b, c) = (xx.get(x, y), xy.get(x, y), yy.get(x, y));
+        (a + c - ((a - c).powi(2) + 4.0 * b * b).sqrt()) / 2.0
+    });
+
+    let max_response = response.data().iter().cloned().fold(0f32, f32::max);
+    if max_response <= 0.0 {
+        return Vec::new();
+    }
+    let threshold = settings.feature_quality * max_response;
+    let mut candidates = Vec::new();
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let value = response.get(x, y);
+            let is_local_max = (-1..=1).all(|dy| {
+                (-1..=1).all(|dx| response.get_clamped(x as i64 + dx, y as i64 + dy) <= value)
+            });
+            if value >= threshold && is_local_max {
+                candidates.push((value, x as f32, y as f32));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let min_distance = settings.min_feature_distance as f32;
+    let mut features: Vec<(f32, f32)> = Vec::new();
+    for (_, x, y) in candidates {
+        if features.len() == settings.max_features {
+            break;
+        }
+        let isolated = features
+            .iter()
+            .all(|&(fx, fy)| (fx - x).powi(2) + (fy - y).powi(2) >= min_distance * min_distance);
+        if isolated {
+            features.push((x, y));
+        }
+    }
+    features
+}
+
+/// Corners of `frame` worth tracking, strongest first.
+pub fn detect_features(
+    frame: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    settings: &LucasKanadeSettings,
+) -> Result<Vec<(f32, f32)>> {
+    settings.validate()?;
+    Ok(good_features(&FloatImage::from_rgb(frame), settings))
+}
+
+/// Flow of every point from `frame_1` to `frame_2`, or `None` where the point
+/// was lost.
+pub fn track_features(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    points: &[(f32, f32)],
+    settings: &LucasKanadeSettings,
+) -> Result<Vec<Option<(f32, f32)>>> {
+    check_dimensions(frame_1.dimensions(), frame_2.dimensions())?;
+    settings.validate()?;
+    let image_1 = FloatImage::from_rgb(frame_1);
+    let image_2 = FloatImage::from_rgb(frame_2);
+    let tracker = Tracker::new(&image_1, &image_2, settings);
+    Ok(points
+        .iter()
+        .map(|&point| {
+            let (flow, tracked) = tracker.track(point);
+            tracked.then_some(flow)
+        })
+        .collect())
+}
+
+// Spreads the vectors of tracked features over the frame with Gaussian
+// weights. Pixels out of reach of every feature get no motion.
+fn splat_features(width: u32, height: u32, tracked: &[TrackedPoint], sigma: f32) -> FlowField {
+    let reach = (2.0 * sigma).ceil() as i64;
+    let mut sums = vec![(0f32, 0f32, 0f32); (width * height) as usize];
+    for &((px, py), (dx, dy)) in tracked {
+        let (cx, cy) = (px.round() as i64, py.round() as i64);
+        for y in (cy - reach).max(0)..=(cy + reach).min(height as i64 - 1) {
+            for x in (cx - reach).max(0)..=(cx + reach).min(width as i64 - 1) {
+                let distance = (x as f32 - px).powi(2) + (y as f32 - py).powi(2);
+                let weight = (-distance / (2.0 * sigma * sigma)).exp();
+                let sum = &mut sums[(y as u32 * width + x as u32) as usize];
+                *sum = (sum.0 + weight * dx, sum.1 + weight * dy, sum.2 + weight);
+            }
+        }
+    }
+    let mut flow = FlowField::new(width, height, 1);
+    for (vector, &(sum_x, sum_y, total)) in flow.iter_mut().zip(sums.iter()) {
+        if total > f32::EPSILON {
+            *vector = (sum_x / total, sum_y / total);
+        }
+    }
+    flow
+}
+
+fn sparse_flow(tracker: &Tracker, image_1: &FloatImage, settings: &LucasKanadeSettings) -> FlowField {
+    let tracked: Vec<_> = good_features(image_1, settings)
+        .into_iter()
+        .filter_map(|point| {
+            let (flow, tracked) = tracker.track(point);
+            tracked.then_some((point, flow))
+        })
+        .collect();
+    let (width, height) = image_1.dimensions();
+    splat_features(width, height, &tracked, settings.min_feature_distance.max(1) as f32)
+}
+
+// Tracks the center of every grid cell and interpolates between them.
+fn dense_flow(tracker: &Tracker, width: u32, height: u32, spacing: u32) -> FlowField {
+    let offset = (spacing - 1) as f32 / 2.0;
+    let (grid_width, grid_height) = (width.div_ceil(spacing), height.div_ceil(spacing));
+    let mut grid = FlowField::new(grid_width, grid_height, spacing);
+    for y in 0..grid_height {
+        for x in 0..grid_width {
+            let center = (
+                ((x * spacing) as f32 + offset).min((width - 1) as f32),
+                ((y * spacing) as f32 + offset).min((height - 1) as f32),
+            );
+            grid[(x, y)] = tracker.track(center).0;
+        }
+    }
+    if spacing == 1 {
+        return grid;
+    }
+
+    let mut flow = FlowField::new(width, height, 1);
+    for y in 0..height {
+        let gy = ((y as f32 - offset) / spacing as f32).clamp(0.0, (grid_height - 1) as f32);
+        let (y0, fy) = (gy.floor() as u32, gy.fract());
+        let y1 = (y0 + 1).min(grid_height - 1);
+        for x in 0..width {
+            let gx = ((x as f32 - offset) / spacing as f32).clamp(0.0, (grid_width - 1) as f32);
+            let (x0, fx) = (gx.floor() as u32, gx.fract());
+            let x1 = (x0 + 1).min(grid_width - 1);
+            let lerp = |a: (f32, f32), b: (f32, f32), f: f32| (a.0 + (b.0 - a.0) * f, a.1 + (b.1 - a.1) * f);
+            let top = lerp(grid[(x0, y0)], grid[(x1, y0)], fx);
+            let bottom = lerp(grid[(x0, y1)], grid[(x1, y1)], fx);
+            flow[(x, y)] = lerp(top, bottom, fy);
+        }
+    }
+    flow
+}
+
+/// Pyramidal Lucas-Kanade flow from `frame_1` to `frame_2` at full resolution.
+pub fn calc_flow(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    settings: &LucasKanadeSettings,
+) -> FlowField {
+    let image_1 = FloatImage::from_rgb(frame_1);
+    let image_2 = FloatImage::from_rgb(frame_2);
+    let tracker = Tracker::new(&image_1, &image_2, settings);
+    match settings.mode {
+        LucasKanadeMode::Sparse => sparse_flow(&tracker, &image_1, settings),
+        LucasKanadeMode::Dense => {
+            dense_flow(&tracker, image_1.width(), image_1.height(), settings.grid_spacing)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{interior_error, translation_frames};
+
+    #[test]
+    fn dense_flow_recovers_translations() {
+        for (dx, dy) in [(2.5, -1.25), (3.0, 2.0), (-0.5, 0.75)] {
+            let frames = translation_frames(dx, dy, 2);
+            let flow = calc_flow(&frames[0], &frames[1], &LucasKanadeSettings::default());
+            let error = interior_error(&flow, (dx, dy), 16);
+            assert!(error < 0.05, "EPE {} for ({}, {})", error, dx, dy);
+        }
+    }
+
+    #[test]
+    fn tracked_features_follow_a_translation() {
+        let frames = translation_frames(2.5, -1.25, 2);
+        let settings = LucasKanadeSettings::default();
+        let points: Vec<(f32, f32)> = detect_features(&frames[0], &settings)
+            .unwrap()
+            .into_iter()
+            .filter(|&(x, y)| (16.0..112.0).contains(&x) && (16.0..80.0).contains(&y))
+            .collect();
+        assert!(points.len() >= 20, "only {} inner features", points.len());
+        let flows = track_features(&frames[0], &frames[1], &points, &settings).unwrap();
+        for (point, flow) in points.iter().zip(flows) {
+            let (dx, dy) = flow.unwrap_or_else(|| panic!("lost the feature at {:?}", point));
+            let error = ((dx - 2.5).powi(2) + (dy + 1.25).powi(2)).sqrt();
+            assert!(error < 0.25, "feature at {:?} moved ({}, {})", point, dx, dy);
+        }
+
+        let sparse = LucasKanadeSettings {
+            mode: LucasKanadeMode::Sparse,
+            ..Default::default()
+        };
+        let flow = calc_flow(&frames[0], &frames[1], &sparse);
+        let error = interior_error(&flow, (2.5, -1.25), 16);
+        assert!(error < 0.1, "sparse EPE {}", error);
+    }
+}
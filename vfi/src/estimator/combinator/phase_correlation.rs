@@ -0,0 +1,269 @@
+mod fft;
+
+use std::f32::consts::PI;
+
+use image::{ImageBuffer, Rgb};
+use num::complex::Complex32;
+use num::integer::div_ceil;
+
+use crate::error::{Result, VfiError};
+
+use self::fft::fft_2d;
+use super::float_image::FloatImage;
+
+// Keeps the normalized cross-power spectrum finite where both spectra vanish.
+const SPECTRUM_EPSILON: f32 = 1e-6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PhaseCorrelationMode {
+    /// One translation for the whole frame, e.g. a camera pan.
+    Global,
+    /// One translation per block, correlated over a `window_width` tile
+    /// centered on the block.
+    Block,
+}
+
+pub struct PhaseCorrelationSettings {
+    pub mode: PhaseCorrelationMode,
+    pub window_width: u32,
+    pub subpixel: bool,
+    pub smoothed: bool,
+    pub bidirectional: bool,
+}
+
+impl Default for PhaseCorrelationSettings {
+    fn default() -> PhaseCorrelationSettings {
+        PhaseCorrelationSettings {
+            mode: PhaseCorrelationMode::Block,
+            window_width: 32,
+            subpixel: true,
+            smoothed: false,
+            bidirectional: false,
+        }
+    }
+}
+
+impl PhaseCorrelationSettings {
+    pub fn validate_for_block_width(&self, block_width: u32) -> Result<()> {
+        if block_width == 0 {
+            return Err(VfiError::InvalidSettings(
+                "block_width must be at least 1".to_string(),
+            ));
+        }
+        if self.mode == PhaseCorrelationMode::Block {
+            if !self.window_width.is_power_of_two() || self.window_width < 4 {
+                return Err(VfiError::InvalidSettings(format!(
+                    "window_width must be a power of two of at least 4, got {}",
+                    self.window_width
+                )));
+            }
+            if self.window_width < block_width {
+                return Err(VfiError::InvalidSettings(format!(
+                    "window_width {} is smaller than block_width {}",
+                    self.window_width, block_width
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Hann weight of sample `n` out of `len`, never exactly zero.
+fn hann(n: u32, len: u32) -> f32 {
+    0.5 - 0.5 * (2.0 * PI * (n as f32 + 0.5) / len as f32).cos()
+}
+
+// Reads the `region` starting at `(left, top)`, removes its mean, applies a
+// Hann window and zero-pads it to `size`.
+fn windowed_tile(
+    image: &FloatImage,
+    (left, top): (i64, i64),
+    (region_width, region_height): (u32, u32),
+    (width, height): (usize, usize),
+) -> Vec<Complex32> {
+    let mut values = Vec::with_capacity((region_width * region_height) as usize);
+    for y in 0..region_height {
+        for x in 0..region_width {
+            values.push(image.get_clamped(left + x as i64, top + y as i64));
+        }
+    }
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+
+    let mut tile = vec![Complex32::new(0.0, 0.0); width * height];
+    for y in 0..region_height {
+        for x in 0..region_width {
+            let value = values[(y * region_width + x) as usize] - mean;
+            tile[y as usize * width + x as usize] =
+                Complex32::new(value * hann(x, region_width) * hann(y, region_height), 0.0);
+        }
+    }
+    tile
+}
+
+// Offset of the true peak from the sample at `center`, from a parabola
+// through it and its two neighbours.
+fn parabolic_offset(before: f32, center: f32, after: f32) -> f32 {
+    let curvature = before - 2.0 * center + after;
+    if curvature >= 0.0 {
+        return 0.0;
+    }
+    (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+}
+
+// Translation from `tile_1` to `tile_2` and the height of the correlation
+// peak, which is 1 for a perfect translation and falls towards 0 without one.
+fn correlate(
+    mut tile_1: Vec<Complex32>,
+    mut tile_2: Vec<Complex32>,
+    (width, height): (usize, usize),
+    subpixel: bool,
+) -> (f32, f32, f32) {
+    fft_2d(&mut tile_1, width, height, false);
+    fft_2d(&mut tile_2, width, height, false);
+    let mut surface: Vec<Complex32> = tile_1
+        .iter()
+        .zip(tile_2.iter())
+        .map(|(spectrum_1, spectrum_2)| {
+            let cross = spectrum_2 * spectrum_1.conj();
+            cross / (cross.norm() + SPECTRUM_EPSILON)
+        })
+        .collect();
+    fft_2d(&mut surface, width, height, true);
+
+    let (peak_index, peak) = surface
+        .iter()
+        .map(|value| value.re)
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap();
+    let (peak_x, peak_y) = (peak_index % width, peak_index / width);
+    let at = |x: usize, y: usize| surface[(y % height) * width + x % width].re;
+
+    let (mut dx, mut dy) = (peak_x as f32, peak_y as f32);
+    if subpixel {
+        dx += parabolic_offset(at(peak_x + width - 1, peak_y), peak, at(peak_x + 1, peak_y));
+        dy += parabolic_offset(at(peak_x, peak_y + height - 1), peak, at(peak_x, peak_y + 1));
+    }
+    // The surface wraps around, so peaks past the middle are negative shifts.
+    if dx > (width / 2) as f32 {
+        dx -= width as f32;
+    }
+    if dy > (height / 2) as f32 {
+        dy -= height as f32;
+    }
+    (dx, dy, peak)
+}
+
+/// Block flow from `frame_1` to `frame_2` on the same grid as block matching
+/// with `block_width` blocks, as `(dx, dy, error)` with `error = 1 - peak`.
+pub fn calc_flow(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    block_width: u32,
+    settings: &PhaseCorrelationSettings,
+) -> Vec<Vec<(f32, f32, f32)>> {
+    let image_1 = FloatImage::from_rgb(frame_1);
+    let image_2 = FloatImage::from_rgb(frame_2);
+    let (width, height) = image_1.dimensions();
+    let grid_width = div_ceil(width, block_width) as usize;
+    let grid_height = div_ceil(height, block_width) as usize;
+    let to_block_vector = |(dx, dy, peak): (f32, f32, f32)| (dx, dy, 1.0 - peak);
+
+    match settings.mode {
+        PhaseCorrelationMode::Global => {
+            let size = (width.next_power_of_two() as usize, height.next_power_of_two() as usize);
+            let vector = correlate(
+                windowed_tile(&image_1, (0, 0), (width, height), size),
+                windowed_tile(&image_2, (0, 0), (width, height), size),
+                size,
+                settings.subpixel,
+            );
+            vec![vec![to_block_vector(vector); grid_width]; grid_height]
+        }
+        PhaseCorrelationMode::Block => {
+            let window = settings.window_width;
+            let size = (window as usize, window as usize);
+            (0..grid_height)
+                .map(|i| {
+                    (0..grid_width)
+                        .map(|j| {
+                            let left = (j as u32 * block_width + block_width / 2) as i64 - (window / 2) as i64;
+                            let top = (i as u32 * block_width + block_width / 2) as i64 - (window / 2) as i64;
+                            to_block_vector(correlate(
+                                windowed_tile(&image_1, (left, top), (window, window), size),
+                                windowed_tile(&image_2, (left, top), (window, window), size),
+                                size,
+                                settings.subpixel,
+                            ))
+                        })
+                        .collect()
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::translation_frames;
+
+    // The synthetic texture is a handful of sinusoids, so whitening its sparse
+    // spectrum leaves fractional shifts at the mercy of quantization noise;
+    // integer shifts give a clean peak.
+    const SHIFTS: [(f32, f32); 3] = [(3.0, 2.0), (-4.0, 1.0), (0.0, -5.0)];
+
+    fn flow(shift: (f32, f32), mode: PhaseCorrelationMode) -> Vec<Vec<(f32, f32, f32)>> {
+        let frames = translation_frames(shift.0, shift.1, 2);
+        let settings = PhaseCorrelationSettings {
+            mode,
+            ..Default::default()
+        };
+        calc_flow(&frames[0], &frames[1], 8, &settings)
+    }
+
+    #[test]
+    fn blocks_recover_integer_translations() {
+        for shift in SHIFTS {
+            let flow = flow(shift, PhaseCorrelationMode::Block);
+            // Blocks whose window stays inside the frame.
+            for row in &flow[2..flow.len() - 2] {
+                for &(dx, dy, error) in &row[2..row.len() - 2] {
+                    assert!(
+                        (dx - shift.0).abs() < 0.05 && (dy - shift.1).abs() < 0.05,
+                        "({}, {}) instead of {:?}",
+                        dx,
+                        dy,
+                        shift
+                    );
+                    assert!(error < 0.8, "error {}", error);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn global_mode_recovers_integer_translations() {
+        for shift in SHIFTS {
+            let flow = flow(shift, PhaseCorrelationMode::Global);
+            let (dx, dy, _) = flow[0][0];
+            assert!(
+                (dx - shift.0).abs() < 0.05 && (dy - shift.1).abs() < 0.05,
+                "({}, {}) instead of {:?}",
+                dx,
+                dy,
+                shift
+            );
+            assert!(flow.iter().flatten().all(|&vector| vector == flow[0][0]));
+        }
+    }
+
+    #[test]
+    fn parabolic_offset_stays_between_the_neighbours() {
+        assert_eq!(parabolic_offset(0.5, 1.0, 0.5), 0.0);
+        assert_eq!(parabolic_offset(0.0, 1.0, 1.0), 0.5);
+        assert_eq!(parabolic_offset(1.0, 0.9, -1.0), -0.5);
+        // Not a peak, so there is nothing to refine.
+        assert_eq!(parabolic_offset(0.0, 0.2, 1.0), 0.0);
+    }
+}
@@ -0,0 +1,124 @@
+use std::f32::consts::PI;
+
+use num::complex::Complex32;
+
+// In-place iterative radix-2 FFT; `data.len()` must be a power of two. The
+// inverse transform is scaled by `1 / len`.
+pub fn fft(data: &mut [Complex32], inverse: bool) {
+    let len = data.len();
+    debug_assert!(len.is_power_of_two());
+
+    let mut j = 0;
+    for i in 1..len {
+        let mut bit = len >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut size = 2;
+    while size <= len {
+        let twiddles: Vec<Complex32> = (0..size / 2)
+            .map(|k| Complex32::from_polar(1.0, sign * 2.0 * PI * k as f32 / size as f32))
+            .collect();
+        for start in (0..len).step_by(size) {
+            for (k, twiddle) in twiddles.iter().enumerate() {
+                let even = data[start + k];
+                let odd = data[start + k + size / 2] * twiddle;
+                data[start + k] = even + odd;
+                data[start + k + size / 2] = even - odd;
+            }
+        }
+        size <<= 1;
+    }
+
+    if inverse {
+        for value in data.iter_mut() {
+            *value /= len as f32;
+        }
+    }
+}
+
+// Transforms every row and then every column of a row-major `width`×`height`
+// array.
+pub fn fft_2d(data: &mut [Complex32], width: usize, height: usize, inverse: bool) {
+    for row in data.chunks_mut(width) {
+        fft(row, inverse);
+    }
+    let mut column = vec![Complex32::new(0.0, 0.0); height];
+    for x in 0..width {
+        for y in 0..height {
+            column[y] = data[y * width + x];
+        }
+        fft(&mut column, inverse);
+        for y in 0..height {
+            data[y * width + x] = column[y];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(len: usize) -> Vec<Complex32> {
+        (0..len)
+            .map(|n| Complex32::new(
+                    (n as f32 * 0.7).sin() + n as f32 / len as f32,
+                    (n as f32 * 1.3).cos(),
+                ))
+            .collect()
+    }
+
+    fn assert_close(actual: &[Complex32], expected: &[Complex32]) {
+        for (index, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert!((a - e).norm() < 1e-4, "sample {}: {} instead of {}", index, a, e);
+        }
+    }
+
+    #[test]
+    fn matches_the_direct_transform() {
+        let input = signal(16);
+        let mut spectrum = input.clone();
+        fft(&mut spectrum, false);
+        let direct: Vec<Complex32> = (0..16)
+            .map(|k| {
+                input.iter().enumerate().fold(Complex32::new(0.0, 0.0), |sum, (n, value)| {
+                    sum + value * Complex32::from_polar(1.0, -2.0 * PI * (k * n) as f32 / 16.0)
+                })
+            })
+            .collect();
+        assert_close(&spectrum, &direct);
+    }
+
+    #[test]
+    fn inverse_undoes_the_forward_transform() {
+        for len in [1, 2, 8, 64] {
+            let input = signal(len);
+            let mut data = input.clone();
+            fft(&mut data, false);
+            fft(&mut data, true);
+            assert_close(&data, &input);
+        }
+
+        let input = signal(32 * 8);
+        let mut data = input.clone();
+        fft_2d(&mut data, 32, 8, false);
+        fft_2d(&mut data, 32, 8, true);
+        assert_close(&data, &input);
+    }
+
+    #[test]
+    fn constant_image_has_only_a_dc_term() {
+        let mut constant = vec![Complex32::new(2.0, 0.0); 4 * 4];
+        fft_2d(&mut constant, 4, 4, false);
+        assert_eq!(constant[0], Complex32::new(32.0, 0.0));
+        assert!(constant[1..].iter().all(|value| value.norm() < 1e-6));
+    }
+}
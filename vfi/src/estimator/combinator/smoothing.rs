@@ -1,49 +1,78 @@
+use crate::error::{Result, VfiError};
+
+const ERROR_EPSILON: f64 = 1e-3;
+
 pub struct SmoothingSettings {
     pub filter_window_width: u32,
 }
 
-impl SmoothingSettings {
-    pub fn default() -> SmoothingSettings {
+impl Default for SmoothingSettings {
+    fn default() -> SmoothingSettings {
         SmoothingSettings {
             filter_window_width: 3,
         }
     }
 }
 
+impl SmoothingSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.filter_window_width.is_multiple_of(2) {
+            return Err(VfiError::InvalidSettings(format!(
+                "filter_window_width must be odd, got {}",
+                self.filter_window_width
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn validate_for_grid(&self, grid_width: u32, grid_height: u32) -> Result<()> {
+        self.validate()?;
+        if self.filter_window_width > grid_width || self.filter_window_width > grid_height {
+            return Err(VfiError::InvalidSettings(format!(
+                "filter_window_width {} does not fit the {}x{} block grid",
+                self.filter_window_width, grid_width, grid_height
+            )));
+        }
+        Ok(())
+    }
+}
+
 fn get_window_weights(
-    error_flow: &Vec<Vec<(i16, i16, u32)>>,
+    error_flow: &[Vec<(f32, f32, f32)>],
     filter_w: u32,
     i: u32,
     j: u32,
 ) -> Vec<Vec<f64>> {
     let mut weights = vec![vec![0f64; filter_w as usize]; filter_w as usize];
-    let center_error = error_flow[i as usize][j as usize].2;
+    // Costs such as NCC and census reach exactly zero, keep the ratios finite.
+    let center_error = error_flow[i as usize][j as usize].2 as f64 + ERROR_EPSILON;
     for y in 0..filter_w {
         for x in 0..filter_w {
-            weights[y as usize][x as usize] = center_error as f64
-                / error_flow[(i + y - filter_w / 2) as usize][(j + x - filter_w / 2) as usize].2
-                    as f64;
+            weights[y as usize][x as usize] = center_error
+                / (error_flow[(i + y - filter_w / 2) as usize][(j + x - filter_w / 2) as usize].2
+                    as f64
+                    + ERROR_EPSILON);
         }
     }
     weights
 }
 
-fn vector_diff(v1: (i16, i16), v2: (i16, i16)) -> f64 {
-    ((v1.0.abs_diff(v2.0).pow(2) + v1.1.abs_diff(v2.1).pow(2)) as f64).sqrt()
+fn vector_diff(v1: (f32, f32), v2: (f32, f32)) -> f64 {
+    (((v1.0 - v2.0) as f64).powi(2) + ((v1.1 - v2.1) as f64).powi(2)).sqrt()
 }
 
 fn get_median_weighted_vector(
-    error_flow: &Vec<Vec<(i16, i16, u32)>>,
+    error_flow: &[Vec<(f32, f32, f32)>],
     filter_w: u32,
-    weights: &Vec<Vec<f64>>,
+    weights: &[Vec<f64>],
     i: u32,
     j: u32,
-) -> (i16, i16) {
+) -> (f32, f32) {
     assert_eq!(weights.len() as u32, filter_w);
     assert_eq!(weights[0].len() as u32, filter_w);
 
     let mut min_deviation = f64::INFINITY;
-    let mut mwv = (0i16, 0i16);
+    let mut mwv = (0f32, 0f32);
     for vi in 0..filter_w {
         for vj in 0..filter_w {
             let cur_mwv = (
@@ -76,12 +105,12 @@ fn get_median_weighted_vector(
 }
 
 pub fn smooth_error_flow(
-    error_flow: &Vec<Vec<(i16, i16, u32)>>,
+    error_flow: &[Vec<(f32, f32, f32)>],
     filter_w: u32,
-) -> Vec<Vec<(i16, i16)>> {
-    assert!(filter_w % 2 != 0);
+) -> Vec<Vec<(f32, f32)>> {
+    assert!(!filter_w.is_multiple_of(2));
     let half_fw = filter_w / 2;
-    let mut flow = vec![vec![(0i16, 0i16); error_flow[0].len()]; error_flow.len()];
+    let mut flow = vec![vec![(0f32, 0f32); error_flow[0].len()]; error_flow.len()];
     for i in half_fw..error_flow.len() as u32 - half_fw {
         for j in half_fw..error_flow[0].len() as u32 - half_fw {
             let weights = get_window_weights(error_flow, filter_w, i, j);
@@ -0,0 +1,260 @@
+use image::{ImageBuffer, Rgb};
+
+use crate::error::{Result, VfiError};
+use crate::flow::FlowField;
+
+use super::float_image::{resize_flow, to_flow_field, FloatImage};
+
+const MAX_PYRAMID_LEVELS: u8 = 8;
+// Squared gradients below this leave the data term without a direction.
+const GRADIENT_IS_ZERO: f32 = 1e-10;
+
+pub struct TvL1Settings {
+    pub lambda: f32,
+    pub theta: f32,
+    pub tau: f32,
+    pub warps: u32,
+    pub pyramid_levels: u8,
+    pub iterations: u32,
+}
+
+impl Default for TvL1Settings {
+    fn default() -> TvL1Settings {
+        TvL1Settings {
+            lambda: 0.15,
+            theta: 0.3,
+            tau: 0.25,
+            warps: 5,
+            pyramid_levels: 5,
+            iterations: 50,
+        }
+    }
+}
+
+impl TvL1Settings {
+    pub fn validate(&self) -> Result<()> {
+        for (name, value) in [("lambda", self.lambda), ("theta", self.theta)] {
+            if value.is_nan() || value <= 0.0 {
+                return Err(VfiError::InvalidSettings(format!(
+                    "{} must be positive, got {}",
+                    name, value
+                )));
+            }
+        }
+        // The dual step is only stable for tau up to 1/4.
+        if !(self.tau > 0.0 && self.tau <= 0.25) {
+            return Err(VfiError::InvalidSettings(format!(
+                "tau must be in (0, 0.25], got {}",
+                self.tau
+            )));
+        }
+        if self.warps == 0 {
+            return Err(VfiError::InvalidSettings(
+                "warps must be positive".to_string(),
+            ));
+        }
+        if self.pyramid_levels == 0 || self.pyramid_levels > MAX_PYRAMID_LEVELS {
+            return Err(VfiError::InvalidSettings(format!(
+                "pyramid_levels must be between 1 and {}, got {}",
+                MAX_PYRAMID_LEVELS, self.pyramid_levels
+            )));
+        }
+        if self.iterations == 0 {
+            return Err(VfiError::InvalidSettings(
+                "iterations must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Forward differences, zero on the last column and row.
+fn forward_gradient(values: &[f32], width: usize, height: usize) -> (Vec<f32>, Vec<f32>) {
+    let mut gradient_x = vec![0f32; values.len()];
+    let mut gradient_y = vec![0f32; values.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let index = y * width + x;
+            if x + 1 < width {
+                gradient_x[index] = values[index + 1] - values[index];
+            }
+            if y + 1 < height {
+                gradient_y[index] = values[index + width] - values[index];
+            }
+        }
+    }
+    (gradient_x, gradient_y)
+}
+
+// Negative adjoint of `forward_gradient`.
+fn divergence(field_x: &[f32], field_y: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let mut result = vec![0f32; field_x.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let index = y * width + x;
+            let mut value = 0.0;
+            if x + 1 < width {
+                value += field_x[index];
+            }
+            if x > 0 {
+                value -= field_x[index - 1];
+            }
+            if y + 1 < height {
+                value += field_y[index];
+            }
+            if y > 0 {
+                value -= field_y[index - width];
+            }
+            result[index] = value;
+        }
+    }
+    result
+}
+
+// Dual variables of the total variation of one flow component.
+struct Dual {
+    x: Vec<f32>,
+    y: Vec<f32>,
+}
+
+impl Dual {
+    fn new(len: usize) -> Dual {
+        Dual {
+            x: vec![0.0; len],
+            y: vec![0.0; len],
+        }
+    }
+
+    // Projected gradient ascent step towards `flow`'s gradient.
+    fn update(&mut self, flow: &[f32], width: usize, height: usize, step: f32) {
+        let (gradient_x, gradient_y) = forward_gradient(flow, width, height);
+        for index in 0..flow.len() {
+            let norm = 1.0
+                + step
+                    * (gradient_x[index] * gradient_x[index] + gradient_y[index] * gradient_y[index])
+                        .sqrt();
+            self.x[index] = (self.x[index] + step * gradient_x[index]) / norm;
+            self.y[index] = (self.y[index] + step * gradient_y[index]) / norm;
+        }
+    }
+}
+
+// One warp on one level: linearizes `frame_2` around the current flow and
+// alternates the pointwise thresholding of the L1 data term with the
+// total-variation denoising of the flow.
+fn refine_warp(
+    image_1: &FloatImage,
+    image_2: &FloatImage,
+    flow: (FloatImage, FloatImage),
+    duals: &mut (Dual, Dual),
+    settings: &TvL1Settings,
+) -> (FloatImage, FloatImage) {
+    let (width, height) = image_1.dimensions();
+    let (w, h) = (width as usize, height as usize);
+    let (gradient_x_2, gradient_y_2) = image_2.gradients();
+    let (flow_x, flow_y) = flow;
+
+    let mut linearization = Vec::with_capacity(w * h);
+    for y in 0..height {
+        for x in 0..width {
+            let (u0, v0) = (flow_x.get(x, y), flow_y.get(x, y));
+            let (sx, sy) = (x as f32 + u0, y as f32 + v0);
+            let (ix, iy) = (gradient_x_2.sample(sx, sy), gradient_y_2.sample(sx, sy));
+            // Residual of the linearized constraint without the unknown flow.
+            let constant = image_2.sample(sx, sy) - ix * u0 - iy * v0 - image_1.get(x, y);
+            linearization.push((ix, iy, ix * ix + iy * iy, constant));
+        }
+    }
+
+    let threshold = settings.lambda * settings.theta;
+    let step = settings.tau / settings.theta;
+    let mut u = flow_x.data().to_vec();
+    let mut v = flow_y.data().to_vec();
+    let (mut data_u, mut data_v) = (u.clone(), v.clone());
+    for _ in 0..settings.iterations {
+        for (index, &(ix, iy, gradient_squared, constant)) in linearization.iter().enumerate() {
+            let residual = constant + ix * u[index] + iy * v[index];
+            let (du, dv) = if residual < -threshold * gradient_squared {
+                (threshold * ix, threshold * iy)
+            } else if residual > threshold * gradient_squared {
+                (-threshold * ix, -threshold * iy)
+            } else if gradient_squared > GRADIENT_IS_ZERO {
+                (-residual * ix / gradient_squared, -residual * iy / gradient_squared)
+            } else {
+                (0.0, 0.0)
+            };
+            data_u[index] = u[index] + du;
+            data_v[index] = v[index] + dv;
+        }
+
+        let divergence_u = divergence(&duals.0.x, &duals.0.y, w, h);
+        let divergence_v = divergence(&duals.1.x, &duals.1.y, w, h);
+        for index in 0..u.len() {
+            u[index] = data_u[index] + settings.theta * divergence_u[index];
+            v[index] = data_v[index] + settings.theta * divergence_v[index];
+        }
+        duals.0.update(&u, w, h, step);
+        duals.1.update(&v, w, h, step);
+    }
+    (
+        FloatImage::from_data(width, height, u),
+        FloatImage::from_data(width, height, v),
+    )
+}
+
+/// TV-L1 flow from `frame_1` to `frame_2` at full resolution, refined coarse
+/// to fine with several warps per level.
+pub fn calc_flow(
+    frame_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    frame_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    settings: &TvL1Settings,
+) -> FlowField {
+    let pyramid_1 = FloatImage::from_rgb(frame_1).pyramid(settings.pyramid_levels);
+    let pyramid_2 = FloatImage::from_rgb(frame_2).pyramid(settings.pyramid_levels);
+
+    let (coarse_width, coarse_height) = pyramid_1.last().unwrap().dimensions();
+    let mut flow = (
+        FloatImage::new(coarse_width, coarse_height),
+        FloatImage::new(coarse_width, coarse_height),
+    );
+    for (level_1, level_2) in pyramid_1.iter().zip(pyramid_2.iter()).rev() {
+        let (width, height) = level_1.dimensions();
+        if flow.0.dimensions() != (width, height) {
+            flow = resize_flow(&flow.0, &flow.1, width, height);
+        }
+        let len = (width * height) as usize;
+        let mut duals = (Dual::new(len), Dual::new(len));
+        for _ in 0..settings.warps {
+            flow = refine_warp(level_1, level_2, flow, &mut duals, settings);
+        }
+    }
+    to_flow_field(&flow.0, &flow.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{interior_error, translation_frames};
+
+    #[test]
+    fn recovers_translations() {
+        for (dx, dy) in [(2.5, -1.25), (3.0, 2.0), (-0.5, 0.75)] {
+            let frames = translation_frames(dx, dy, 2);
+            let flow = calc_flow(&frames[0], &frames[1], &TvL1Settings::default());
+            let error = interior_error(&flow, (dx, dy), 16);
+            assert!(error < 0.1, "EPE {} for ({}, {})", error, dx, dy);
+        }
+    }
+
+    #[test]
+    fn dropped_out_pixels_barely_move_the_flow() {
+        let mut frames = translation_frames(2.5, -1.25, 2);
+        // Every 37th pixel of the second frame turns white.
+        for index in (0..128 * 96).step_by(37) {
+            frames[1].put_pixel(index % 128, index / 128, Rgb([255; 3]));
+        }
+        let flow = calc_flow(&frames[0], &frames[1], &TvL1Settings::default());
+        let error = interior_error(&flow, (2.5, -1.25), 16);
+        assert!(error < 0.15, "EPE {}", error);
+    }
+}
@@ -0,0 +1,288 @@
+mod quality;
+
+use std::fmt;
+use std::ops::AddAssign;
+use std::path::Path;
+
+use crate::error::{check_dimensions, Result, VfiError};
+use crate::flow::FlowField;
+use crate::operations::scale_up;
+
+pub use self::quality::{ms_ssim, psnr, ssim};
+
+// KITTI counts a pixel as an outlier when its endpoint error exceeds both
+// 3 px and 5% of the true motion.
+const OUTLIER_THRESHOLD: f32 = 3.0;
+const OUTLIER_RELATIVE_THRESHOLD: f32 = 0.05;
+
+/// Pixels taken into account, on top of those the ground truth marks invalid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvaluationRegion {
+    All,
+    NonOccluded,
+    Occluded,
+}
+
+/// Error sums over the evaluated pixels. Adding the errors of several frames
+/// weighs every pixel equally, like the KITTI benchmark.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FlowErrors {
+    pub pixels: u64,
+    pub endpoint_error: f64,
+    pub angular_error: f64,
+    pub outliers: u64,
+    pub over_1px: u64,
+    pub over_3px: u64,
+    pub over_5px: u64,
+}
+
+impl FlowErrors {
+    fn add_pixel(&mut self, estimate: (f32, f32), truth: (f32, f32)) {
+        let endpoint_error = (estimate.0 - truth.0).hypot(estimate.1 - truth.1);
+        // Angle between the space-time vectors (dx, dy, 1), as in Barron et al.
+        let dot = estimate.0 * truth.0 + estimate.1 * truth.1 + 1.0;
+        let norms = ((estimate.0 * estimate.0 + estimate.1 * estimate.1 + 1.0)
+            * (truth.0 * truth.0 + truth.1 * truth.1 + 1.0))
+            .sqrt();
+        let angular_error = (dot / norms).clamp(-1.0, 1.0).acos().to_degrees();
+
+        self.pixels += 1;
+        self.endpoint_error += endpoint_error as f64;
+        self.angular_error += angular_error as f64;
+        let magnitude = truth.0.hypot(truth.1);
+        if endpoint_error > OUTLIER_THRESHOLD && endpoint_error > OUTLIER_RELATIVE_THRESHOLD * magnitude {
+            self.outliers += 1;
+        }
+        self.over_1px += (endpoint_error > 1.0) as u64;
+        self.over_3px += (endpoint_error > 3.0) as u64;
+        self.over_5px += (endpoint_error > 5.0) as u64;
+    }
+
+    fn percentage(&self, count: u64) -> f64 {
+        if self.pixels == 0 {
+            return 0.0;
+        }
+        100.0 * count as f64 / self.pixels as f64
+    }
+
+    /// Average endpoint error in pixels.
+    pub fn average_endpoint_error(&self) -> f64 {
+        if self.pixels == 0 {
+            return 0.0;
+        }
+        self.endpoint_error / self.pixels as f64
+    }
+
+    /// Average angular error in degrees.
+    pub fn average_angular_error(&self) -> f64 {
+        if self.pixels == 0 {
+            return 0.0;
+        }
+        self.angular_error / self.pixels as f64
+    }
+
+    /// Percentage of KITTI outliers.
+    pub fn fl_all(&self) -> f64 {
+        self.percentage(self.outliers)
+    }
+
+    /// Percentages of pixels with an endpoint error above 1, 3 and 5 px.
+    pub fn r1(&self) -> f64 {
+        self.percentage(self.over_1px)
+    }
+
+    pub fn r3(&self) -> f64 {
+        self.percentage(self.over_3px)
+    }
+
+    pub fn r5(&self) -> f64 {
+        self.percentage(self.over_5px)
+    }
+}
+
+impl AddAssign for FlowErrors {
+    fn add_assign(&mut self, other: FlowErrors) {
+        self.pixels += other.pixels;
+        self.endpoint_error += other.endpoint_error;
+        self.angular_error += other.angular_error;
+        self.outliers += other.outliers;
+        self.over_1px += other.over_1px;
+        self.over_3px += other.over_3px;
+        self.over_5px += other.over_5px;
+    }
+}
+
+impl fmt::Display for FlowErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "EPE {:.3}px, AE {:.2}°, Fl-all {:.2}%, R1 {:.2}%, R3 {:.2}%, R5 {:.2}% over {} pixels",
+            self.average_endpoint_error(),
+            self.average_angular_error(),
+            self.fl_all(),
+            self.r1(),
+            self.r3(),
+            self.r5(),
+            self.pixels
+        )
+    }
+}
+
+/// Compares `estimate` with a per-pixel `ground_truth` over the pixels of
+/// `region` that the ground truth marks valid. A block flow is expanded to
+/// the ground truth size first. `occlusion` holds one flag per pixel, set
+/// where the content of the first frame is hidden in the second.
+pub fn evaluate(
+    estimate: &FlowField,
+    ground_truth: &FlowField,
+    occlusion: Option<&[bool]>,
+    region: EvaluationRegion,
+) -> Result<FlowErrors> {
+    if ground_truth.spacing() != 1 {
+        return Err(VfiError::InvalidSettings(
+            "ground truth must hold one vector per pixel".to_string(),
+        ));
+    }
+    let (width, height) = ground_truth.dimensions();
+    // A block estimate needs one vector per block, the last one partial.
+    let spacing = estimate.spacing();
+    check_dimensions(
+        (width.div_ceil(spacing), height.div_ceil(spacing)),
+        estimate.dimensions(),
+    )?;
+    if let Some(occlusion) = occlusion {
+        if occlusion.len() != (width * height) as usize {
+            return Err(VfiError::InvalidSettings(format!(
+                "{} occlusion values do not match {} flow vectors",
+                occlusion.len(),
+                width * height
+            )));
+        }
+    } else if region != EvaluationRegion::All {
+        return Err(VfiError::MissingInput(format!(
+            "evaluating {:?} pixels needs an occlusion mask",
+            region
+        )));
+    }
+
+    let estimate = scale_up(estimate, width, height);
+    let mut errors = FlowErrors::default();
+    for (x, y, truth) in ground_truth.enumerate() {
+        let occluded = occlusion.is_some_and(|occlusion| occlusion[(y * width + x) as usize]);
+        let in_region = match region {
+            EvaluationRegion::All => true,
+            EvaluationRegion::NonOccluded => !occluded,
+            EvaluationRegion::Occluded => occluded,
+        };
+        if in_region && ground_truth.is_valid(x, y) {
+            errors.add_pixel(estimate[(x, y)], truth);
+        }
+    }
+    Ok(errors)
+}
+
+/// Reads ground truth flow, picking the format from the extension: `.flo`,
+/// `.pfm`, or `.png` for KITTI.
+pub fn read_ground_truth(filename: &str) -> Result<FlowField> {
+    match Path::new(filename).extension().and_then(|extension| extension.to_str()) {
+        Some("flo") => FlowField::read_flo(filename),
+        Some("pfm") => FlowField::read_pfm(filename),
+        Some("png") => FlowField::read_kitti_png(filename),
+        _ => Err(VfiError::InvalidSettings(format!(
+            "unknown flow format of {}",
+            filename
+        ))),
+    }
+}
+
+/// Reads an occlusion mask image, where bright pixels are occluded.
+pub fn read_occlusion_mask(filename: &str) -> Result<Vec<bool>> {
+    let mask = image::open(filename)?.into_luma8();
+    Ok(mask.pixels().map(|pixel| pixel.0[0] > 127).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(vectors: Vec<(f32, f32)>) -> FlowField {
+        FlowField::from_vectors(vectors.len() as u32, 1, 1, vectors).unwrap()
+    }
+
+    fn errors(estimate: Vec<(f32, f32)>, truth: Vec<(f32, f32)>) -> FlowErrors {
+        evaluate(&field(estimate), &field(truth), None, EvaluationRegion::All).unwrap()
+    }
+
+    #[test]
+    fn small_error_on_large_motion_is_not_an_outlier() {
+        let errors = errors(vec![(103.5, 0.0)], vec![(100.0, 0.0)]);
+        assert_eq!(errors.endpoint_error, 3.5);
+        assert_eq!(errors.outliers, 0);
+        assert_eq!(errors.over_3px, 1);
+    }
+
+    #[test]
+    fn same_error_on_small_motion_is_an_outlier() {
+        let errors = errors(vec![(13.5, 0.0)], vec![(10.0, 0.0)]);
+        assert_eq!(errors.outliers, 1);
+        assert_eq!(errors.fl_all(), 100.0);
+    }
+
+    #[test]
+    fn identical_vectors_have_no_error() {
+        let vectors = vec![(0.0, 0.0), (3.0, 4.0), (-1.5, 0.25)];
+        let errors = errors(vectors.clone(), vectors);
+        assert_eq!(errors.average_endpoint_error(), 0.0);
+        assert_eq!(errors.average_angular_error(), 0.0);
+        assert_eq!(errors.r1(), 0.0);
+    }
+
+    #[test]
+    fn angular_error_uses_space_time_vectors() {
+        let errors = errors(vec![(1.0, 0.0)], vec![(0.0, 0.0)]);
+        assert!((errors.average_angular_error() - 45.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn regions_follow_the_occlusion_mask() {
+        let estimate = field(vec![(1.0, 0.0), (2.0, 0.0), (4.0, 0.0), (8.0, 0.0)]);
+        let truth = field(vec![(0.0, 0.0); 4]).with_validity(vec![true, true, true, false]).unwrap();
+        let occlusion = [false, true, false, true];
+        let evaluate_region = |region| evaluate(&estimate, &truth, Some(&occlusion), region).unwrap();
+
+        let all = evaluate_region(EvaluationRegion::All);
+        assert_eq!((all.pixels, all.endpoint_error), (3, 7.0));
+        let non_occluded = evaluate_region(EvaluationRegion::NonOccluded);
+        assert_eq!((non_occluded.pixels, non_occluded.endpoint_error), (2, 5.0));
+        let occluded = evaluate_region(EvaluationRegion::Occluded);
+        assert_eq!((occluded.pixels, occluded.endpoint_error), (1, 2.0));
+    }
+
+    #[test]
+    fn regions_need_an_occlusion_mask() {
+        let flow = field(vec![(0.0, 0.0)]);
+        let result = evaluate(&flow, &flow, None, EvaluationRegion::NonOccluded);
+        assert!(matches!(result, Err(VfiError::MissingInput(_))));
+    }
+
+    #[test]
+    fn block_estimate_is_expanded() {
+        let estimate = FlowField::from_vectors(1, 1, 2, vec![(1.0, 0.0)]).unwrap();
+        let truth = FlowField::from_vectors(2, 2, 1, vec![(1.0, 0.0); 4]).unwrap();
+        let errors = evaluate(&estimate, &truth, None, EvaluationRegion::All).unwrap();
+        assert_eq!((errors.pixels, errors.endpoint_error), (4, 0.0));
+    }
+
+    #[test]
+    fn block_estimate_must_cover_the_ground_truth() {
+        let truth = FlowField::from_vectors(5, 3, 1, vec![(0.0, 0.0); 15]).unwrap();
+        let covering = FlowField::from_vectors(3, 2, 2, vec![(0.0, 0.0); 6]).unwrap();
+        assert!(evaluate(&covering, &truth, None, EvaluationRegion::All).is_ok());
+        for (width, height) in [(2, 2), (3, 1), (4, 2)] {
+            let estimate =
+                FlowField::from_vectors(width, height, 2, vec![(0.0, 0.0); (width * height) as usize]).unwrap();
+            let result = evaluate(&estimate, &truth, None, EvaluationRegion::All);
+            assert!(matches!(result, Err(VfiError::DimensionMismatch { .. })), "{}x{}", width, height);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use image::{ImageBuffer, Rgb};
+
+use crate::error::{check_dimensions, Result, VfiError};
+
+// Window and constants of Wang et al.
+const WINDOW_WIDTH: usize = 11;
+const WINDOW_SIGMA: f64 = 1.5;
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+// Identical images would score infinitely high and swamp any average. 100 dB
+// is an RMS error of about 0.003 levels, far below any visible difference.
+const MAX_PSNR: f64 = 100.0;
+
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f64>,
+}
+
+impl Plane {
+    fn luma(image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Plane {
+        Plane {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            data: image
+                .pixels()
+                .map(|pixel| {
+                    let [r, g, b] = pixel.0;
+                    0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64
+                })
+                .collect(),
+        }
+    }
+
+    fn zip(&self, other: &Plane, f: impl Fn(f64, f64) -> f64) -> Plane {
+        Plane {
+            width: self.width,
+            height: self.height,
+            data: self.data.iter().zip(other.data.iter()).map(|(&a, &b)| f(a, b)).collect(),
+        }
+    }
+
+    // Gaussian filter keeping only positions where the window fits.
+    fn filter(&self, kernel: &[f64]) -> Plane {
+        let width = self.width + 1 - kernel.len();
+        let height = self.height + 1 - kernel.len();
+        let mut rows = Vec::with_capacity(width * self.height);
+        for y in 0..self.height {
+            let row = &self.data[y * self.width..(y + 1) * self.width];
+            for x in 0..width {
+                rows.push(kernel.iter().zip(&row[x..]).map(|(k, v)| k * v).sum::<f64>());
+            }
+        }
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(
+                    kernel
+                        .iter()
+                        .enumerate()
+                        .map(|(i, k)| k * rows[(y + i) * width + x])
+                        .sum::<f64>(),
+                );
+            }
+        }
+        Plane { width, height, data }
+    }
+
+    // Halves both dimensions by averaging 2x2 squares.
+    fn downsample(&self) -> Plane {
+        let (width, height) = (self.width / 2, self.height / 2);
+        let at = |x: usize, y: usize| self.data[y * self.width + x];
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(
+                    (at(2 * x, 2 * y) + at(2 * x + 1, 2 * y) + at(2 * x, 2 * y + 1) + at(2 * x + 1, 2 * y + 1))
+                        / 4.0,
+                );
+            }
+        }
+        Plane { width, height, data }
+    }
+}
+
+fn gaussian_window() -> Vec<f64> {
+    let center = (WINDOW_WIDTH / 2) as f64;
+    let window: Vec<f64> = (0..WINDOW_WIDTH)
+        .map(|i| (-(i as f64 - center).powi(2) / (2.0 * WINDOW_SIGMA * WINDOW_SIGMA)).exp())
+        .collect();
+    let sum: f64 = window.iter().sum();
+    window.iter().map(|w| w / sum).collect()
+}
+
+// Mean SSIM and mean contrast-structure term of two planes.
+fn ssim_terms(plane_1: &Plane, plane_2: &Plane, window: &[f64]) -> (f64, f64) {
+    let mean_1 = plane_1.filter(window);
+    let mean_2 = plane_2.filter(window);
+    let square_1 = plane_1.zip(plane_1, |a, b| a * b).filter(window);
+    let square_2 = plane_2.zip(plane_2, |a, b| a * b).filter(window);
+    let product = plane_1.zip(plane_2, |a, b| a * b).filter(window);
+
+    let (mut ssim, mut contrast_structure) = (0.0, 0.0);
+    for index in 0..mean_1.data.len() {
+        let (mu_1, mu_2) = (mean_1.data[index], mean_2.data[index]);
+        let variance_1 = square_1.data[index] - mu_1 * mu_1;
+        let variance_2 = square_2.data[index] - mu_2 * mu_2;
+        let covariance = product.data[index] - mu_1 * mu_2;
+        let cs = (2.0 * covariance + C2) / (variance_1 + variance_2 + C2);
+        let luminance = (2.0 * mu_1 * mu_2 + C1) / (mu_1 * mu_1 + mu_2 * mu_2 + C1);
+        ssim += luminance * cs;
+        contrast_structure += cs;
+    }
+    let count = mean_1.data.len() as f64;
+    (ssim / count, contrast_structure / count)
+}
+
+fn check_size(
+    image_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    image_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    min_size: u32,
+    metric: &str,
+) -> Result<()> {
+    check_dimensions(image_1.dimensions(), image_2.dimensions())?;
+    let (width, height) = image_1.dimensions();
+    if width < min_size || height < min_size {
+        return Err(VfiError::InvalidSettings(format!(
+            "{} needs images of at least {}x{}, got {}x{}",
+            metric, min_size, min_size, width, height
+        )));
+    }
+    Ok(())
+}
+
+/// Peak signal-to-noise ratio over all RGB channels in dB, capped at 100 dB
+/// for identical images.
+pub fn psnr(
+    image_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    image_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+) -> Result<f64> {
+    check_size(image_1, image_2, 1, "PSNR")?;
+    let squared_error: f64 = image_1
+        .as_raw()
+        .iter()
+        .zip(image_2.as_raw().iter())
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum();
+    let mse = squared_error / image_1.as_raw().len() as f64;
+    Ok((10.0 * (255.0 * 255.0 / mse).log10()).min(MAX_PSNR))
+}
+
+/// Structural similarity of the luma channels with an 11x11 Gaussian window.
+pub fn ssim(
+    image_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    image_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+) -> Result<f64> {
+    check_size(image_1, image_2, WINDOW_WIDTH as u32, "SSIM")?;
+    let (ssim, _) = ssim_terms(&Plane::luma(image_1), &Plane::luma(image_2), &gaussian_window());
+    Ok(ssim)
+}
+
+/// Multi-scale structural similarity over five scales, each half the size of
+/// the previous one.
+pub fn ms_ssim(
+    image_1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    image_2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+) -> Result<f64> {
+    let min_size = (WINDOW_WIDTH << (MS_SSIM_WEIGHTS.len() - 1)) as u32;
+    check_size(image_1, image_2, min_size, "MS-SSIM")?;
+    let window = gaussian_window();
+    let mut plane_1 = Plane::luma(image_1);
+    let mut plane_2 = Plane::luma(image_2);
+    let mut result = 1.0;
+    for (scale, weight) in MS_SSIM_WEIGHTS.iter().enumerate() {
+        let (ssim, contrast_structure) = ssim_terms(&plane_1, &plane_2, &window);
+        // Luminance only enters at the coarsest scale. Negative terms are
+        // clamped so the fractional powers stay real.
+        let term = if scale + 1 == MS_SSIM_WEIGHTS.len() { ssim } else { contrast_structure };
+        result *= term.max(0.0).powf(*weight);
+        plane_1 = plane_1.downsample();
+        plane_2 = plane_2.downsample();
+    }
+    Ok(result)
+}
@@ -0,0 +1,422 @@
+pub(crate) mod flo;
+mod kitti;
+mod pfm;
+
+use std::ops::{Index, IndexMut};
+
+use crate::error::{check_dimensions, Result, VfiError};
+
+/// Which of the two frames shows the content under a vector, as found by a
+/// forward-backward consistency check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visibility {
+    Both,
+    OnlyFirst,
+    OnlySecond,
+}
+
+/// Dense grid of motion vectors stored row by row.
+///
+/// `spacing` is the distance in pixels between neighbouring vectors, so a
+/// block matching result with 8×8 blocks has `spacing == 8` and a per-pixel
+/// field has `spacing == 1`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlowField {
+    width: u32,
+    height: u32,
+    spacing: u32,
+    vectors: Vec<(f32, f32)>,
+    confidence: Option<Vec<f32>>,
+    visibility: Option<Vec<Visibility>>,
+    validity: Option<Vec<bool>>,
+}
+
+fn vector_count(width: u32, height: u32) -> Option<usize> {
+    (width as usize).checked_mul(height as usize)
+}
+
+impl FlowField {
+    /// Zero field. Panics on the sizes `from_vectors` rejects.
+    pub fn new(width: u32, height: u32, spacing: u32) -> FlowField {
+        assert!(spacing > 0, "flow spacing must be positive");
+        assert!(width > 0 && height > 0, "a {}x{} flow field holds no vectors", width, height);
+        let count = vector_count(width, height)
+            .unwrap_or_else(|| panic!("a {}x{} flow field is too large", width, height));
+        FlowField {
+            width,
+            height,
+            spacing,
+            vectors: vec![(0.0, 0.0); count],
+            confidence: None,
+            visibility: None,
+            validity: None,
+        }
+    }
+
+    pub fn from_vectors(
+        width: u32,
+        height: u32,
+        spacing: u32,
+        vectors: Vec<(f32, f32)>,
+    ) -> Result<FlowField> {
+        if spacing == 0 {
+            return Err(VfiError::InvalidSettings(
+                "flow spacing must be positive".to_string(),
+            ));
+        }
+        if width == 0 || height == 0 {
+            return Err(VfiError::InvalidSettings(format!(
+                "a {}x{} flow field holds no vectors",
+                width, height
+            )));
+        }
+        if vector_count(width, height) != Some(vectors.len()) {
+            return Err(VfiError::InvalidSettings(format!(
+                "{} vectors do not fill a {}x{} flow field",
+                vectors.len(),
+                width,
+                height
+            )));
+        }
+        Ok(FlowField {
+            width,
+            height,
+            spacing,
+            vectors,
+            confidence: None,
+            visibility: None,
+            validity: None,
+        })
+    }
+
+    pub fn from_nested(nested: Vec<Vec<(f32, f32)>>, spacing: u32) -> Result<FlowField> {
+        let height = nested.len() as u32;
+        let width = nested.first().map_or(0, |row| row.len()) as u32;
+        for (index, row) in nested.iter().enumerate() {
+            if row.len() as u32 != width {
+                return Err(VfiError::InvalidSettings(format!(
+                    "row {} holds {} vectors, but row 0 holds {}",
+                    index,
+                    row.len(),
+                    width
+                )));
+            }
+        }
+        FlowField::from_vectors(width, height, spacing, nested.into_iter().flatten().collect())
+    }
+
+    pub fn to_nested(&self) -> Vec<Vec<(f32, f32)>> {
+        self.rows().map(|row| row.to_vec()).collect()
+    }
+
+    /// Reads a Middlebury `.flo` file as a per-pixel field.
+    pub fn read_flo(filename: &str) -> Result<FlowField> {
+        flo::read(filename)
+    }
+
+    /// Writes the field as a Middlebury `.flo` file. Fields with a spacing
+    /// above 1 are expanded to one vector per pixel.
+    pub fn write_flo(&self, filename: &str) -> Result<()> {
+        flo::write(self, filename)
+    }
+
+    /// Reads a KITTI 16-bit flow PNG as a per-pixel field with a validity mask.
+    pub fn read_kitti_png(filename: &str) -> Result<FlowField> {
+        kitti::read(filename)
+    }
+
+    /// Writes the field as a KITTI 16-bit flow PNG, one vector per pixel.
+    pub fn write_kitti_png(&self, filename: &str) -> Result<()> {
+        kitti::write(self, filename)
+    }
+
+    /// Reads a three-channel PFM file as a per-pixel field.
+    pub fn read_pfm(filename: &str) -> Result<FlowField> {
+        pfm::read(filename)
+    }
+
+    /// Writes the field as a three-channel PFM file, one vector per pixel.
+    pub fn write_pfm(&self, filename: &str) -> Result<()> {
+        pfm::write(self, filename)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn spacing(&self) -> u32 {
+        self.spacing
+    }
+
+    /// Size of the frame area covered by the field, in pixels.
+    pub fn pixel_dimensions(&self) -> (u32, u32) {
+        (self.width * self.spacing, self.height * self.spacing)
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Option<(f32, f32)> {
+        if x < self.width && y < self.height {
+            Some(self.vectors[self.index_of(x, y)])
+        } else {
+            None
+        }
+    }
+
+    /// Vector covering the frame pixel `(x, y)`, clamped to the field border.
+    pub fn at_pixel(&self, x: u32, y: u32) -> (f32, f32) {
+        let (gx, gy) = self.grid_position(x, y);
+        self.vectors[self.index_of(gx, gy)]
+    }
+
+    pub fn vectors(&self) -> &[(f32, f32)] {
+        &self.vectors
+    }
+
+    pub fn rows(&self) -> std::slice::Chunks<'_, (f32, f32)> {
+        self.vectors.chunks(self.width.max(1) as usize)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, (f32, f32)> {
+        self.vectors.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, (f32, f32)> {
+        self.vectors.iter_mut()
+    }
+
+    /// Iterates over `(x, y, vector)` in row-major order.
+    pub fn enumerate(&self) -> impl Iterator<Item = (u32, u32, (f32, f32))> + '_ {
+        let width = self.width;
+        self.vectors
+            .iter()
+            .enumerate()
+            .map(move |(index, &v)| (index as u32 % width, index as u32 / width, v))
+    }
+
+    pub fn confidence(&self) -> Option<&[f32]> {
+        self.confidence.as_deref()
+    }
+
+    pub fn confidence_at(&self, x: u32, y: u32) -> Option<f32> {
+        let index = self.index_of(x, y);
+        self.confidence.as_ref().map(|confidence| confidence[index])
+    }
+
+    pub fn confidence_at_pixel(&self, x: u32, y: u32) -> Option<f32> {
+        let (gx, gy) = self.grid_position(x, y);
+        self.confidence_at(gx, gy)
+    }
+
+    pub fn set_confidence(&mut self, confidence: Vec<f32>) -> Result<()> {
+        if confidence.len() != self.vectors.len() {
+            return Err(VfiError::InvalidSettings(format!(
+                "{} confidence values do not match {} flow vectors",
+                confidence.len(),
+                self.vectors.len()
+            )));
+        }
+        self.confidence = Some(confidence);
+        Ok(())
+    }
+
+    pub fn with_confidence(mut self, confidence: Vec<f32>) -> Result<FlowField> {
+        self.set_confidence(confidence)?;
+        Ok(self)
+    }
+
+    pub fn clear_confidence(&mut self) {
+        self.confidence = None;
+    }
+
+    pub fn visibility(&self) -> Option<&[Visibility]> {
+        self.visibility.as_deref()
+    }
+
+    pub fn visibility_at_pixel(&self, x: u32, y: u32) -> Option<Visibility> {
+        let (gx, gy) = self.grid_position(x, y);
+        let index = self.index_of(gx, gy);
+        self.visibility.as_ref().map(|visibility| visibility[index])
+    }
+
+    pub fn set_visibility(&mut self, visibility: Vec<Visibility>) -> Result<()> {
+        if visibility.len() != self.vectors.len() {
+            return Err(VfiError::InvalidSettings(format!(
+                "{} visibility values do not match {} flow vectors",
+                visibility.len(),
+                self.vectors.len()
+            )));
+        }
+        self.visibility = Some(visibility);
+        Ok(())
+    }
+
+    pub fn with_visibility(mut self, visibility: Vec<Visibility>) -> Result<FlowField> {
+        self.set_visibility(visibility)?;
+        Ok(self)
+    }
+
+    pub fn clear_visibility(&mut self) {
+        self.visibility = None;
+    }
+
+    /// Which vectors hold a known motion, as marked by ground truth formats.
+    /// Without a mask every vector is valid.
+    pub fn validity(&self) -> Option<&[bool]> {
+        self.validity.as_deref()
+    }
+
+    pub fn is_valid(&self, x: u32, y: u32) -> bool {
+        let index = self.index_of(x, y);
+        self.validity.as_ref().is_none_or(|validity| validity[index])
+    }
+
+    pub fn is_valid_at_pixel(&self, x: u32, y: u32) -> bool {
+        let (gx, gy) = self.grid_position(x, y);
+        self.is_valid(gx, gy)
+    }
+
+    pub fn set_validity(&mut self, validity: Vec<bool>) -> Result<()> {
+        if validity.len() != self.vectors.len() {
+            return Err(VfiError::InvalidSettings(format!(
+                "{} validity values do not match {} flow vectors",
+                validity.len(),
+                self.vectors.len()
+            )));
+        }
+        self.validity = Some(validity);
+        Ok(())
+    }
+
+    pub fn with_validity(mut self, validity: Vec<bool>) -> Result<FlowField> {
+        self.set_validity(validity)?;
+        Ok(self)
+    }
+
+    pub fn clear_validity(&mut self) {
+        self.validity = None;
+    }
+
+    pub fn map<F>(&self, mut f: F) -> FlowField
+    where
+        F: FnMut((f32, f32)) -> (f32, f32),
+    {
+        self.with_vectors(self.vectors.iter().map(|&v| f(v)).collect())
+    }
+
+    pub fn zip<F>(&self, other: &FlowField, mut f: F) -> Result<FlowField>
+    where
+        F: FnMut((f32, f32), (f32, f32)) -> (f32, f32),
+    {
+        check_dimensions(self.dimensions(), other.dimensions())?;
+        Ok(self.with_vectors(
+            self.vectors
+                .iter()
+                .zip(other.vectors.iter())
+                .map(|(&a, &b)| f(a, b))
+                .collect(),
+        ))
+    }
+
+    fn with_vectors(&self, vectors: Vec<(f32, f32)>) -> FlowField {
+        FlowField {
+            width: self.width,
+            height: self.height,
+            spacing: self.spacing,
+            vectors,
+            confidence: self.confidence.clone(),
+            visibility: self.visibility.clone(),
+            validity: self.validity.clone(),
+        }
+    }
+
+    fn grid_position(&self, x: u32, y: u32) -> (u32, u32) {
+        (
+            (x / self.spacing).min(self.width - 1),
+            (y / self.spacing).min(self.height - 1),
+        )
+    }
+
+    fn index_of(&self, x: u32, y: u32) -> usize {
+        (y * self.width + x) as usize
+    }
+}
+
+impl Index<(u32, u32)> for FlowField {
+    type Output = (f32, f32);
+
+    fn index(&self, (x, y): (u32, u32)) -> &(f32, f32) {
+        assert!(x < self.width && y < self.height);
+        &self.vectors[self.index_of(x, y)]
+    }
+}
+
+impl IndexMut<(u32, u32)> for FlowField {
+    fn index_mut(&mut self, (x, y): (u32, u32)) -> &mut (f32, f32) {
+        assert!(x < self.width && y < self.height);
+        let index = self.index_of(x, y);
+        &mut self.vectors[index]
+    }
+}
+
+impl<'a> IntoIterator for &'a FlowField {
+    type Item = &'a (f32, f32);
+    type IntoIter = std::slice::Iter<'a, (f32, f32)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.vectors.iter()
+    }
+}
+
+impl From<FlowField> for Vec<Vec<(f32, f32)>> {
+    fn from(flow: FlowField) -> Vec<Vec<(f32, f32)>> {
+        flow.to_nested()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "holds no vectors")]
+    fn new_rejects_empty_fields() {
+        FlowField::new(0, 0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "spacing must be positive")]
+    fn new_rejects_zero_spacing() {
+        FlowField::new(2, 2, 0);
+    }
+
+    #[test]
+    fn from_vectors_rejects_what_new_rejects() {
+        assert!(FlowField::from_vectors(0, 0, 1, Vec::new()).is_err());
+        assert!(FlowField::from_vectors(1, 1, 0, vec![(0.0, 0.0)]).is_err());
+        assert!(FlowField::from_vectors(2, 1, 1, vec![(0.0, 0.0)]).is_err());
+    }
+
+    #[test]
+    fn from_nested_names_the_ragged_row() {
+        let rows = vec![vec![(0.0, 0.0); 2], vec![(0.0, 0.0); 1]];
+        match FlowField::from_nested(rows, 1) {
+            Err(VfiError::InvalidSettings(message)) => assert!(message.contains("row 1"), "{}", message),
+            other => panic!("unexpected {:?}", other.map(|flow| flow.dimensions())),
+        }
+    }
+
+    #[test]
+    fn block_field_covers_its_pixels() {
+        let flow = FlowField::from_vectors(2, 1, 4, vec![(1.0, 0.0), (0.0, 1.0)]).unwrap();
+        assert_eq!(flow.pixel_dimensions(), (8, 4));
+        assert_eq!(flow.at_pixel(3, 3), (1.0, 0.0));
+        assert_eq!(flow.at_pixel(4, 0), (0.0, 1.0));
+    }
+}
@@ -0,0 +1,187 @@
+use std::fs;
+
+use crate::error::{Result, VfiError};
+
+use super::FlowField;
+
+// "PIEH" read as a little-endian f32, the tag opening a Middlebury `.flo` file.
+const FLO_TAG: f32 = 202021.25;
+// The Middlebury reference code rejects larger dimensions as corrupt headers.
+const MAX_DIMENSION: i32 = 99999;
+const HEADER_LEN: usize = 12;
+// Components above this mark a vector of unknown motion, written as `UNKNOWN_FLOW`.
+const UNKNOWN_FLOW_THRESHOLD: f32 = 1e9;
+const UNKNOWN_FLOW: f32 = 1e10;
+
+fn word(bytes: &[u8], index: usize) -> Option<[u8; 4]> {
+    bytes
+        .get(4 * index..4 * index + 4)
+        .map(|word| word.try_into().unwrap())
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<FlowField> {
+    let (tag, width, height) = match (word(bytes, 0), word(bytes, 1), word(bytes, 2)) {
+        (Some(tag), Some(width), Some(height)) => (
+            f32::from_le_bytes(tag),
+            i32::from_le_bytes(width),
+            i32::from_le_bytes(height),
+        ),
+        _ => return Err(VfiError::MalformedFlow("truncated header".to_string())),
+    };
+    if tag != FLO_TAG {
+        return Err(VfiError::MalformedFlow("missing PIEH tag".to_string()));
+    }
+    for (name, value) in [("width", width), ("height", height)] {
+        if !(1..=MAX_DIMENSION).contains(&value) {
+            return Err(VfiError::MalformedFlow(format!(
+                "{} must be between 1 and {}, got {}",
+                name, MAX_DIMENSION, value
+            )));
+        }
+    }
+    let (width, height) = (width as u32, height as u32);
+    let count = width as usize * height as usize;
+    let expected_len = HEADER_LEN + 8 * count;
+    if bytes.len() != expected_len {
+        return Err(VfiError::MalformedFlow(format!(
+            "expected {} bytes for a {}x{} field, got {}",
+            expected_len,
+            width,
+            height,
+            bytes.len()
+        )));
+    }
+    let mut vectors = Vec::with_capacity(count);
+    let mut validity = Vec::with_capacity(count);
+    for index in 0..count {
+        let dx = f32::from_le_bytes(word(bytes, 3 + 2 * index).unwrap());
+        let dy = f32::from_le_bytes(word(bytes, 4 + 2 * index).unwrap());
+        let valid = dx.abs() <= UNKNOWN_FLOW_THRESHOLD && dy.abs() <= UNKNOWN_FLOW_THRESHOLD;
+        vectors.push(if valid { (dx, dy) } else { (0.0, 0.0) });
+        validity.push(valid);
+    }
+    let flow = FlowField::from_vectors(width, height, 1, vectors)?;
+    if validity.contains(&false) {
+        flow.with_validity(validity)
+    } else {
+        Ok(flow)
+    }
+}
+
+// Block flows are written out per pixel, each pixel taking its block's vector.
+pub(crate) fn encode(flow: &FlowField) -> Result<Vec<u8>> {
+    let (width, height) = flow.pixel_dimensions();
+    if width == 0 || height == 0 || width > MAX_DIMENSION as u32 || height > MAX_DIMENSION as u32 {
+        return Err(VfiError::InvalidSettings(format!(
+            "a {}x{} flow cannot be stored as .flo",
+            width, height
+        )));
+    }
+    let mut bytes = Vec::with_capacity(HEADER_LEN + 8 * (width * height) as usize);
+    bytes.extend_from_slice(&FLO_TAG.to_le_bytes());
+    bytes.extend_from_slice(&(width as i32).to_le_bytes());
+    bytes.extend_from_slice(&(height as i32).to_le_bytes());
+    for y in 0..height {
+        for x in 0..width {
+            let (dx, dy) = if flow.is_valid_at_pixel(x, y) {
+                flow.at_pixel(x, y)
+            } else {
+                (UNKNOWN_FLOW, UNKNOWN_FLOW)
+            };
+            bytes.extend_from_slice(&dx.to_le_bytes());
+            bytes.extend_from_slice(&dy.to_le_bytes());
+        }
+    }
+    Ok(bytes)
+}
+
+pub(super) fn read(filename: &str) -> Result<FlowField> {
+    decode(&fs::read(filename)?)
+}
+
+pub(super) fn write(flow: &FlowField, filename: &str) -> Result<()> {
+    fs::write(filename, encode(flow)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_flow() -> FlowField {
+        let vectors = vec![(0.5, -1.0), (2.0, 0.0), (0.0, 0.0), (-3.25, 4.0), (1.0, 1.0), (0.0, -0.5)];
+        FlowField::from_vectors(3, 2, 1, vectors).unwrap()
+    }
+
+    #[test]
+    fn round_trip_keeps_vectors() {
+        let flow = sample_flow();
+        let bytes = encode(&flow).unwrap();
+        assert_eq!(&bytes[..4], b"PIEH");
+        assert_eq!(bytes.len(), HEADER_LEN + 8 * 6);
+        assert_eq!(decode(&bytes).unwrap(), flow);
+    }
+
+    #[test]
+    fn round_trip_keeps_invalid_vectors() {
+        let flow = sample_flow().with_validity(vec![true, true, false, true, true, true]).unwrap();
+        let decoded = decode(&encode(&flow).unwrap()).unwrap();
+        assert_eq!(decoded.validity(), flow.validity());
+        assert_eq!(decoded, flow);
+    }
+
+    #[test]
+    fn block_flow_is_written_per_pixel() {
+        let flow = FlowField::from_vectors(2, 1, 2, vec![(1.0, 0.0), (0.0, 2.0)]).unwrap();
+        let decoded = decode(&encode(&flow).unwrap()).unwrap();
+        assert_eq!(decoded.dimensions(), (4, 2));
+        assert_eq!(decoded[(1, 1)], (1.0, 0.0));
+        assert_eq!(decoded[(2, 0)], (0.0, 2.0));
+    }
+
+    #[test]
+    fn rejects_missing_tag() {
+        let mut bytes = encode(&sample_flow()).unwrap();
+        bytes[..4].copy_from_slice(b"PIEX");
+        assert!(matches!(decode(&bytes), Err(VfiError::MalformedFlow(_))));
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        let bytes = encode(&sample_flow()).unwrap();
+        assert!(matches!(decode(&bytes[..HEADER_LEN - 1]), Err(VfiError::MalformedFlow(_))));
+        assert!(matches!(decode(&[]), Err(VfiError::MalformedFlow(_))));
+    }
+
+    #[test]
+    fn rejects_length_mismatch() {
+        let mut bytes = encode(&sample_flow()).unwrap();
+        assert!(matches!(decode(&bytes[..bytes.len() - 4]), Err(VfiError::MalformedFlow(_))));
+        bytes.extend_from_slice(&[0; 8]);
+        assert!(matches!(decode(&bytes), Err(VfiError::MalformedFlow(_))));
+    }
+
+    #[test]
+    fn rejects_bad_dimensions() {
+        let mut bytes = encode(&sample_flow()).unwrap();
+        bytes[4..8].copy_from_slice(&0i32.to_le_bytes());
+        assert!(matches!(decode(&bytes), Err(VfiError::MalformedFlow(_))));
+        bytes[4..8].copy_from_slice(&(MAX_DIMENSION + 1).to_le_bytes());
+        assert!(matches!(decode(&bytes), Err(VfiError::MalformedFlow(_))));
+    }
+
+    #[test]
+    fn marks_unknown_flow_invalid() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&FLO_TAG.to_le_bytes());
+        bytes.extend_from_slice(&2i32.to_le_bytes());
+        bytes.extend_from_slice(&1i32.to_le_bytes());
+        for value in [1.5f32, -2.0, 2e9, 0.0] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        let flow = decode(&bytes).unwrap();
+        assert_eq!(flow.validity(), Some(&[true, false][..]));
+        assert_eq!(flow[(0, 0)], (1.5, -2.0));
+        assert_eq!(flow[(1, 0)], (0.0, 0.0));
+    }
+}
@@ -0,0 +1,110 @@
+use image::{DynamicImage, ImageBuffer, ImageFormat, Rgb};
+
+use crate::error::{Result, VfiError};
+
+use super::FlowField;
+
+// KITTI stores each component as `64 * d + 2^15` in a 16-bit channel.
+const SCALE: f32 = 64.0;
+const OFFSET: f32 = 32768.0;
+
+fn encode_component(value: f32) -> u16 {
+    (value * SCALE + OFFSET).round().clamp(0.0, u16::MAX as f32) as u16
+}
+
+fn decode_component(value: u16) -> f32 {
+    (value as f32 - OFFSET) / SCALE
+}
+
+/// Reads a KITTI flow PNG: 16-bit RGB with the encoded `dx` and `dy` in red
+/// and green and a validity flag in blue. Invalid vectors are zero.
+pub(super) fn read(filename: &str) -> Result<FlowField> {
+    let image = match image::open(filename)? {
+        DynamicImage::ImageRgb16(image) => image,
+        _ => {
+            return Err(VfiError::MalformedFlow(format!(
+                "{} is not a 16-bit RGB PNG",
+                filename
+            )))
+        }
+    };
+    let (width, height) = image.dimensions();
+    let mut vectors = Vec::with_capacity((width * height) as usize);
+    let mut validity = Vec::with_capacity((width * height) as usize);
+    for pixel in image.pixels() {
+        let [red, green, blue] = pixel.0;
+        let valid = blue > 0;
+        vectors.push(if valid {
+            (decode_component(red), decode_component(green))
+        } else {
+            (0.0, 0.0)
+        });
+        validity.push(valid);
+    }
+    FlowField::from_vectors(width, height, 1, vectors)?.with_validity(validity)
+}
+
+// Components outside the encodable range of about ±512 px are clamped.
+pub(super) fn write(flow: &FlowField, filename: &str) -> Result<()> {
+    let (width, height) = flow.pixel_dimensions();
+    let image: ImageBuffer<Rgb<u16>, Vec<u16>> = ImageBuffer::from_fn(width, height, |x, y| {
+        let (dx, dy) = flow.at_pixel(x, y);
+        if flow.is_valid_at_pixel(x, y) && dx.is_finite() && dy.is_finite() {
+            Rgb([encode_component(dx), encode_component(dy), 1])
+        } else {
+            Rgb([0, 0, 0])
+        }
+    });
+    image.save_with_format(filename, ImageFormat::Png)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("vfi-kitti-{}-{}.png", process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn round_trip_keeps_invalid_vectors() {
+        let vectors = vec![(1.5, -0.25), (0.0, 0.0), (-100.0, 3.0), (0.015625, 250.0)];
+        let flow = FlowField::from_vectors(2, 2, 1, vectors)
+            .unwrap()
+            .with_validity(vec![true, false, true, true])
+            .unwrap();
+        let path = temp_path("round-trip");
+        write(&flow, &path).unwrap();
+        let decoded = read(&path);
+        fs::remove_file(&path).ok();
+        assert_eq!(decoded.unwrap(), flow);
+    }
+
+    #[test]
+    fn clamps_to_512_pixels() {
+        let flow = FlowField::from_vectors(2, 1, 1, vec![(600.0, -600.0), (-511.0, 511.5)]).unwrap();
+        let path = temp_path("clamp");
+        write(&flow, &path).unwrap();
+        let decoded = read(&path);
+        fs::remove_file(&path).ok();
+        let decoded = decoded.unwrap();
+        assert_eq!(decoded[(0, 0)], (32767.0 / 64.0, -512.0));
+        assert_eq!(decoded[(1, 0)], (-511.0, 511.5));
+        assert_eq!(decoded.validity(), Some(&[true, true][..]));
+    }
+
+    #[test]
+    fn rejects_8_bit_images() {
+        let path = temp_path("8-bit");
+        image::RgbImage::new(2, 2).save(&path).unwrap();
+        let decoded = read(&path);
+        fs::remove_file(&path).ok();
+        assert!(matches!(decoded, Err(VfiError::MalformedFlow(_))));
+    }
+}
@@ -0,0 +1,183 @@
+use std::fs;
+
+use crate::error::{Result, VfiError};
+
+use super::FlowField;
+
+// Next whitespace-separated header token, leaving `position` on the
+// whitespace byte that ends it.
+fn next_token<'a>(bytes: &'a [u8], position: &mut usize) -> Option<&'a str> {
+    while bytes.get(*position).is_some_and(|byte| byte.is_ascii_whitespace()) {
+        *position += 1;
+    }
+    let start = *position;
+    while bytes.get(*position).is_some_and(|byte| !byte.is_ascii_whitespace()) {
+        *position += 1;
+    }
+    if start == *position {
+        return None;
+    }
+    std::str::from_utf8(&bytes[start..*position]).ok()
+}
+
+/// Reads a three-channel `PF` file, taking `dx` and `dy` from the first two
+/// channels. Rows are stored bottom to top, and the sign of the scale selects
+/// the byte order. Non-finite vectors are marked invalid.
+pub(super) fn read(filename: &str) -> Result<FlowField> {
+    let bytes = fs::read(filename)?;
+    let mut position = 0;
+    match next_token(&bytes, &mut position) {
+        Some("PF") => {}
+        Some("Pf") => {
+            return Err(VfiError::MalformedFlow(
+                "single-channel PFM cannot hold flow".to_string(),
+            ))
+        }
+        _ => return Err(VfiError::MalformedFlow("missing PF header".to_string())),
+    }
+    let mut dimension = |name: &str| {
+        next_token(&bytes, &mut position)
+            .and_then(|token| token.parse::<u32>().ok())
+            .filter(|&value| value > 0)
+            .ok_or_else(|| VfiError::MalformedFlow(format!("invalid {}", name)))
+    };
+    let width = dimension("width")?;
+    let height = dimension("height")?;
+    let scale = next_token(&bytes, &mut position)
+        .and_then(|token| token.parse::<f32>().ok())
+        .filter(|scale| *scale != 0.0 && scale.is_finite())
+        .ok_or_else(|| VfiError::MalformedFlow("invalid scale".to_string()))?;
+    // A single whitespace byte separates the header from the data.
+    let data = &bytes[(position + 1).min(bytes.len())..];
+
+    let count = width as usize * height as usize;
+    if data.len() != 12 * count {
+        return Err(VfiError::MalformedFlow(format!(
+            "expected {} bytes of data for a {}x{} field, got {}",
+            12 * count,
+            width,
+            height,
+            data.len()
+        )));
+    }
+    let value = |index: usize| {
+        let word = data[4 * index..4 * index + 4].try_into().unwrap();
+        if scale < 0.0 {
+            f32::from_le_bytes(word)
+        } else {
+            f32::from_be_bytes(word)
+        }
+    };
+
+    let mut vectors = Vec::with_capacity(count);
+    let mut validity = Vec::with_capacity(count);
+    for y in (0..height as usize).rev() {
+        for x in 0..width as usize {
+            let index = 3 * (y * width as usize + x);
+            let (dx, dy) = (value(index), value(index + 1));
+            let valid = dx.is_finite() && dy.is_finite();
+            vectors.push(if valid { (dx, dy) } else { (0.0, 0.0) });
+            validity.push(valid);
+        }
+    }
+    let flow = FlowField::from_vectors(width, height, 1, vectors)?;
+    if validity.contains(&false) {
+        flow.with_validity(validity)
+    } else {
+        Ok(flow)
+    }
+}
+
+// Written little-endian with a zero third channel. PFM has no validity
+// channel, so invalid vectors are written as NaN.
+pub(super) fn write(flow: &FlowField, filename: &str) -> Result<()> {
+    let (width, height) = flow.pixel_dimensions();
+    let mut bytes = format!("PF\n{} {}\n-1.0\n", width, height).into_bytes();
+    bytes.reserve(12 * (width * height) as usize);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let (dx, dy) = if flow.is_valid_at_pixel(x, y) {
+                flow.at_pixel(x, y)
+            } else {
+                (f32::NAN, f32::NAN)
+            };
+            for value in [dx, dy, 0.0] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+    fs::write(filename, bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("vfi-pfm-{}-{}.pfm", process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn read_bytes(name: &str, bytes: &[u8]) -> Result<FlowField> {
+        let path = temp_path(name);
+        fs::write(&path, bytes).unwrap();
+        let flow = read(&path);
+        fs::remove_file(&path).ok();
+        flow
+    }
+
+    #[test]
+    fn round_trip_keeps_invalid_vectors() {
+        let vectors = vec![(1.5, -0.25), (0.0, 0.0), (-100.0, 3.0), (0.1, 1e6)];
+        let flow = FlowField::from_vectors(2, 2, 1, vectors)
+            .unwrap()
+            .with_validity(vec![true, false, true, true])
+            .unwrap();
+        let path = temp_path("round-trip");
+        write(&flow, &path).unwrap();
+        let decoded = read(&path);
+        fs::remove_file(&path).ok();
+        assert_eq!(decoded.unwrap(), flow);
+    }
+
+    #[test]
+    fn reads_big_endian_rows_bottom_to_top() {
+        // A positive scale means big-endian data. The first stored row is the
+        // bottom row of the image.
+        let mut bytes = b"PF\n2 2\n1.0\n".to_vec();
+        let rows = [[(5.0f32, 6.0f32), (7.0, 8.0)], [(1.0, 2.0), (3.0, 4.0)]];
+        for row in rows {
+            for (dx, dy) in row {
+                for value in [dx, dy, 0.0] {
+                    bytes.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+        }
+        let flow = read_bytes("big-endian", &bytes).unwrap();
+        assert_eq!(flow.dimensions(), (2, 2));
+        assert_eq!(flow[(0, 0)], (1.0, 2.0));
+        assert_eq!(flow[(1, 0)], (3.0, 4.0));
+        assert_eq!(flow[(0, 1)], (5.0, 6.0));
+        assert_eq!(flow[(1, 1)], (7.0, 8.0));
+        assert_eq!(flow.validity(), None);
+    }
+
+    #[test]
+    fn rejects_single_channel_files() {
+        let mut bytes = b"Pf\n1 1\n-1.0\n".to_vec();
+        bytes.extend_from_slice(&0f32.to_le_bytes());
+        assert!(matches!(read_bytes("single-channel", &bytes), Err(VfiError::MalformedFlow(_))));
+    }
+
+    #[test]
+    fn rejects_length_mismatch() {
+        let mut bytes = b"PF\n1 1\n-1.0\n".to_vec();
+        bytes.extend_from_slice(&[0; 8]);
+        assert!(matches!(read_bytes("length", &bytes), Err(VfiError::MalformedFlow(_))));
+    }
+}
@@ -0,0 +1,16 @@
+pub mod compensator;
+pub mod error;
+pub mod estimator;
+pub mod evaluation;
+pub mod flow;
+pub mod operations;
+pub mod retiming;
+pub mod synthetic;
+pub mod visualizer;
+
+mod plotter;
+
+pub use estimator::combinator;
+pub use error::VfiError;
+pub use estimator::Estimator;
+pub use flow::{FlowField, Visibility};
@@ -1,12 +1,13 @@
-use crate::tester::test;
+use crate::tester::{test, test_interpolation, test_synthetic};
 
 mod tester;
-mod estimator;
-mod operations;
-mod compensator;
-mod visualizer;
-mod plotter;
 
-fn main() {
-    test();
+fn main() -> Result<(), vfi::VfiError> {
+    if std::env::args().any(|arg| arg == "--leave-one-out") {
+        test_interpolation()
+    } else if std::env::args().any(|arg| arg == "--synthetic") {
+        test_synthetic()
+    } else {
+        test()
+    }
 }
@@ -1,6 +1,8 @@
 use image::{GenericImage, ImageBuffer, Pixel, Rgb, RgbImage};
 use num::integer::Roots;
 
+use crate::flow::{FlowField, Visibility};
+
 pub fn add_padding(
     img: &ImageBuffer<Rgb<u8>, Vec<u8>>,
     p_width: u32,
@@ -25,16 +27,16 @@ pub fn add_padding(
         }
     }
 
-    return out_img;
+    out_img
 }
 
 pub fn pixel_difference(pix_1: Rgb<u8>, pix_2: Rgb<u8>) -> u16 {
     let ch1 = pix_1.channels();
     let ch2 = pix_2.channels();
-    return ((ch1[0].abs_diff(ch2[0]) as u32).pow(2)
+    ((ch1[0].abs_diff(ch2[0]) as u32).pow(2)
         + (ch1[1].abs_diff(ch2[1]) as u32).pow(2)
         + (ch1[2].abs_diff(ch2[2]) as u32).pow(2))
-    .sqrt() as u16;
+    .sqrt() as u16
 }
 
 pub fn pixel_average(pix_1: Rgb<u8>, pix_2: Rgb<u8>) -> Rgb<u8> {
@@ -47,22 +49,119 @@ pub fn pixel_average(pix_1: Rgb<u8>, pix_2: Rgb<u8>) -> Rgb<u8> {
     ])
 }
 
-pub fn scale_up(flow: Vec<Vec<(f32, f32)>>, factor: u32) -> Vec<Vec<(f32, f32)>> {
-    if factor == 1 { return flow; }
-    let width = flow[0].len() * factor as usize;
-    let height = flow.len() * factor as usize;
-    let mut res_flow = vec![vec![(0f32, 0f32); width]; height];
-    for i in 0..flow.len() as u32 {
-        for j in 0..flow[0].len() as u32 {
-            for ib in 0..factor {
-                for jb in 0..factor {
-                    let res_i = i * factor + ib;
-                    let res_j = j * factor + jb;
-                    res_flow[res_i as usize][res_j as usize] = flow[i as usize][j as usize];
+// Halves both dimensions by averaging 2x2 neighbourhoods; odd trailing rows
+// and columns are folded into the last output pixel.
+pub fn downsample(img: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let (width, height) = img.dimensions();
+    let (out_width, out_height) = ((width / 2).max(1), (height / 2).max(1));
+    ImageBuffer::from_fn(out_width, out_height, |x, y| {
+        let x_end = if x == out_width - 1 { width } else { 2 * x + 2 };
+        let y_end = if y == out_height - 1 { height } else { 2 * y + 2 };
+        let mut sum = [0u32; 3];
+        let mut count = 0;
+        for src_y in 2 * y..y_end {
+            for src_x in 2 * x..x_end {
+                let pixel = img[(src_x, src_y)];
+                for c in 0..3 {
+                    sum[c] += pixel[c] as u32;
                 }
+                count += 1;
             }
         }
+        Rgb(sum.map(|s| ((s + count / 2) / count) as u8))
+    })
+}
+
+fn pixel_at_clamped(img: &ImageBuffer<Rgb<u8>, Vec<u8>>, x: i64, y: i64) -> [f32; 3] {
+    let (width, height) = img.dimensions();
+    let pixel = img[(
+        x.clamp(0, width as i64 - 1) as u32,
+        y.clamp(0, height as i64 - 1) as u32,
+    )];
+    [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32]
+}
+
+pub fn sample_bilinear(img: &ImageBuffer<Rgb<u8>, Vec<u8>>, x: f32, y: f32) -> [f32; 3] {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let p00 = pixel_at_clamped(img, x0, y0);
+    let p10 = pixel_at_clamped(img, x0 + 1, y0);
+    let p01 = pixel_at_clamped(img, x0, y0 + 1);
+    let p11 = pixel_at_clamped(img, x0 + 1, y0 + 1);
+    let mut res = [0f32; 3];
+    for c in 0..3 {
+        let top = p00[c] + (p10[c] - p00[c]) * fx;
+        let bottom = p01[c] + (p11[c] - p01[c]) * fx;
+        res[c] = top + (bottom - top) * fy;
     }
+    res
+}
+
+fn catmull_rom_weights(t: f32) -> [f32; 4] {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    [
+        0.5 * (-t3 + 2.0 * t2 - t),
+        0.5 * (3.0 * t3 - 5.0 * t2 + 2.0),
+        0.5 * (-3.0 * t3 + 4.0 * t2 + t),
+        0.5 * (t3 - t2),
+    ]
+}
 
+pub fn sample_bicubic(img: &ImageBuffer<Rgb<u8>, Vec<u8>>, x: f32, y: f32) -> [f32; 3] {
+    let (x0, y0) = (x.floor(), y.floor());
+    let wx = catmull_rom_weights(x - x0);
+    let wy = catmull_rom_weights(y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let mut res = [0f32; 3];
+    for (j, wy) in wy.iter().enumerate() {
+        for (i, wx) in wx.iter().enumerate() {
+            let pixel = pixel_at_clamped(img, x0 + i as i64 - 1, y0 + j as i64 - 1);
+            for c in 0..3 {
+                res[c] += pixel[c] * wx * wy;
+            }
+        }
+    }
+    res.map(|value| value.clamp(0.0, 255.0))
+}
+
+pub fn scale_up(flow: &FlowField, width: u32, height: u32) -> FlowField {
+    if flow.spacing() == 1 && flow.dimensions() == (width, height) {
+        return flow.clone();
+    }
+    let mut res_flow = FlowField::new(width, height, 1);
+    for y in 0..height {
+        for x in 0..width {
+            res_flow[(x, y)] = flow.at_pixel(x, y);
+        }
+    }
+    if flow.confidence().is_some() {
+        let mut confidence = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                confidence.push(flow.confidence_at_pixel(x, y).unwrap_or(0.0));
+            }
+        }
+        res_flow.set_confidence(confidence).ok();
+    }
+    if flow.visibility().is_some() {
+        let mut visibility = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                visibility.push(flow.visibility_at_pixel(x, y).unwrap_or(Visibility::Both));
+            }
+        }
+        res_flow.set_visibility(visibility).ok();
+    }
+    if flow.validity().is_some() {
+        let mut validity = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                validity.push(flow.is_valid_at_pixel(x, y));
+            }
+        }
+        res_flow.set_validity(validity).ok();
+    }
     res_flow
 }
@@ -22,7 +22,7 @@ pub fn draw_line(
     let sy: i32 = if y0 < y1 { 1 } else { -1 };
 
     // Initialize error
-    let mut err: i32 = if dx > dy { dx as i32 } else { -(dy as i32) } / 2;
+    let mut err: i32 = if dx > dy { dx } else { -dy } / 2;
     let mut err2;
 
     let mut first = true;
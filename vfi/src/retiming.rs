@@ -0,0 +1,218 @@
+use std::fmt;
+
+use crate::error::{Result, VfiError};
+
+/// Frame rate as an exact fraction, e.g. `24000/1001` for NTSC film.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameRate {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+impl FrameRate {
+    pub const fn fps(fps: u32) -> FrameRate {
+        FrameRate {
+            numerator: fps,
+            denominator: 1,
+        }
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.numerator as f64 / self.denominator as f64
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.numerator == 0 || self.denominator == 0 {
+            return Err(VfiError::InvalidSettings(format!(
+                "frame rate must be positive, got {}/{}",
+                self.numerator, self.denominator
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FrameRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.denominator == 1 {
+            write!(f, "{}", self.numerator)
+        } else {
+            write!(f, "{:.3}", self.as_f64())
+        }
+    }
+}
+
+/// An output frame at position `pair + t` on the input timeline: input frame
+/// `pair` itself when `t == 0`, otherwise an interpolation towards `pair + 1`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetimedFrame {
+    pub index: u64,
+    pub pair: u32,
+    pub t: f32,
+}
+
+/// Maps output frames of the `target` rate onto the input timeline of the
+/// `source` rate. Output frame `n` is shown at `n / target` seconds.
+pub struct Retimer {
+    source: FrameRate,
+    target: FrameRate,
+}
+
+impl Retimer {
+    pub fn new(source: FrameRate, target: FrameRate) -> Result<Retimer> {
+        source.validate()?;
+        target.validate()?;
+        Ok(Retimer { source, target })
+    }
+
+    pub fn source(&self) -> FrameRate {
+        self.source
+    }
+
+    pub fn target(&self) -> FrameRate {
+        self.target
+    }
+
+    // Output frame `n` sits at input position `n * step_num / step_den`.
+    fn step(&self) -> (u64, u64) {
+        (
+            self.target.denominator as u64 * self.source.numerator as u64,
+            self.target.numerator as u64 * self.source.denominator as u64,
+        )
+    }
+
+    // First output frame at or after input position `pair`.
+    fn first_output(&self, pair: u32) -> u64 {
+        let (step_num, step_den) = self.step();
+        (pair as u64 * step_den).div_ceil(step_num)
+    }
+
+    /// Output frames whose timestamps fall in `[pair, pair + 1)` on the input
+    /// timeline, with `pair` counted from 0.
+    pub fn frames_for_pair(&self, pair: u32) -> Vec<RetimedFrame> {
+        let (step_num, step_den) = self.step();
+        (self.first_output(pair)..self.first_output(pair + 1))
+            .map(|index| RetimedFrame {
+                index,
+                pair,
+                t: ((index * step_num - pair as u64 * step_den) as f64 / step_den as f64) as f32,
+            })
+            .collect()
+    }
+
+    /// Number of output frames covering `input_frames` input frames, up to and
+    /// including the last one.
+    pub fn output_frame_count(&self, input_frames: u32) -> u64 {
+        if input_frames == 0 {
+            return 0;
+        }
+        let (step_num, step_den) = self.step();
+        let last = input_frames as u64 - 1;
+        let first_after = self.first_output(input_frames - 1);
+        let lands_on_last = first_after * step_num == last * step_den;
+        first_after + lands_on_last as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retimer(source: u32, target: u32) -> Retimer {
+        Retimer::new(FrameRate::fps(source), FrameRate::fps(target)).unwrap()
+    }
+
+    // `(index, t)` of every output frame of the pair.
+    fn times(retimer: &Retimer, pair: u32) -> Vec<(u64, f32)> {
+        retimer
+            .frames_for_pair(pair)
+            .iter()
+            .inspect(|frame| assert_eq!(frame.pair, pair))
+            .map(|frame| (frame.index, (frame.t * 1e4).round() / 1e4))
+            .collect()
+    }
+
+    #[test]
+    fn film_to_60_alternates_three_and_two_frames() {
+        let retimer = retimer(24, 60);
+        assert_eq!(times(&retimer, 0), [(0, 0.0), (1, 0.4), (2, 0.8)]);
+        assert_eq!(times(&retimer, 1), [(3, 0.2), (4, 0.6)]);
+        assert_eq!(times(&retimer, 2), [(5, 0.0), (6, 0.4), (7, 0.8)]);
+        assert_eq!(retimer.output_frame_count(24), 58);
+    }
+
+    #[test]
+    fn pal_to_30_adds_a_frame_every_five() {
+        let retimer = retimer(25, 30);
+        let expected = [0.0, 0.8333, 0.6667, 0.5, 0.3333, 0.1667];
+        let mut outputs = Vec::new();
+        for pair in 0..5 {
+            outputs.extend(times(&retimer, pair));
+        }
+        // Output 1 falls in pair 0, and pair 5 repeats the pattern of pair 0.
+        assert_eq!(outputs.iter().map(|&(index, _)| index).collect::<Vec<_>>(), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(outputs.iter().map(|&(_, t)| t).collect::<Vec<_>>(), expected);
+        assert_eq!(times(&retimer, 5), [(6, 0.0), (7, 0.8333)]);
+    }
+
+    #[test]
+    fn doubling_inserts_midpoints() {
+        let retimer = retimer(30, 60);
+        let mut outputs = 0;
+        for pair in 0..179 {
+            assert_eq!(times(&retimer, pair), [(2 * pair as u64, 0.0), (2 * pair as u64 + 1, 0.5)]);
+            outputs += retimer.frames_for_pair(pair).len() as u64;
+        }
+        // The last input frame follows the 358 frames of the 179 pairs.
+        assert_eq!(outputs + 1, 359);
+        assert_eq!(retimer.output_frame_count(180), 359);
+    }
+
+    #[test]
+    fn slowing_down_leaves_pairs_without_output() {
+        let retimer = retimer(60, 24);
+        assert_eq!(times(&retimer, 0), [(0, 0.0)]);
+        assert_eq!(times(&retimer, 1), []);
+        assert_eq!(times(&retimer, 2), [(1, 0.5)]);
+        assert_eq!(times(&retimer, 3), []);
+        assert_eq!(times(&retimer, 4), []);
+        assert_eq!(times(&retimer, 5), [(2, 0.0)]);
+        assert_eq!(retimer.output_frame_count(6), 3);
+        assert_eq!(retimer.output_frame_count(5), 2);
+    }
+
+    #[test]
+    fn output_frame_count_matches_the_pairs() {
+        let ntsc = FrameRate { numerator: 24000, denominator: 1001 };
+        let rates = [FrameRate::fps(24), FrameRate::fps(25), FrameRate::fps(30), FrameRate::fps(60), ntsc];
+        for source in rates {
+            for target in rates {
+                let retimer = Retimer::new(source, target).unwrap();
+                assert_eq!(retimer.output_frame_count(0), 0);
+                assert_eq!(retimer.output_frame_count(1), 1);
+                for input_frames in [2, 7, 100] {
+                    let pairs: u64 = (0..input_frames - 1)
+                        .map(|pair| retimer.frames_for_pair(pair).len() as u64)
+                        .sum();
+                    // The last input frame adds an output when one lands on it.
+                    let last = retimer.frames_for_pair(input_frames - 1);
+                    let lands_on_last = last.first().is_some_and(|frame| frame.t == 0.0);
+                    assert_eq!(
+                        retimer.output_frame_count(input_frames),
+                        pairs + lands_on_last as u64,
+                        "{} -> {} over {} frames",
+                        source,
+                        target,
+                        input_frames
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_zero_rates() {
+        assert!(Retimer::new(FrameRate::fps(0), FrameRate::fps(60)).is_err());
+        assert!(Retimer::new(FrameRate::fps(24), FrameRate { numerator: 60, denominator: 0 }).is_err());
+    }
+}
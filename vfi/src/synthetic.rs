@@ -0,0 +1,433 @@
+use std::f64::consts::PI;
+use std::fs;
+
+use image::{GrayImage, ImageBuffer, Luma, Rgb};
+
+use crate::error::{Result, VfiError};
+use crate::flow::FlowField;
+
+// Sinusoids summed into each texture. Their frequencies stay well below the
+// sampling limit, so sub-pixel motion renders without aliasing.
+const TEXTURE_WAVES: u32 = 6;
+const MIN_FREQUENCY: f64 = 0.08;
+const MAX_FREQUENCY: f64 = 0.6;
+
+/// Motion of the background from one frame to the next. Rotation and zoom
+/// are about the frame center.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Motion {
+    Translation { dx: f32, dy: f32 },
+    /// Angle in radians, clockwise in image coordinates.
+    Rotation { angle: f32 },
+    Zoom { scale: f32 },
+}
+
+/// A textured rectangle at `(x, y)` in the first frame that moves by
+/// `(dx, dy)` per frame. Later rectangles are drawn over earlier ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MovingRectangle {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub dx: f32,
+    pub dy: f32,
+}
+
+pub struct SceneSettings {
+    pub width: u32,
+    pub height: u32,
+    pub background_motion: Motion,
+    pub rectangles: Vec<MovingRectangle>,
+    /// Factor and offset applied to the intensities once per frame.
+    pub brightness_gain: f32,
+    pub brightness_offset: f32,
+    pub seed: u32,
+}
+
+impl Default for SceneSettings {
+    fn default() -> SceneSettings {
+        SceneSettings {
+            width: 256,
+            height: 192,
+            background_motion: Motion::Translation { dx: 2.5, dy: -1.25 },
+            rectangles: Vec::new(),
+            brightness_gain: 1.0,
+            brightness_offset: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+impl SceneSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(VfiError::InvalidSettings(
+                "scene dimensions must be positive".to_string(),
+            ));
+        }
+        let motion_is_valid = match self.background_motion {
+            Motion::Translation { dx, dy } => dx.is_finite() && dy.is_finite(),
+            Motion::Rotation { angle } => angle.is_finite(),
+            Motion::Zoom { scale } => scale.is_finite() && scale > 0.0,
+        };
+        if !motion_is_valid {
+            return Err(VfiError::InvalidSettings(format!(
+                "invalid background motion {:?}",
+                self.background_motion
+            )));
+        }
+        for rectangle in self.rectangles.iter() {
+            let values = [rectangle.x, rectangle.y, rectangle.dx, rectangle.dy];
+            if !(rectangle.width > 0.0 && rectangle.height > 0.0) || values.iter().any(|v| !v.is_finite()) {
+                return Err(VfiError::InvalidSettings(format!(
+                    "invalid rectangle {:?}",
+                    rectangle
+                )));
+            }
+        }
+        let gain_is_valid = self.brightness_gain.is_finite() && self.brightness_gain > 0.0;
+        if !gain_is_valid || !self.brightness_offset.is_finite() {
+            return Err(VfiError::InvalidSettings(format!(
+                "invalid brightness change {} * v + {}",
+                self.brightness_gain, self.brightness_offset
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Frames with the exact motion between them. `flows[n]` and `occlusions[n]`
+/// describe the step from `frames[n]` to `frames[n + 1]`.
+pub struct SyntheticSequence {
+    pub frames: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+    pub flows: Vec<FlowField>,
+    pub occlusions: Vec<Vec<bool>>,
+}
+
+impl SyntheticSequence {
+    /// Writes the sequence in the layout the tester reads: frames as
+    /// `{n}.png` counted from 1, ground truth as `flow/{n}.flo` and
+    /// occlusion masks as `occlusion/{n}.png`.
+    pub fn save(&self, directory: &str) -> Result<()> {
+        fs::create_dir_all(format!("{}/flow", directory))?;
+        fs::create_dir_all(format!("{}/occlusion", directory))?;
+        for (index, frame) in self.frames.iter().enumerate() {
+            frame.save(format!("{}/{}.png", directory, index + 1))?;
+        }
+        for (index, (flow, occlusion)) in self.flows.iter().zip(self.occlusions.iter()).enumerate() {
+            flow.write_flo(&format!("{}/flow/{}.flo", directory, index + 1))?;
+            let mask = GrayImage::from_fn(flow.width(), flow.height(), |x, y| {
+                Luma([if occlusion[(y * flow.width() + x) as usize] { 255 } else { 0 }])
+            });
+            mask.save(format!("{}/occlusion/{}.png", directory, index + 1))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Layer {
+    Background,
+    Rectangle(usize),
+}
+
+// Uniform value in [0, 1) from a seed and an index, via SplitMix64.
+fn unit(seed: u32, index: u32) -> f64 {
+    let mut z = ((seed as u64) << 32 | index as u64).wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Colour of a texture at continuous texture coordinates.
+fn texture(seed: u32, (u, v): (f64, f64)) -> [f64; 3] {
+    let mut colour = [128.0; 3];
+    for wave in 0..TEXTURE_WAVES {
+        let random = |k: u32| unit(seed, wave * 8 + k);
+        let frequency = MIN_FREQUENCY + (MAX_FREQUENCY - MIN_FREQUENCY) * random(0);
+        let direction = 2.0 * PI * random(1);
+        let phase = 2.0 * PI * random(2);
+        let amplitude = 10.0 + 10.0 * random(3);
+        let value = amplitude * (frequency * (u * direction.cos() + v * direction.sin()) + phase).sin();
+        for (channel, c) in colour.iter_mut().enumerate() {
+            *c += value * (0.5 + 0.5 * random(4 + channel as u32));
+        }
+    }
+    colour
+}
+
+struct Scene<'a> {
+    settings: &'a SceneSettings,
+    center: (f64, f64),
+}
+
+impl Scene<'_> {
+    // Position in the next frame of the background point at `(x, y)`.
+    fn move_background(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let (cx, cy) = self.center;
+        match self.settings.background_motion {
+            Motion::Translation { dx, dy } => (x + dx as f64, y + dy as f64),
+            Motion::Rotation { angle } => {
+                let (sin, cos) = (angle as f64).sin_cos();
+                (cx + cos * (x - cx) - sin * (y - cy), cy + sin * (x - cx) + cos * (y - cy))
+            }
+            Motion::Zoom { scale } => (cx + scale as f64 * (x - cx), cy + scale as f64 * (y - cy)),
+        }
+    }
+
+    // Position in the previous frame of the background point at `(x, y)`.
+    fn unmove_background(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let (cx, cy) = self.center;
+        match self.settings.background_motion {
+            Motion::Translation { dx, dy } => (x - dx as f64, y - dy as f64),
+            Motion::Rotation { angle } => {
+                let (sin, cos) = (angle as f64).sin_cos();
+                (cx + cos * (x - cx) + sin * (y - cy), cy - sin * (x - cx) + cos * (y - cy))
+            }
+            Motion::Zoom { scale } => (cx + (x - cx) / scale as f64, cy + (y - cy) / scale as f64),
+        }
+    }
+
+    fn rectangle_origin(&self, index: usize, frame: u32) -> (f64, f64) {
+        let rectangle = &self.settings.rectangles[index];
+        (
+            rectangle.x as f64 + frame as f64 * rectangle.dx as f64,
+            rectangle.y as f64 + frame as f64 * rectangle.dy as f64,
+        )
+    }
+
+    fn top_layer(&self, frame: u32, (x, y): (f64, f64)) -> Layer {
+        (0..self.settings.rectangles.len())
+            .rev()
+            .find(|&index| {
+                let rectangle = &self.settings.rectangles[index];
+                let (left, top) = self.rectangle_origin(index, frame);
+                x >= left && x < left + rectangle.width as f64 && y >= top && y < top + rectangle.height as f64
+            })
+            .map_or(Layer::Background, Layer::Rectangle)
+    }
+
+    fn render(&self, frame: u32) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let gain = (self.settings.brightness_gain as f64).powi(frame as i32);
+        let offset = self.settings.brightness_offset as f64 * frame as f64;
+        ImageBuffer::from_fn(self.settings.width, self.settings.height, |x, y| {
+            let position = (x as f64, y as f64);
+            let colour = match self.top_layer(frame, position) {
+                Layer::Background => {
+                    let origin = (0..frame).fold(position, |p, _| self.unmove_background(p));
+                    texture(self.settings.seed, origin)
+                }
+                Layer::Rectangle(index) => {
+                    let (left, top) = self.rectangle_origin(index, frame);
+                    texture(self.settings.seed.wrapping_add(index as u32 + 1), (position.0 - left, position.1 - top))
+                }
+            };
+            Rgb(colour.map(|c| (c * gain + offset).round().clamp(0.0, 255.0) as u8))
+        })
+    }
+
+    // Flow from `frame` to the next frame, and the pixels whose content is
+    // hidden or outside the next frame.
+    fn motion(&self, frame: u32) -> Result<(FlowField, Vec<bool>)> {
+        let (width, height) = (self.settings.width, self.settings.height);
+        let mut vectors = Vec::with_capacity((width * height) as usize);
+        let mut occlusion = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let position = (x as f64, y as f64);
+                let layer = self.top_layer(frame, position);
+                let destination = match layer {
+                    Layer::Background => self.move_background(position),
+                    Layer::Rectangle(index) => {
+                        let rectangle = &self.settings.rectangles[index];
+                        (position.0 + rectangle.dx as f64, position.1 + rectangle.dy as f64)
+                    }
+                };
+                let outside = destination.0 < 0.0
+                    || destination.1 < 0.0
+                    || destination.0 > (width - 1) as f64
+                    || destination.1 > (height - 1) as f64;
+                occlusion.push(outside || self.top_layer(frame + 1, destination) != layer);
+                vectors.push((
+                    (destination.0 - position.0) as f32,
+                    (destination.1 - position.1) as f32,
+                ));
+            }
+        }
+        Ok((FlowField::from_vectors(width, height, 1, vectors)?, occlusion))
+    }
+}
+
+/// Renders `frame_count` frames of the scene with their ground truth.
+pub fn generate(settings: &SceneSettings, frame_count: u32) -> Result<SyntheticSequence> {
+    settings.validate()?;
+    if frame_count < 2 {
+        return Err(VfiError::InvalidSettings(format!(
+            "a sequence needs at least 2 frames, got {}",
+            frame_count
+        )));
+    }
+    let scene = Scene {
+        settings,
+        center: ((settings.width - 1) as f64 / 2.0, (settings.height - 1) as f64 / 2.0),
+    };
+    let frames = (0..frame_count).map(|frame| scene.render(frame)).collect();
+    let mut flows = Vec::with_capacity(frame_count as usize - 1);
+    let mut occlusions = Vec::with_capacity(frame_count as usize - 1);
+    for frame in 0..frame_count - 1 {
+        let (flow, occlusion) = scene.motion(frame)?;
+        flows.push(flow);
+        occlusions.push(occlusion);
+    }
+    Ok(SyntheticSequence {
+        frames,
+        flows,
+        occlusions,
+    })
+}
+
+// Frames of a 128x96 background translated by `(dx, dy)` per frame.
+#[cfg(test)]
+pub(crate) fn translation_frames(dx: f32, dy: f32, frame_count: u32) -> Vec<ImageBuffer<Rgb<u8>, Vec<u8>>> {
+    let settings = SceneSettings {
+        width: 128,
+        height: 96,
+        background_motion: Motion::Translation { dx, dy },
+        ..Default::default()
+    };
+    generate(&settings, frame_count).unwrap().frames
+}
+
+// Mean endpoint error against a uniform `expected` motion more than `margin`
+// pixels inside the frame, where estimators see no clamped border samples.
+#[cfg(test)]
+pub(crate) fn interior_error(flow: &FlowField, expected: (f32, f32), margin: u32) -> f32 {
+    let (width, height) = flow.pixel_dimensions();
+    let mut total = 0.0;
+    let mut count = 0;
+    for y in margin..height - margin {
+        for x in margin..width - margin {
+            let (dx, dy) = flow.at_pixel(x, y);
+            total += ((dx - expected.0).powi(2) + (dy - expected.1).powi(2)).sqrt();
+            count += 1;
+        }
+    }
+    total / count as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compensator::compensate_at;
+    use crate::estimator::combinator::{Algorithm, SearchStrategy};
+    use crate::estimator::Estimator;
+    use crate::evaluation::{evaluate, psnr, EvaluationRegion};
+
+    fn translation(dx: f32, dy: f32) -> SceneSettings {
+        SceneSettings {
+            width: 128,
+            height: 96,
+            background_motion: Motion::Translation { dx, dy },
+            ..Default::default()
+        }
+    }
+
+    fn block_matching(algorithm: Algorithm) -> Estimator {
+        let mut estimator = Estimator::new();
+        estimator.set_algorithm(algorithm);
+        estimator.settings.block_matching.block_width = 8;
+        estimator.settings.block_matching.search_strategy = SearchStrategy::Full;
+        estimator.settings.smoothing.filter_window_width = 3;
+        estimator
+    }
+
+    // Endpoint error of `estimator` between the first two frames.
+    fn endpoint_error(estimator: &mut Estimator, sequence: &SyntheticSequence) -> f64 {
+        estimator
+            .set_frames_directly(sequence.frames[0].clone(), sequence.frames[1].clone())
+            .unwrap();
+        let flow = estimator.estimate_motion().unwrap();
+        evaluate(&flow, &sequence.flows[0], Some(&sequence.occlusions[0]), EvaluationRegion::NonOccluded)
+            .unwrap()
+            .average_endpoint_error()
+    }
+
+    // PSNR of the middle frame rebuilt from its neighbours with a block
+    // matching estimate.
+    fn interpolation_psnr(sequence: &SyntheticSequence) -> f64 {
+        let mut estimator = block_matching(Algorithm::BlockMatching);
+        estimator
+            .set_frames_directly(sequence.frames[0].clone(), sequence.frames[2].clone())
+            .unwrap();
+        let flow = estimator.estimate_motion().unwrap();
+        let middle = compensate_at(&sequence.frames[0], &sequence.frames[2], &flow, 0.5).unwrap();
+        psnr(&middle, &sequence.frames[1]).unwrap()
+    }
+
+    #[test]
+    fn same_seed_gives_same_frames() {
+        let settings = SceneSettings {
+            rectangles: vec![MovingRectangle { x: 20.0, y: 10.0, width: 30.0, height: 20.0, dx: 1.5, dy: 0.5 }],
+            ..translation(2.5, -1.25)
+        };
+        let first = generate(&settings, 3).unwrap();
+        let second = generate(&settings, 3).unwrap();
+        assert_eq!(first.frames, second.frames);
+        assert_eq!(first.flows, second.flows);
+        assert_eq!(first.occlusions, second.occlusions);
+
+        let other_seed = generate(&SceneSettings { seed: 2, ..settings }, 3).unwrap();
+        assert_ne!(first.frames, other_seed.frames);
+    }
+
+    #[test]
+    fn translation_ground_truth_is_exact() {
+        let sequence = generate(&translation(2.5, -1.25), 2).unwrap();
+        let flow = &sequence.flows[0];
+        assert_eq!((flow.dimensions(), flow.spacing()), ((128, 96), 1));
+        assert!(flow.iter().all(|&vector| vector == (2.5, -1.25)));
+    }
+
+    #[test]
+    fn covered_background_is_occluded() {
+        let settings = SceneSettings {
+            rectangles: vec![MovingRectangle { x: 40.0, y: 30.0, width: 30.0, height: 20.0, dx: 4.0, dy: 0.0 }],
+            ..translation(0.0, 0.0)
+        };
+        let sequence = generate(&settings, 2).unwrap();
+        let occluded = |x: u32, y: u32| sequence.occlusions[0][(y * settings.width + x) as usize];
+        // The rectangle spans x 40..70 and moves onto x 70..74.
+        for x in 70..74 {
+            assert!(occluded(x, 40), "pixel {} ahead of the rectangle is not occluded", x);
+        }
+        assert!(!occluded(74, 40));
+        // The strip it uncovers and the rectangle itself stay visible.
+        for x in 36..70 {
+            assert!(!occluded(x, 40), "pixel {} is occluded", x);
+        }
+        assert!(!occluded(80, 10));
+    }
+
+    #[test]
+    fn block_matching_recovers_translations() {
+        // Integer matching rounds (2.5, -1.25) off by about 0.56 px.
+        for (dx, dy, max_error) in [(3.0, -2.0, 0.1), (2.5, -1.25, 0.7)] {
+            let sequence = generate(&translation(dx, dy), 2).unwrap();
+            for algorithm in [Algorithm::BlockMatching, Algorithm::SmoothedBlockMatching] {
+                let error = endpoint_error(&mut block_matching(algorithm), &sequence);
+                assert!(error < max_error, "EPE {} for ({}, {})", error, dx, dy);
+            }
+        }
+    }
+
+    #[test]
+    fn interpolated_frame_matches_the_middle_frame() {
+        for (dx, dy, min_psnr) in [(3.0, -2.0, 35.0), (2.5, -1.25, 40.0)] {
+            let sequence = generate(&translation(dx, dy), 3).unwrap();
+            let psnr = interpolation_psnr(&sequence);
+            assert!(psnr > min_psnr, "PSNR {} for ({}, {})", psnr, dx, dy);
+        }
+    }
+}
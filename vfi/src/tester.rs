@@ -2,7 +2,15 @@ use std::io::Write;
 use std::process::Command;
 use std::{time::Instant, fs::File};
 use std::fs;
-use crate::{estimator::{Estimator, combinator::Algorithm}, compensator::compensate, visualizer::{visualize_flow, VisualizationMethod}};
+use std::path::Path;
+use image::RgbImage;
+use vfi::{FlowField, VfiError, evaluation::{evaluate, ms_ssim, psnr, read_ground_truth, read_occlusion_mask, ssim, EvaluationRegion, FlowErrors}, estimator::{Estimator, combinator::{Algorithm, LucasKanadeMode, PhaseCorrelationMode, SearchStats}}, compensator::{compensate, interpolate_at, CompensationMethod}, retiming::{FrameRate, Retimer}, synthetic::{generate, Motion, MovingRectangle, SceneSettings}, operations::scale_up, visualizer::{draw_partition, visualize_flow, VisualizationMethod}};
+
+// `input/` holds frames extracted at 30 fps; 60 fps matches the old doubling.
+const SOURCE_FRAME_RATE: FrameRate = FrameRate::fps(30);
+const TARGET_FRAME_RATE: FrameRate = FrameRate::fps(60);
+
+const INPUT_FRAMES: u32 = 180;
 
 
 fn get_every_estimator_setting() -> Vec<Estimator> {
@@ -137,14 +145,99 @@ fn get_every_estimator_setting() -> Vec<Estimator> {
     estimator_18.description = "SBDBMA(8-7-5)".to_string();
 
     let mut estimator_19 = Estimator::new();
-    estimator_19.set_algorithm(Algorithm::LucasKanade);
-    estimator_19.description = "Lucas-Kanade".to_string();
+    estimator_19.set_algorithm(Algorithm::BlockMatching);
+    estimator_19.settings.block_matching.block_width = 8;
+    estimator_19.settings.block_matching.search_radius = 7;
+    estimator_19.settings.block_matching.use_predictors = true;
+    estimator_19.settings.block_matching.early_termination_cost = Some(16.0);
+    estimator_19.description = "PBMA(8-7)".to_string();
 
     let mut estimator_20 = Estimator::new();
-    estimator_20.set_algorithm(Algorithm::GunnarFarneback);
-    estimator_20.description = "Gunnar-Farneback".to_string();
-
-    let estimators = vec![
+    estimator_20.set_algorithm(Algorithm::QuadtreeBlockMatching);
+    estimator_20.settings.block_matching.block_width = 16;
+    estimator_20.settings.block_matching.search_radius = 7;
+    estimator_20.settings.quadtree.min_block_width = 4;
+    estimator_20.description = "QBMA(16-4-7)".to_string();
+
+    let mut estimator_21 = Estimator::new();
+    estimator_21.set_algorithm(Algorithm::BidirectionalBlockMatching);
+    estimator_21.settings.block_matching.block_width = 8;
+    estimator_21.settings.block_matching.search_radius = 7;
+    estimator_21.settings.occlusion.detect = true;
+    estimator_21.compensation = CompensationMethod::Obmc;
+    estimator_21.description = "OBDBMA(8-7)".to_string();
+
+    let mut estimator_22 = Estimator::new();
+    estimator_22.set_algorithm(Algorithm::LucasKanade);
+    estimator_22.description = "Lucas-Kanade".to_string();
+
+    let mut estimator_23 = Estimator::new();
+    estimator_23.set_algorithm(Algorithm::LucasKanade);
+    estimator_23.settings.lucas_kanade.mode = LucasKanadeMode::Sparse;
+    estimator_23.description = "Sparse Lucas-Kanade".to_string();
+
+    let mut estimator_24 = Estimator::new();
+    estimator_24.set_algorithm(Algorithm::OpenCvLucasKanade);
+    estimator_24.description = "OpenCV Lucas-Kanade".to_string();
+
+    let mut estimator_25 = Estimator::new();
+    estimator_25.set_algorithm(Algorithm::GunnarFarneback);
+    estimator_25.description = "Gunnar-Farneback".to_string();
+
+    let mut estimator_26 = Estimator::new();
+    estimator_26.set_algorithm(Algorithm::OpenCvGunnarFarneback);
+    estimator_26.description = "OpenCV Gunnar-Farneback".to_string();
+
+    let mut estimator_27 = Estimator::new();
+    estimator_27.set_algorithm(Algorithm::HornSchunck);
+    estimator_27.description = "HS(15-100)".to_string();
+
+    let mut estimator_28 = Estimator::new();
+    estimator_28.set_algorithm(Algorithm::HornSchunck);
+    estimator_28.settings.grayscale = true;
+    estimator_28.settings.conv_edges = true;
+    estimator_28.description = "EHS(15-100)".to_string();
+
+    let mut estimator_29 = Estimator::new();
+    estimator_29.set_algorithm(Algorithm::TvL1);
+    estimator_29.description = "TV-L1".to_string();
+
+    let mut estimator_30 = Estimator::new();
+    estimator_30.set_algorithm(Algorithm::PhaseCorrelation);
+    estimator_30.settings.phase_correlation.mode = PhaseCorrelationMode::Global;
+    estimator_30.description = "GPC".to_string();
+
+    let mut estimator_31 = Estimator::new();
+    estimator_31.set_algorithm(Algorithm::PhaseCorrelation);
+    estimator_31.settings.block_matching.block_width = 16;
+    estimator_31.settings.phase_correlation.window_width = 32;
+    estimator_31.description = "PC(16-32)".to_string();
+
+    let mut estimator_32 = Estimator::new();
+    estimator_32.set_algorithm(Algorithm::PhaseCorrelation);
+    estimator_32.settings.block_matching.block_width = 16;
+    estimator_32.settings.phase_correlation.window_width = 32;
+    estimator_32.settings.phase_correlation.smoothed = true;
+    estimator_32.settings.phase_correlation.bidirectional = true;
+    estimator_32.settings.smoothing.filter_window_width = 3;
+    estimator_32.description = "SBDPC(16-32-3)".to_string();
+
+    let mut estimator_33 = Estimator::new();
+    estimator_33.set_algorithm(Algorithm::BlockMatching);
+    estimator_33.settings.block_matching.block_width = 8;
+    estimator_33.settings.block_matching.search_radius = 7;
+    estimator_33.compensation = CompensationMethod::Obmc;
+    estimator_33.description = "BMA(8-7)-OBMC".to_string();
+
+    let mut estimator_34 = Estimator::new();
+    estimator_34.set_algorithm(Algorithm::SmoothedBlockMatching);
+    estimator_34.settings.block_matching.block_width = 8;
+    estimator_34.settings.block_matching.search_radius = 7;
+    estimator_34.settings.smoothing.filter_window_width = 3;
+    estimator_34.compensation = CompensationMethod::Obmc;
+    estimator_34.description = "SBMA(8-7-3)-OBMC".to_string();
+
+    vec![
         estimator_1,
         estimator_2,
         estimator_3,
@@ -165,85 +258,248 @@ fn get_every_estimator_setting() -> Vec<Estimator> {
         estimator_18,
         estimator_19,
         estimator_20,
-    ] as Vec<Estimator>;
-
-    estimators
+        estimator_21,
+        estimator_22,
+        estimator_23,
+        estimator_24,
+        estimator_25,
+        estimator_26,
+        estimator_27,
+        estimator_28,
+        estimator_29,
+        estimator_30,
+        estimator_31,
+        estimator_32,
+        estimator_33,
+        estimator_34,
+    ]
 }
 
 
-fn create_video(algo_name: String) {
+fn create_video(algo_name: &str, retimer: &Retimer) -> Result<(), VfiError> {
+    let (source, target) = (retimer.source(), retimer.target());
     Command::new("ffmpeg").args([
         "-framerate", 
-        "30",
+        &format!("{}/{}", target.numerator, target.denominator),
         "-i",
         &format!("./Results/{}/frames/%d.png", algo_name),
         "-c:v", 
         "libx264",
         "-pix_fmt",
         "yuv420p",
-        &format!("./Results/{}/{}-Interpolated30fps.mp4", algo_name, algo_name),
-    ]).spawn().expect("failed to execute process");
+        &format!("./Results/{}/{}-Interpolated{}fps.mp4", algo_name, algo_name, target),
+    ]).status()?;
 
     Command::new("ffmpeg").args([
         "-framerate", 
-        "30",
+        &format!("{}/{}", source.numerator, source.denominator),
         "-i",
         &format!("./Results/{}/flow/%d.png", algo_name),
         "-c:v", 
         "libx264",
         "-pix_fmt",
         "yuv420p",
-        &format!("./Results/{}/{}-Flow30fps.mp4", algo_name, algo_name),
-    ]).spawn().expect("failed to execute process");
+        &format!("./Results/{}/{}-Flow{}fps.mp4", algo_name, algo_name, source),
+    ]).status()?;
+
+    Ok(())
+}
+
+
+// Output frames are numbered from 1 like the input frames.
+fn save_output_frame(
+    estimator: &Estimator,
+    index: u64,
+    frame: &RgbImage,
+) -> Result<(), VfiError> {
+    frame.save(
+        format!("./Results/{}/frames/{}.png", 
+        estimator.description, 
+        index + 1)
+    )?;
+    Ok(())
+}
+
+
+// Errors of one pair against `input/flow/`, also restricted to the pixels
+// `input/occlusion/` leaves visible when a mask is present.
+struct PairErrors {
+    all: FlowErrors,
+    non_occluded: Option<FlowErrors>,
 }
 
 
-pub fn test() {
+fn evaluate_pair(frame_index: u32, flow: &FlowField) -> Result<Option<PairErrors>, VfiError> {
+    let ground_truth_filename = ["flo", "png", "pfm"]
+        .iter()
+        .map(|extension| format!("./input/flow/{}.{}", frame_index, extension))
+        .find(|filename| Path::new(filename).exists());
+    let ground_truth = match ground_truth_filename {
+        Some(filename) => read_ground_truth(&filename)?,
+        None => return Ok(None),
+    };
+    let occlusion_filename = format!("./input/occlusion/{}.png", frame_index);
+    let occlusion = if Path::new(&occlusion_filename).exists() {
+        Some(read_occlusion_mask(&occlusion_filename)?)
+    } else {
+        None
+    };
+
+    let all = evaluate(flow, &ground_truth, occlusion.as_deref(), EvaluationRegion::All)?;
+    let non_occluded = match occlusion.as_deref() {
+        Some(occlusion) => Some(evaluate(flow, &ground_truth, Some(occlusion), EvaluationRegion::NonOccluded)?),
+        None => None,
+    };
+    Ok(Some(PairErrors { all, non_occluded }))
+}
+
+
+// OBMC blends neighbouring block vectors, so it needs the block grid the
+// estimator produced rather than the per-pixel field.
+fn compensation_flow<'a>(
+    estimator: &Estimator,
+    block_flow: &'a FlowField,
+    flow: &'a FlowField,
+) -> &'a FlowField {
+    match estimator.compensation {
+        CompensationMethod::Obmc => block_flow,
+        _ => flow,
+    }
+}
+
+
+fn process_frame_pair(
+    estimator: &mut Estimator,
+    retimer: &Retimer,
+    frame_index: u32,
+    search_stats: &mut SearchStats,
+) -> Result<FlowField, VfiError> {
+    let frame_1_filename = &format!("./input/{}.png", frame_index);
+    let frame_2_filename = &format!("./input/{}.png", frame_index + 1);
+
+    estimator.set_frames_from_files(frame_1_filename, frame_2_filename)?;
+    let block_flow = estimator.estimate_block_motion()?;
+    if let Some(stats) = estimator.search_stats() {
+        *search_stats += stats;
+    }
+    estimator.set_previous_flow(Some(block_flow.clone()));
+    let (width, height) = estimator.get_frames_size()?;
+    let flow = scale_up(&block_flow, width, height);
+
+    let frame_1 = image::open(frame_1_filename)?.into_rgb8();
+    let frame_2 = image::open(frame_2_filename)?.into_rgb8();
+    for output in retimer.frames_for_pair(frame_index - 1) {
+        if output.t == 0.0 {
+            save_output_frame(estimator, output.index, &frame_1)?;
+        } else {
+            let compensation_flow = compensation_flow(estimator, &block_flow, &flow);
+            let frame = interpolate_at(&frame_1, &frame_2, compensation_flow, output.t, estimator.compensation)?;
+            save_output_frame(estimator, output.index, &frame)?;
+        }
+    }
+
+    let mut flow_image = visualize_flow(
+        &flow,
+        VisualizationMethod::HSEScheme,
+    );
+    if let Some(partition) = estimator.partition() {
+        draw_partition(&mut flow_image, partition);
+    }
+    flow_image.save(
+        format!("./Results/{}/flow/{}.png", 
+        estimator.description, 
+        frame_index)
+    )?;
+    flow.write_flo(&format!("./Results/{}/flow/{}.flo", estimator.description, frame_index))?;
+
+    Ok(flow)
+}
+
+
+// ffmpeg stops reading numbered frames at the first missing one, so a failed
+// pair repeats its first frame instead of leaving a gap.
+fn fill_failed_pair(estimator: &Estimator, retimer: &Retimer, frame_index: u32) -> Result<(), VfiError> {
+    let frame_1 = image::open(format!("./input/{}.png", frame_index))?.into_rgb8();
+    for output in retimer.frames_for_pair(frame_index - 1) {
+        save_output_frame(estimator, output.index, &frame_1)?;
+    }
+    Ok(())
+}
+
+
+fn check_settings(estimator: &mut Estimator) -> Result<(), VfiError> {
+    estimator.set_frames_from_files("./input/1.png", "./input/2.png")?;
+    if let Some(layout) = estimator.validate()? {
+        println!(
+            "{}: padding {}px, {}x{} block grid",
+            estimator.description, layout.padding, layout.grid_width, layout.grid_height
+        );
+    }
+    Ok(())
+}
+
+
+pub fn test() -> Result<(), VfiError> {
     fs::remove_dir_all("./Results").ok();
-    fs::create_dir_all("./Results").unwrap();
+    fs::create_dir_all("./Results")?;
 
     let estimators = get_every_estimator_setting();
     for mut estimator in estimators {
         println!("Computing: {}", estimator.description);
-        fs::create_dir_all(format!("./Results/{}", estimator.description)).unwrap();
-        fs::create_dir_all(format!("./Results/{}/frames", estimator.description)).unwrap();
-        fs::create_dir_all(format!("./Results/{}/flow", estimator.description)).unwrap();
+        if let Err(err) = check_settings(&mut estimator) {
+            eprintln!("{}: skipped, {}", estimator.description, err);
+            continue;
+        }
+        fs::create_dir_all(format!("./Results/{}", estimator.description))?;
+        fs::create_dir_all(format!("./Results/{}/frames", estimator.description))?;
+        fs::create_dir_all(format!("./Results/{}/flow", estimator.description))?;
 
         let now = Instant::now();
 
-        let input_frames = 180;
+        let input_frames = INPUT_FRAMES;
+        let retimer = Retimer::new(SOURCE_FRAME_RATE, TARGET_FRAME_RATE)?;
+        let mut failed_pairs = 0;
+        let mut failed_evaluations = 0;
+        let mut search_stats = SearchStats::default();
+        let mut flow_errors = FlowErrors::default();
+        let mut non_occluded_errors = FlowErrors::default();
+        let mut evaluation_report = String::new();
         for frame_index in 1..input_frames {
             println!("{}: {}/{} frames done", estimator.description, frame_index, input_frames);
-            let frame_1_filename = &format!("./input/{}.png", frame_index);
-            let frame_2_filename = &format!("./input/{}.png", frame_index + 1);
-
-            estimator.set_frames_from_files(frame_1_filename, frame_2_filename);
-            let flow = estimator.estimate_motion();
-
-            image::open(frame_1_filename).unwrap().into_rgb8().save(
-                format!("./Results/{}/frames/{}.png", 
-                estimator.description, 
-                frame_index * 2 - 1)
-            ).unwrap();
-            compensate(
-                frame_1_filename, 
-                frame_2_filename, 
-                &flow
-            ).save(
-                format!("./Results/{}/frames/{}.png", 
-                estimator.description, 
-                frame_index * 2)
-            ).unwrap();
-        
-            visualize_flow(
-                &flow,
-                1,
-                VisualizationMethod::HSEScheme,
-            ).save(
-                format!("./Results/{}/flow/{}.png", 
-                estimator.description, 
-                frame_index)
-            ).unwrap();
+            // The pair's frames are written by now, so a broken ground truth
+            // only costs its accuracy figures.
+            match process_frame_pair(&mut estimator, &retimer, frame_index, &mut search_stats)
+                .map(|flow| evaluate_pair(frame_index, &flow))
+            {
+                Ok(Ok(Some(pair_errors))) => {
+                    evaluation_report += &format!("Pair {}: {}\n", frame_index, pair_errors.all);
+                    flow_errors += pair_errors.all;
+                    if let Some(non_occluded) = pair_errors.non_occluded {
+                        evaluation_report += &format!("Pair {} non-occluded: {}\n", frame_index, non_occluded);
+                        non_occluded_errors += non_occluded;
+                    }
+                }
+                Ok(Ok(None)) => {}
+                Ok(Err(err)) => {
+                    eprintln!("{}: evaluation of frame pair {} failed: {}", estimator.description, frame_index, err);
+                    evaluation_report += &format!("Pair {}: evaluation failed: {}\n", frame_index, err);
+                    failed_evaluations += 1;
+                }
+                Err(err) => {
+                    eprintln!("{}: frame pair {} failed: {}", estimator.description, frame_index, err);
+                    estimator.set_previous_flow(None);
+                    failed_pairs += 1;
+                    if let Err(err) = fill_failed_pair(&estimator, &retimer, frame_index) {
+                        eprintln!("{}: frame pair {} left a gap: {}", estimator.description, frame_index, err);
+                    }
+                }
+            }
+        }
+
+        // The last input frame has no pair but may still land on an output timestamp.
+        if let Some(output) = retimer.frames_for_pair(input_frames - 1).first().filter(|output| output.t == 0.0) {
+            let last_frame = image::open(format!("./input/{}.png", input_frames))?.into_rgb8();
+            save_output_frame(&estimator, output.index, &last_frame)?;
         }
 
         let elapsed_time = now.elapsed();
@@ -251,15 +507,211 @@ pub fn test() {
 
         let mut file = File::create(
             format!("./Results/{}/Performance.txt", estimator.description)
-        ).unwrap();
-        let message = format!(
-            "Total time for {} frames: {:?}s\nAverage per frame: {:?}ms", 
+        )?;
+        let mut message = format!(
+            "Total time for {} frames: {:?}s\nAverage per frame: {:?}ms\nFailed frame pairs: {}", 
             input_frames,
             elapsed_time.as_secs(), 
-            elapsed_time.as_millis() / (input_frames - 1)
+            elapsed_time.as_millis() / (input_frames - 1) as u128,
+            failed_pairs
+        );
+        message += &format!(
+            "\nFrame rate: {} -> {} fps\nOutput frames: {}",
+            retimer.source(),
+            retimer.target(),
+            retimer.output_frame_count(input_frames)
         );
-        file.write_all(message.as_bytes()).unwrap();
+        if search_stats.blocks > 0 {
+            message += &format!(
+                "\nSearched blocks: {}\nAverage candidates per block: {:.2}",
+                search_stats.blocks,
+                search_stats.average_candidates()
+            );
+        }
+        if failed_evaluations > 0 {
+            message += &format!("\nFailed evaluations: {}", failed_evaluations);
+        }
+        if flow_errors.pixels > 0 {
+            message += &format!("\nAccuracy: {}", flow_errors);
+        }
+        if non_occluded_errors.pixels > 0 {
+            message += &format!("\nNon-occluded accuracy: {}", non_occluded_errors);
+        }
+        file.write_all(message.as_bytes())?;
+        if !evaluation_report.is_empty() {
+            fs::write(
+                format!("./Results/{}/Evaluation.txt", estimator.description),
+                evaluation_report,
+            )?;
+        }
+
+        if let Err(err) = create_video(&estimator.description, &retimer) {
+            eprintln!("{}: video encoding failed: {}", estimator.description, err);
+        }
+    }
+
+    Ok(())
+}
+
+
+// Quality of frame `frame_index + 1` rebuilt from its two neighbours.
+struct Reconstruction {
+    frame_index: u32,
+    psnr: f64,
+    ssim: f64,
+    ms_ssim: f64,
+}
+
 
-        create_video(estimator.description);
+fn reconstruct_frame(estimator: &mut Estimator, frame_index: u32) -> Result<Reconstruction, VfiError> {
+    let frame_1_filename = &format!("./input/{}.png", frame_index);
+    let frame_2_filename = &format!("./input/{}.png", frame_index + 2);
+
+    estimator.set_frames_from_files(frame_1_filename, frame_2_filename)?;
+    let block_flow = estimator.estimate_block_motion()?;
+    estimator.set_previous_flow(Some(block_flow.clone()));
+    let (width, height) = estimator.get_frames_size()?;
+    let flow = scale_up(&block_flow, width, height);
+
+    let compensation_flow = compensation_flow(estimator, &block_flow, &flow);
+    let interpolated = compensate(frame_1_filename, frame_2_filename, compensation_flow, estimator.compensation)?;
+    let reference = image::open(format!("./input/{}.png", frame_index + 1))?.into_rgb8();
+    Ok(Reconstruction {
+        frame_index: frame_index + 1,
+        psnr: psnr(&interpolated, &reference)?,
+        ssim: ssim(&interpolated, &reference)?,
+        ms_ssim: ms_ssim(&interpolated, &reference)?,
+    })
+}
+
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let middle = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    } else {
+        sorted[middle]
     }
 }
+
+
+// Leave-one-out evaluation: every frame with two neighbours is interpolated
+// from them and compared with the real frame.
+pub fn test_interpolation() -> Result<(), VfiError> {
+    let estimators = get_every_estimator_setting();
+    for mut estimator in estimators {
+        println!("Reconstructing: {}", estimator.description);
+        if let Err(err) = check_settings(&mut estimator) {
+            eprintln!("{}: skipped, {}", estimator.description, err);
+            continue;
+        }
+        fs::create_dir_all(format!("./Results/{}", estimator.description))?;
+
+        let mut reconstructions = Vec::new();
+        for frame_index in 1..INPUT_FRAMES - 1 {
+            match reconstruct_frame(&mut estimator, frame_index) {
+                Ok(reconstruction) => reconstructions.push(reconstruction),
+                Err(err) => {
+                    eprintln!("{}: frame {} failed: {}", estimator.description, frame_index + 1, err);
+                    estimator.set_previous_flow(None);
+                }
+            }
+        }
+        if reconstructions.is_empty() {
+            continue;
+        }
+
+        let mut report = String::from("frame,psnr,ssim,ms_ssim\n");
+        for reconstruction in reconstructions.iter() {
+            report += &format!(
+                "{},{:.4},{:.6},{:.6}\n",
+                reconstruction.frame_index, reconstruction.psnr, reconstruction.ssim, reconstruction.ms_ssim
+            );
+        }
+        let psnrs: Vec<f64> = reconstructions.iter().map(|r| r.psnr).collect();
+        let ssims: Vec<f64> = reconstructions.iter().map(|r| r.ssim).collect();
+        let ms_ssims: Vec<f64> = reconstructions.iter().map(|r| r.ms_ssim).collect();
+        for (label, statistic) in [("mean", mean as fn(&[f64]) -> f64), ("median", median)] {
+            report += &format!(
+                "{},{:.4},{:.6},{:.6}\n",
+                label, statistic(&psnrs), statistic(&ssims), statistic(&ms_ssims)
+            );
+        }
+        fs::write(format!("./Results/{}/Interpolation.csv", estimator.description), report)?;
+    }
+
+    Ok(())
+}
+
+
+fn synthetic_scenes() -> Vec<(&'static str, SceneSettings)> {
+    vec![
+        ("Translation", SceneSettings::default()),
+        ("Sub-pixel translation", SceneSettings {
+            background_motion: Motion::Translation { dx: 0.3, dy: 0.7 },
+            ..Default::default()
+        }),
+        ("Rotation", SceneSettings {
+            background_motion: Motion::Rotation { angle: 0.02 },
+            ..Default::default()
+        }),
+        ("Zoom", SceneSettings {
+            background_motion: Motion::Zoom { scale: 1.03 },
+            ..Default::default()
+        }),
+        ("Moving rectangles", SceneSettings {
+            background_motion: Motion::Translation { dx: 1.0, dy: 0.0 },
+            rectangles: vec![
+                MovingRectangle { x: 40.0, y: 40.0, width: 60.0, height: 50.0, dx: 4.5, dy: 2.0 },
+                MovingRectangle { x: 80.0, y: 60.0, width: 50.0, height: 60.0, dx: -3.0, dy: -1.5 },
+            ],
+            ..Default::default()
+        }),
+        ("Brightness change", SceneSettings {
+            brightness_gain: 1.1,
+            brightness_offset: -5.0,
+            ..Default::default()
+        }),
+    ]
+}
+
+
+// Accuracy of every estimator on generated frame pairs with exact ground truth.
+pub fn test_synthetic() -> Result<(), VfiError> {
+    let mut sequences = Vec::new();
+    for (name, scene) in synthetic_scenes() {
+        sequences.push((name, generate(&scene, 2)?));
+    }
+
+    let estimators = get_every_estimator_setting();
+    for mut estimator in estimators {
+        println!("Benchmarking: {}", estimator.description);
+        let mut report = String::new();
+        for (name, sequence) in sequences.iter() {
+            estimator.set_previous_flow(None);
+            estimator.set_frames_directly(sequence.frames[0].clone(), sequence.frames[1].clone())?;
+            let errors = estimator.estimate_block_motion().and_then(|flow| {
+                let all = evaluate(&flow, &sequence.flows[0], Some(&sequence.occlusions[0]), EvaluationRegion::All)?;
+                let non_occluded = evaluate(&flow, &sequence.flows[0], Some(&sequence.occlusions[0]), EvaluationRegion::NonOccluded)?;
+                Ok((all, non_occluded))
+            });
+            match errors {
+                Ok((all, non_occluded)) => {
+                    report += &format!("{}: {}\n{} non-occluded: {}\n", name, all, name, non_occluded);
+                }
+                Err(err) => report += &format!("{}: failed, {}\n", name, err),
+            }
+        }
+        fs::create_dir_all(format!("./Results/{}", estimator.description))?;
+        fs::write(format!("./Results/{}/Synthetic.txt", estimator.description), report)?;
+    }
+
+    Ok(())
+}
@@ -2,31 +2,26 @@ use std::f64::consts::PI;
 
 use image::{ImageBuffer, Rgb, RgbImage};
 
+use crate::estimator::combinator::BlockRegion;
+use crate::flow::FlowField;
 use crate::plotter::draw_line;
 use crate::plotter::draw_rect;
 
-fn visualize_as_vector_field(
-    vf: &Vec<Vec<(f32, f32)>>,
-    block_size: u32,
-) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-    let (height, width) = (
-        vf.len() as u32 * block_size,
-        vf[0].len() as u32 * block_size,
-    );
+fn visualize_as_vector_field(vf: &FlowField) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let block_size = vf.spacing();
+    let (width, height) = vf.pixel_dimensions();
     let mut img: RgbImage = ImageBuffer::new(width, height);
-    for i in 0..vf.len() {
-        for j in 0..vf[i].len() {
-            if vf[i][j] == (0.0, 0.0) {
-                continue;
-            }
-            let sx = j as u32 * block_size + block_size / 2;
-            let sy = i as u32 * block_size + block_size / 2;
-            let ex = (sx as i32 + vf[i][j].0 as i32).clamp(0, (width - 1) as i32) as u32;
-            let ey = (sy as i32 + vf[i][j].1 as i32).clamp(0, (height - 1) as i32) as u32;
-            draw_line(&mut img, (sx, sy), (ex, ey), Rgb([0, 255, 0]), 1);
-            img.put_pixel(sx, sy, Rgb([0, 0, 255]));
-            img.put_pixel(ex, ey, Rgb([255, 0, 0]));
+    for (j, i, (dx, dy)) in vf.enumerate() {
+        if (dx, dy) == (0.0, 0.0) {
+            continue;
         }
+        let sx = j * block_size + block_size / 2;
+        let sy = i * block_size + block_size / 2;
+        let ex = (sx as i32 + dx as i32).clamp(0, (width - 1) as i32) as u32;
+        let ey = (sy as i32 + dy as i32).clamp(0, (height - 1) as i32) as u32;
+        draw_line(&mut img, (sx, sy), (ex, ey), Rgb([0, 255, 0]), 1);
+        img.put_pixel(sx, sy, Rgb([0, 0, 255]));
+        img.put_pixel(ex, ey, Rgb([255, 0, 0]));
     }
     img
 }
@@ -39,7 +34,9 @@ fn cartesian_to_polar(dx: f32, dy: f32) -> (f32, f32) {
 }
 
 fn hsv_to_rgb(h_val: f32, s_val: f32, v_val: f32) -> Option<Rgb<u8>> {
-    if h_val > 360.0 || h_val < 0.0 || s_val > 100.0 || s_val < 0.0 || v_val > 100.0 || v_val < 0.0
+    if !(0.0..=360.0).contains(&h_val)
+        || !(0.0..=100.0).contains(&s_val)
+        || !(0.0..=100.0).contains(&v_val)
     {
         return None;
     }
@@ -53,23 +50,23 @@ fn hsv_to_rgb(h_val: f32, s_val: f32, v_val: f32) -> Option<Rgb<u8>> {
     let g: f32;
     let b: f32;
 
-    if h_val >= 0.0 && h_val < 60.0 {
+    if (0.0..60.0).contains(&h_val) {
         r = c;
         g = x;
         b = 0.0;
-    } else if h_val >= 60.0 && h_val < 120.0 {
+    } else if (60.0..120.0).contains(&h_val) {
         r = x;
         g = c;
         b = 0.0;
-    } else if h_val >= 120.0 && h_val < 180.0 {
+    } else if (120.0..180.0).contains(&h_val) {
         r = 0.0;
         g = c;
         b = x;
-    } else if h_val >= 180.0 && h_val < 240.0 {
+    } else if (180.0..240.0).contains(&h_val) {
         r = 0.0;
         g = x;
         b = c;
-    } else if h_val >= 240.0 && h_val < 300.0 {
+    } else if (240.0..300.0).contains(&h_val) {
         r = x;
         g = 0.0;
         b = c;
@@ -87,54 +84,92 @@ fn hsv_to_rgb(h_val: f32, s_val: f32, v_val: f32) -> Option<Rgb<u8>> {
 
 fn vector_to_color(dx: f32, dy: f32, norm_factor: f32) -> Rgb<u8> {
     let (magnitude, angle) = cartesian_to_polar(dx, dy);
-    let norm_magnitude = (magnitude / norm_factor * 100.0).clamp(0.0, 100.0);
-    hsv_to_rgb(angle, 100.0, norm_magnitude).unwrap()
+    let norm_magnitude = if norm_factor > 0.0 {
+        (magnitude / norm_factor * 100.0).clamp(0.0, 100.0)
+    } else {
+        0.0
+    };
+    hsv_to_rgb(angle, 100.0, norm_magnitude).unwrap_or(Rgb([0, 0, 0]))
 }
 
-fn visualize_as_hsv_scheme(
-    vf: &Vec<Vec<(f32, f32)>>,
-    block_size: u32,
-) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-    let (height, width) = (
-        vf.len() as u32 * block_size,
-        vf[0].len() as u32 * block_size,
-    );
+fn visualize_as_hsv_scheme(vf: &FlowField) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let block_size = vf.spacing();
+    let (width, height) = vf.pixel_dimensions();
     let mut img: RgbImage = ImageBuffer::new(width, height);
 
-    let mut mags: Vec<f32> = Vec::new();
-    for i in 0..vf.len() {
-        for j in 0..vf[i].len() {
-           mags.push((vf[i][j].0.powf(2.0) + vf[i][j].1.powf(2.0)).sqrt());
-        }
-    }
-    mags.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    let med_mag = mags[mags.len() / 10 * 9];
-
-    for i in 0..vf.len() {
-        for j in 0..vf[i].len() {
-            let color = vector_to_color(vf[i][j].0, vf[i][j].1, med_mag);
-            let x = j as u32 * block_size;
-            let y = i as u32 * block_size;
-            draw_rect(&mut img, x, y, block_size, block_size, color);
-        }
+    let mut mags: Vec<f32> = vf
+        .iter()
+        .map(|(dx, dy)| (dx.powf(2.0) + dy.powf(2.0)).sqrt())
+        .collect();
+    mags.sort_by(|a, b| a.total_cmp(b));
+    let med_mag = mags.get(mags.len() / 10 * 9).copied().unwrap_or(0.0);
+
+    for (j, i, (dx, dy)) in vf.enumerate() {
+        let color = vector_to_color(dx, dy, med_mag);
+        let x = j * block_size;
+        let y = i * block_size;
+        draw_rect(&mut img, x, y, block_size, block_size, color);
     }
 
     img
 }
 
-#[allow(dead_code)]
 pub enum VisualizationMethod {
     VectorField,
     HSEScheme,
 }
 
-pub fn visualize_flow(
-    vf: &Vec<Vec<(f32, f32)>>,
-    block_size: u32,
-    method: VisualizationMethod,
-) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+pub fn visualize_flow(vf: &FlowField, method: VisualizationMethod) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
     match method {
-        VisualizationMethod::VectorField => visualize_as_vector_field(vf, block_size),
-        VisualizationMethod::HSEScheme => visualize_as_hsv_scheme(vf, block_size),
+        VisualizationMethod::VectorField => visualize_as_vector_field(vf),
+        VisualizationMethod::HSEScheme => visualize_as_hsv_scheme(vf),
+    }
+}
+
+// Outlines the blocks of a variable block size partition on top of `img`.
+pub fn draw_partition(img: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, partition: &[BlockRegion]) {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return;
+    }
+    let color = Rgb([255, 255, 255]);
+    for block in partition {
+        let left = block.x.min(width - 1);
+        let top = block.y.min(height - 1);
+        let right = (block.x + block.size - 1).min(width - 1);
+        let bottom = (block.y + block.size - 1).min(height - 1);
+        draw_line(img, (left, top), (right, top), color, 1);
+        draw_line(img, (left, top), (left, bottom), color, 1);
+        // Neighbours draw the other edges, except along the frame border.
+        if block.x + block.size >= width {
+            draw_line(img, (right, top), (right, bottom), color, 1);
+        }
+        if block.y + block.size >= height {
+            draw_line(img, (left, bottom), (right, bottom), color, 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partition_outline_closes_at_the_frame_border() {
+        let mut img: RgbImage = ImageBuffer::new(10, 10);
+        let partition: Vec<BlockRegion> = [(0, 0), (8, 0), (0, 8), (8, 8)]
+            .iter()
+            .map(|&(x, y)| BlockRegion { x, y, size: 8 })
+            .collect();
+        draw_partition(&mut img, &partition);
+        // `draw_line` marks the first pixel of every line green.
+        let drawn = |x: u32, y: u32| img[(x, y)] != Rgb([0, 0, 0]);
+        for i in 0..10 {
+            assert!(drawn(i, 0) && drawn(0, i), "top or left edge misses {}", i);
+            assert!(drawn(i, 9) && drawn(9, i), "bottom or right edge misses {}", i);
+            assert!(drawn(i, 8) && drawn(8, i), "inner edge misses {}", i);
+        }
+        assert!(!drawn(4, 4));
+        assert!(!drawn(7, 3));
     }
 }